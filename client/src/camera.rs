@@ -102,9 +102,39 @@ impl Camera {
         self.target - self.eye
     }
 
-    pub fn set_position(&mut self, new_pos: &cgmath::Point3<f32>) {
+    /// The heading in degrees around the Y axis, where 0 looks down +Z
+    pub fn yaw(&self) -> f32 {
         let at = self.at();
-        self.eye = *new_pos + 1.7f32 * cgmath::Vector3::unit_y();
+        at.x.atan2(at.z).to_degrees()
+    }
+
+    /// Degrees above the horizon
+    pub fn pitch(&self) -> f32 {
+        let at = self.at();
+        (at.y / at.magnitude()).asin().to_degrees()
+    }
+
+    /// Move the eye, still looking the same way
+    pub fn move_by(&mut self, offset: cgmath::Vector3<f32>) {
+        self.eye += offset;
+        self.target += offset;
+    }
+
+    /// Look along the given heading and pitch, in degrees
+    pub fn look(&mut self, yaw: f32, pitch: f32) {
+        let (yaw, pitch) = (yaw.to_radians(), pitch.to_radians());
+        let at = cgmath::Vector3::new(
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+            pitch.cos() * yaw.cos(),
+        );
+        self.target = self.eye + at;
+    }
+
+    /// Place the eye `eye_height` above the given (foot) position
+    pub fn set_position(&mut self, new_pos: &cgmath::Point3<f32>, eye_height: f32) {
+        let at = self.at();
+        self.eye = *new_pos + eye_height * cgmath::Vector3::unit_y();
         self.target = self.eye + at;
     }
 
@@ -2,18 +2,74 @@ use crate::instance::{Instance, InstanceBuffer};
 use crate::model::{DrawModel, Model};
 use cgmath::Rotation3;
 use log::*;
+use network::PlayerState;
 use std::collections::HashMap;
+use std::time::Instant;
 
 const MAX_INSTANCES: usize = 256;
 
+/// How far above the player's feet the character model is drawn
+const MODEL_HEIGHT: f32 = 1.5;
+const CROUCHED_MODEL_HEIGHT: f32 = 1.0;
+
+const DEAD_HEIGHT: f32 = -100.0;
+
+/// Don't extrapolate positions further than this past the last update, in seconds
+const MAX_EXTRAPOLATION: f32 = 0.25;
+
 pub struct Character {
     instance_id: usize,
+    state: PlayerState,
+    last_update: Instant,
+    alive: bool,
+}
+
+impl Character {
+    /// The last state we heard, moved along by its velocity since
+    fn extrapolated(&self) -> PlayerState {
+        let dt = self
+            .last_update
+            .elapsed()
+            .as_secs_f32()
+            .min(MAX_EXTRAPOLATION);
+        let position: cgmath::Vector3<f32> = self.state.position.into();
+        let velocity: cgmath::Vector3<f32> = self.state.velocity.into();
+        let mut position = position + velocity * dt;
+        position.y = position.y.max(0.0);
+        PlayerState {
+            position: position.into(),
+            ..self.state
+        }
+    }
+
+    fn instance(&self) -> Instance {
+        let mut position: cgmath::Vector3<f32> = self.extrapolated().position.into();
+        position.y += if self.state.crouching {
+            CROUCHED_MODEL_HEIGHT
+        } else {
+            MODEL_HEIGHT
+        };
+        if !self.alive {
+            // Dead characters are kept out of sight until they respawn
+            position.y = DEAD_HEIGHT;
+        }
+        Instance {
+            position,
+            rotation: cgmath::Quaternion::from_axis_angle(
+                cgmath::Vector3::unit_y(),
+                cgmath::Deg(self.state.yaw),
+            ),
+        }
+    }
 }
 
 pub struct CharacterSet {
     model: Model,
     characters: HashMap<u32, Character>,
     instance_buffer: InstanceBuffer,
+
+    /// A character not to draw, because we're looking out of its eyes
+    hidden: Option<u32>,
 }
 
 impl CharacterSet {
@@ -23,30 +79,113 @@ impl CharacterSet {
             model: Model::load(device, queue, layout, res_dir.join("cube.obj")).unwrap(),
             characters: HashMap::new(),
             instance_buffer: InstanceBuffer::new(device, MAX_INSTANCES),
+            hidden: None,
         }
     }
 
+    /// Create a character, or put it back in place if it already exists
     pub fn add(&mut self, id: u32, position: [f32; 3]) {
+        if let Some(character) = self.characters.get_mut(&id) {
+            character.state = PlayerState {
+                position,
+                ..PlayerState::default()
+            };
+            character.last_update = Instant::now();
+            character.alive = true;
+            return;
+        }
         if self.characters.len() >= MAX_INSTANCES {
             panic!("Instance overrun");
         }
         info!("Creating character at {:?}", position);
-        let mut instance = Instance {
-            position: position.into(),
-            rotation: cgmath::Quaternion::from_axis_angle(
-                cgmath::Vector3::unit_z(),
-                cgmath::Deg(0.0),
-            ),
+        let character = Character {
+            instance_id: self.instance_buffer.instances.len(),
+            state: PlayerState {
+                position,
+                ..PlayerState::default()
+            },
+            last_update: Instant::now(),
+            alive: true,
         };
-        instance.position[1] = 1.5;
-        let instance_id = self.instance_buffer.instances.len();
-        self.instance_buffer.instances.push(instance);
-        self.characters.insert(id, Character { instance_id });
+        self.instance_buffer.instances.push(character.instance());
+        self.characters.insert(id, character);
     }
 
-    pub fn update_position(&mut self, id: u32, position: [f32; 3]) {
-        let character = self.characters.get(&id).unwrap();
-        self.instance_buffer.instances[character.instance_id].position = position.into();
+    /// Characters out of view aren't kept, so news of them is ignored
+    pub fn update_state(&mut self, id: u32, state: &PlayerState) {
+        if let Some(character) = self.characters.get_mut(&id) {
+            character.state = *state;
+            character.last_update = Instant::now();
+        }
+    }
+
+    /// Where the character's feet were last we heard, if they're alive
+    pub fn position(&self, id: u32) -> Option<[f32; 3]> {
+        self.characters
+            .get(&id)
+            .filter(|character| character.alive)
+            .map(|character| character.state.position)
+    }
+
+    /// A character's state moved on to now, if they're alive
+    pub fn state(&self, id: u32) -> Option<PlayerState> {
+        self.characters
+            .get(&id)
+            .filter(|character| character.alive)
+            .map(|character| character.extrapolated())
+    }
+
+    /// The characters that are alive, in order
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<_> = self
+            .characters
+            .iter()
+            .filter(|(_, character)| character.alive)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn hide(&mut self, id: Option<u32>) {
+        self.hidden = id;
+    }
+
+    /// Forget a character whose player has left or gone out of view. The last
+    /// instance moves into the gap.
+    pub fn remove(&mut self, id: u32) {
+        let character = match self.characters.remove(&id) {
+            Some(character) => character,
+            None => return,
+        };
+        let last = self.instance_buffer.instances.len() - 1;
+        self.instance_buffer
+            .instances
+            .swap_remove(character.instance_id);
+        if let Some(moved) = self
+            .characters
+            .values_mut()
+            .find(|other| other.instance_id == last)
+        {
+            moved.instance_id = character.instance_id;
+        }
+    }
+
+    pub fn set_alive(&mut self, id: u32, alive: bool) {
+        if let Some(character) = self.characters.get_mut(&id) {
+            character.alive = alive;
+        }
+    }
+
+    /// Move every character's instance to its extrapolated position
+    pub fn update(&mut self) {
+        for (id, character) in self.characters.iter() {
+            let mut instance = character.instance();
+            if Some(*id) == self.hidden {
+                instance.position.y = DEAD_HEIGHT;
+            }
+            self.instance_buffer.instances[character.instance_id] = instance;
+        }
     }
 }
 
@@ -0,0 +1,84 @@
+use crate::character::CharacterSet;
+use crate::instance::{Instance, InstanceBuffer};
+use crate::model::{DrawModel, Model};
+use cgmath::Rotation3;
+use mapgen::VOXEL_SIZE;
+use network::Team;
+use std::collections::HashMap;
+
+/// Flags are drawn as a pole of voxels this many high
+const POLE_VOXELS: usize = 10;
+
+/// How far above a carrier's feet a carried flag is drawn
+const CARRIED_HEIGHT: f32 = 2.0;
+
+struct Flag {
+    position: [f32; 3],
+    carrier: Option<u32>,
+}
+
+/// The flags in capture the flag
+pub struct FlagSet {
+    model: Model,
+    flags: HashMap<Team, Flag>,
+    instance_buffer: InstanceBuffer,
+}
+
+impl FlagSet {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout) -> Self {
+        let res_dir = std::path::Path::new(".").join("res");
+        Self {
+            model: Model::load(device, queue, layout, res_dir.join("voxel.obj")).unwrap(),
+            flags: HashMap::new(),
+            instance_buffer: InstanceBuffer::new(device, Team::ALL.len() * POLE_VOXELS),
+        }
+    }
+
+    pub fn update_flag(&mut self, team: Team, position: [f32; 3], carrier: Option<u32>) {
+        self.flags.insert(team, Flag { position, carrier });
+    }
+
+    /// Rebuild the poles, following carriers around. Our own player's carried flag
+    /// isn't drawn, since it would be in the way.
+    pub fn update(&mut self, characters: &CharacterSet) {
+        self.instance_buffer.instances.clear();
+        for flag in self.flags.values() {
+            let base = match flag.carrier {
+                Some(carrier) => match characters.position(carrier) {
+                    Some(position) => {
+                        cgmath::Vector3::new(position[0], position[1] + CARRIED_HEIGHT, position[2])
+                    }
+                    None => continue,
+                },
+                None => flag.position.into(),
+            };
+            for i in 0..POLE_VOXELS {
+                self.instance_buffer.instances.push(Instance {
+                    position: base + cgmath::Vector3::unit_y() * (i as f32 * VOXEL_SIZE),
+                    rotation: cgmath::Quaternion::from_axis_angle(
+                        cgmath::Vector3::unit_y(),
+                        cgmath::Deg(0.0),
+                    ),
+                });
+            }
+        }
+    }
+}
+
+pub trait DrawFlagSet<'a> {
+    fn draw_flag_set(&mut self, queue: &'_ wgpu::Queue, flags: &'a mut FlagSet);
+}
+
+impl<'a, 'b> DrawFlagSet<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_flag_set(&mut self, queue: &'_ wgpu::Queue, flags: &'b mut FlagSet) {
+        flags.instance_buffer.update(queue);
+        self.set_vertex_buffer(1, flags.instance_buffer.instance_buffer.slice(..));
+        self.draw_model_instanced(
+            &flags.model,
+            0..flags.instance_buffer.instances.len() as u32,
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use crate::overlay::Overlay;
+use glyph_brush::HorizontalAlign;
+use network::{LobbyEntry, MatchPhase, MatchSummary, PlayerStats, ScoreboardEntry, Team};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long each kill or message stays in the feed
+const FEED_TIME: Duration = Duration::from_secs(5);
+
+/// The most lines shown in the feed at once
+const FEED_LENGTH: usize = 5;
+
+const TEXT_SCALE: f32 = 20.0;
+const LINE_HEIGHT: f32 = 24.0;
+const MARGIN: f32 = 10.0;
+
+/// The font is monospaced, with characters about this wide for their height
+const CHARACTER_WIDTH: f32 = 0.6;
+
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const HIGHLIGHT_COLOR: [f32; 4] = [1.0, 0.9, 0.3, 1.0];
+const MESSAGE_COLOR: [f32; 4] = [0.5, 1.0, 0.5, 1.0];
+const BACKGROUND_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+const OWN_ROW_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.15];
+
+fn team_color(team: Option<Team>) -> [f32; 4] {
+    match team {
+        Some(Team::Red) => [1.0, 0.5, 0.5, 1.0],
+        Some(Team::Blue) => [0.5, 0.7, 1.0, 1.0],
+        None => TEXT_COLOR,
+    }
+}
+
+fn scoreboard_row(
+    name: &str,
+    score: &str,
+    kills: &str,
+    deaths: &str,
+    assists: &str,
+    ping: &str,
+) -> String {
+    format!(
+        "{:<20}{:>6}{:>5}{:>5}{:>5}{:>6}",
+        name, score, kills, deaths, assists, ping
+    )
+}
+
+fn leaderboard_row(
+    rank: &str,
+    name: &str,
+    wins: &str,
+    matches: &str,
+    kills: &str,
+    deaths: &str,
+    accuracy: &str,
+) -> String {
+    format!(
+        "{:>3} {:<20}{:>6}{:>8}{:>6}{:>6}{:>6}",
+        rank, name, wins, matches, kills, deaths, accuracy
+    )
+}
+
+fn stats_row(rank: &str, stats: &PlayerStats) -> String {
+    leaderboard_row(
+        rank,
+        &stats.username,
+        &stats.wins.to_string(),
+        &stats.matches.to_string(),
+        &stats.kills.to_string(),
+        &stats.deaths.to_string(),
+        &format!("{:.0}%", stats.accuracy() * 100.0),
+    )
+}
+
+/// Choosing the next map, between rounds
+struct Vote {
+    choices: Vec<String>,
+    votes: Vec<u32>,
+    closes: Instant,
+
+    /// What we voted for, if we have
+    ours: Option<u32>,
+}
+
+struct FeedLine {
+    text: String,
+    color: [f32; 4],
+    time: Instant,
+}
+
+/// The feed of kills and server messages, the scoreboard and leaderboard while
+/// they're asked for, the matches to choose from before joining one, the lobby
+/// before it starts, and the vote on the next map
+pub struct Hud {
+    /// While Tab is held down
+    pub show_scoreboard: bool,
+
+    /// While L is held down, with the best players on the server and our own stats,
+    /// once the server has sent them
+    pub show_leaderboard: bool,
+    pub leaderboard: Vec<PlayerStats>,
+    pub career: Option<PlayerStats>,
+
+    /// Until a match is joined, if the server has more than one
+    pub matches: Option<Vec<MatchSummary>>,
+
+    /// Set if we're watching rather than playing, with the player being followed
+    pub spectating: bool,
+    pub following: Option<u32>,
+
+    scoreboard: Vec<ScoreboardEntry>,
+    names: HashMap<u32, String>,
+    feed: VecDeque<FeedLine>,
+
+    /// Where the match is up to, and when we were told
+    phase: (MatchPhase, Instant),
+    lobby: Vec<LobbyEntry>,
+    min_ready: u32,
+
+    vote: Option<Vote>,
+}
+
+impl Hud {
+    pub fn new() -> Self {
+        Self {
+            show_scoreboard: false,
+            show_leaderboard: false,
+            leaderboard: vec![],
+            career: None,
+            matches: None,
+            spectating: false,
+            following: None,
+            scoreboard: vec![],
+            names: HashMap::new(),
+            feed: VecDeque::new(),
+            phase: (MatchPhase::Playing, Instant::now()),
+            lobby: vec![],
+            min_ready: 0,
+            vote: None,
+        }
+    }
+
+    pub fn start_vote(&mut self, choices: Vec<String>, time_left: f32) {
+        self.vote = Some(Vote {
+            votes: vec![0; choices.len()],
+            choices,
+            closes: Instant::now() + Duration::from_secs_f32(time_left.max(0.0)),
+            ours: None,
+        });
+    }
+
+    pub fn update_votes(&mut self, votes: Vec<u32>) {
+        if let Some(vote) = self.vote.as_mut() {
+            vote.votes = votes;
+        }
+    }
+
+    pub fn end_vote(&mut self) {
+        self.vote = None;
+    }
+
+    pub fn is_voting(&self) -> bool {
+        self.vote.is_some()
+    }
+
+    /// Remember which choice we voted for, to highlight it
+    pub fn vote(&mut self, choice: u32) {
+        if let Some(vote) = self.vote.as_mut() {
+            if (choice as usize) < vote.choices.len() {
+                vote.ours = Some(choice);
+            }
+        }
+    }
+
+    pub fn set_phase(&mut self, phase: MatchPhase) {
+        self.phase = (phase, Instant::now());
+    }
+
+    pub fn set_lobby(&mut self, players: Vec<LobbyEntry>, min_ready: u32) {
+        for entry in players.iter() {
+            self.add_player(entry.id, &entry.username);
+        }
+        self.lobby = players;
+        self.min_ready = min_ready;
+    }
+
+    /// Whether the player is waiting in the lobby, or for the countdown
+    pub fn in_lobby(&self) -> bool {
+        matches!(
+            self.phase.0,
+            MatchPhase::Lobby | MatchPhase::Countdown { .. }
+        )
+    }
+
+    /// A player's line in the lobby, if they're in it
+    pub fn lobby_entry(&self, id: u32) -> Option<&LobbyEntry> {
+        self.lobby.iter().find(|entry| entry.id == id)
+    }
+
+    /// Seconds until the countdown or warmup runs out
+    fn phase_time_left(&self) -> Option<f32> {
+        let (phase, since) = self.phase;
+        match phase {
+            MatchPhase::Countdown { time_left } | MatchPhase::Warmup { time_left } => {
+                Some((time_left - since.elapsed().as_secs_f32()).max(0.0))
+            }
+            MatchPhase::Lobby | MatchPhase::Playing => None,
+        }
+    }
+
+    pub fn add_player(&mut self, id: u32, username: &[u8; 20]) {
+        self.names.insert(id, network::decode_username(username));
+    }
+
+    fn name(&self, id: u32) -> String {
+        self.names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("Player {}", id))
+    }
+
+    pub fn update_scoreboard(&mut self, mut entries: Vec<ScoreboardEntry>) {
+        for entry in entries.iter() {
+            self.add_player(entry.id, &entry.username);
+        }
+        // Red team first, then by score
+        entries.sort_by_key(|entry| {
+            (
+                entry.team != Some(Team::Red),
+                -entry.score,
+                -(entry.kills as i64),
+                entry.deaths,
+            )
+        });
+        self.scoreboard = entries;
+    }
+
+    pub fn player_killed(
+        &mut self,
+        killer: u32,
+        victim: u32,
+        weapon: &str,
+        assists: &[u32],
+        player_id: Option<u32>,
+    ) {
+        let text = if killer == victim {
+            format!("{} [{}]", self.name(victim), weapon)
+        } else {
+            let mut killers = self.name(killer);
+            if !assists.is_empty() {
+                let assists: Vec<_> = assists.iter().map(|id| self.name(*id)).collect();
+                killers = format!("{} + {}", killers, assists.join(", "));
+            }
+            format!("{} [{}] {}", killers, weapon, self.name(victim))
+        };
+        // Kills we had a hand in, one way or another, stand out
+        let involves_us =
+            player_id.is_some_and(|id| id == killer || id == victim || assists.contains(&id));
+        let color = if involves_us {
+            HIGHLIGHT_COLOR
+        } else {
+            TEXT_COLOR
+        };
+        self.add_to_feed(text, color);
+    }
+
+    /// A message from the server's admins
+    pub fn message(&mut self, text: &str) {
+        self.add_to_feed(format!("Server: {}", text), MESSAGE_COLOR);
+    }
+
+    fn add_to_feed(&mut self, text: String, color: [f32; 4]) {
+        self.feed.push_back(FeedLine {
+            text,
+            color,
+            time: Instant::now(),
+        });
+        if self.feed.len() > FEED_LENGTH {
+            self.feed.pop_front();
+        }
+    }
+
+    pub fn draw(&mut self, overlay: &mut Overlay, player_id: Option<u32>) {
+        while matches!(self.feed.front(), Some(line) if line.time.elapsed() > FEED_TIME) {
+            self.feed.pop_front();
+        }
+        // Down the top right corner, newest at the bottom
+        for (i, line) in self.feed.iter().enumerate() {
+            overlay.text(
+                &line.text,
+                [overlay.width() - MARGIN, MARGIN + i as f32 * LINE_HEIGHT],
+                TEXT_SCALE,
+                line.color,
+                HorizontalAlign::Right,
+            );
+        }
+
+        if let MatchPhase::Warmup { .. } = self.phase.0 {
+            let text = format!("Warmup: {:.0}", self.phase_time_left().unwrap_or(0.0));
+            overlay.text(
+                &text,
+                [overlay.width() / 2.0, MARGIN],
+                TEXT_SCALE,
+                HIGHLIGHT_COLOR,
+                HorizontalAlign::Center,
+            );
+        }
+
+        if self.spectating {
+            let text = match self.following {
+                Some(id) => format!("Following {}, click for the next player", self.name(id)),
+                None => "Free camera, click to follow a player".to_string(),
+            };
+            overlay.text(
+                &text,
+                [
+                    overlay.width() / 2.0,
+                    overlay.height() - MARGIN - LINE_HEIGHT,
+                ],
+                TEXT_SCALE,
+                TEXT_COLOR,
+                HorizontalAlign::Center,
+            );
+        }
+
+        if let Some(matches) = self.matches.as_ref() {
+            draw_match_list(overlay, matches);
+        } else if self.in_lobby() {
+            self.draw_lobby(overlay, player_id);
+        } else if self.show_scoreboard {
+            self.draw_scoreboard(overlay, player_id);
+        } else if self.show_leaderboard {
+            self.draw_leaderboard(overlay);
+        } else if let Some(vote) = self.vote.as_ref() {
+            draw_vote(overlay, vote);
+        }
+    }
+
+    fn draw_lobby(&self, overlay: &mut Overlay, player_id: Option<u32>) {
+        let ready = self.lobby.iter().filter(|entry| entry.ready).count();
+        let title = match self.phase_time_left() {
+            Some(time_left) => format!("Starting in {:.0}", time_left),
+            None => format!("Waiting for players: {} of {} ready", ready, self.min_ready),
+        };
+        let help = if self.spectating {
+            "Spectating"
+        } else if self.lobby.iter().any(|entry| entry.team.is_some()) {
+            "Enter: ready   T: switch team"
+        } else {
+            "Enter: ready"
+        };
+        let rows: Vec<_> = self
+            .lobby
+            .iter()
+            .map(|entry| {
+                let status = if entry.ready { "Ready" } else { "" };
+                format!("{:<20}{:>6}", self.name(entry.id), status)
+            })
+            .collect();
+        let longest = rows
+            .iter()
+            .map(String::len)
+            .chain([title.len(), help.len()])
+            .max()
+            .unwrap_or(0);
+        let width = longest as f32 * TEXT_SCALE * CHARACTER_WIDTH + 2.0 * MARGIN;
+        let height = (rows.len() + 3) as f32 * LINE_HEIGHT + 2.0 * MARGIN;
+        let left = ((overlay.width() - width) / 2.0).max(0.0);
+        let top = ((overlay.height() - height) / 2.0).max(0.0);
+        overlay.rect([left, top], [width, height], BACKGROUND_COLOR);
+
+        let mut y = top + MARGIN;
+        overlay.text(
+            &title,
+            [left + MARGIN, y],
+            TEXT_SCALE,
+            HIGHLIGHT_COLOR,
+            HorizontalAlign::Left,
+        );
+        for (entry, row) in self.lobby.iter().zip(rows.iter()) {
+            y += LINE_HEIGHT;
+            if Some(entry.id) == player_id {
+                overlay.rect([left, y], [width, LINE_HEIGHT], OWN_ROW_COLOR);
+            }
+            overlay.text(
+                row,
+                [left + MARGIN, y],
+                TEXT_SCALE,
+                team_color(entry.team),
+                HorizontalAlign::Left,
+            );
+        }
+        overlay.text(
+            help,
+            [left + MARGIN, y + 2.0 * LINE_HEIGHT],
+            TEXT_SCALE,
+            TEXT_COLOR,
+            HorizontalAlign::Left,
+        );
+    }
+
+    fn draw_scoreboard(&self, overlay: &mut Overlay, player_id: Option<u32>) {
+        let header = scoreboard_row("Name", "Score", "K", "D", "A", "Ping");
+        let width = header.len() as f32 * TEXT_SCALE * CHARACTER_WIDTH + 2.0 * MARGIN;
+        let height = (self.scoreboard.len() + 1) as f32 * LINE_HEIGHT + 2.0 * MARGIN;
+        let left = ((overlay.width() - width) / 2.0).max(0.0);
+        let top = ((overlay.height() - height) / 2.0).max(0.0);
+        overlay.rect([left, top], [width, height], BACKGROUND_COLOR);
+
+        let mut y = top + MARGIN;
+        overlay.text(
+            &header,
+            [left + MARGIN, y],
+            TEXT_SCALE,
+            TEXT_COLOR,
+            HorizontalAlign::Left,
+        );
+        for entry in self.scoreboard.iter() {
+            y += LINE_HEIGHT;
+            if Some(entry.id) == player_id {
+                overlay.rect([left, y], [width, LINE_HEIGHT], OWN_ROW_COLOR);
+            }
+            let row = scoreboard_row(
+                &self.name(entry.id),
+                &entry.score.to_string(),
+                &entry.kills.to_string(),
+                &entry.deaths.to_string(),
+                &entry.assists.to_string(),
+                &entry.ping.to_string(),
+            );
+            overlay.text(
+                &row,
+                [left + MARGIN, y],
+                TEXT_SCALE,
+                team_color(entry.team),
+                HorizontalAlign::Left,
+            );
+        }
+    }
+
+    /// The best players, with our own stats underneath
+    fn draw_leaderboard(&self, overlay: &mut Overlay) {
+        let header = leaderboard_row("", "Name", "Wins", "Played", "K", "D", "Acc");
+        let own = self.career.as_ref().map(|stats| stats.username.as_str());
+        let width = header.len() as f32 * TEXT_SCALE * CHARACTER_WIDTH + 2.0 * MARGIN;
+        let rows = self.leaderboard.len() + 1 + if own.is_some() { 2 } else { 0 };
+        let height = rows as f32 * LINE_HEIGHT + 2.0 * MARGIN;
+        let left = ((overlay.width() - width) / 2.0).max(0.0);
+        let top = ((overlay.height() - height) / 2.0).max(0.0);
+        overlay.rect([left, top], [width, height], BACKGROUND_COLOR);
+
+        let mut y = top + MARGIN;
+        overlay.text(
+            &header,
+            [left + MARGIN, y],
+            TEXT_SCALE,
+            HIGHLIGHT_COLOR,
+            HorizontalAlign::Left,
+        );
+        for (rank, stats) in self.leaderboard.iter().enumerate() {
+            y += LINE_HEIGHT;
+            if Some(stats.username.as_str()) == own {
+                overlay.rect([left, y], [width, LINE_HEIGHT], OWN_ROW_COLOR);
+            }
+            overlay.text(
+                &stats_row(&format!("{}.", rank + 1), stats),
+                [left + MARGIN, y],
+                TEXT_SCALE,
+                TEXT_COLOR,
+                HorizontalAlign::Left,
+            );
+        }
+        if let Some(career) = self.career.as_ref() {
+            overlay.text(
+                &stats_row("You", career),
+                [left + MARGIN, y + 2.0 * LINE_HEIGHT],
+                TEXT_SCALE,
+                TEXT_COLOR,
+                HorizontalAlign::Left,
+            );
+        }
+    }
+}
+
+/// A box in the middle of the screen, numbered for the keys that join each match
+fn draw_match_list(overlay: &mut Overlay, matches: &[MatchSummary]) {
+    let mut lines = vec!["Choose a match".to_string()];
+    for (i, summary) in matches.iter().enumerate() {
+        lines.push(format!(
+            "{}. {:<20}{:<18}{:>3} players",
+            i + 1,
+            summary.name,
+            format!("{:?}", summary.mode),
+            summary.players
+        ));
+    }
+    let longest = lines.iter().map(String::len).max().unwrap_or(0);
+    let width = longest as f32 * TEXT_SCALE * CHARACTER_WIDTH + 2.0 * MARGIN;
+    let height = lines.len() as f32 * LINE_HEIGHT + 2.0 * MARGIN;
+    let left = ((overlay.width() - width) / 2.0).max(0.0);
+    let top = ((overlay.height() - height) / 2.0).max(0.0);
+    overlay.rect([left, top], [width, height], BACKGROUND_COLOR);
+    for (i, line) in lines.iter().enumerate() {
+        let color = if i == 0 { HIGHLIGHT_COLOR } else { TEXT_COLOR };
+        overlay.text(
+            line,
+            [left + MARGIN, top + MARGIN + i as f32 * LINE_HEIGHT],
+            TEXT_SCALE,
+            color,
+            HorizontalAlign::Left,
+        );
+    }
+}
+
+/// The maps to vote between, numbered for the keys that vote for them
+fn draw_vote(overlay: &mut Overlay, vote: &Vote) {
+    let time_left = vote.closes.saturating_duration_since(Instant::now());
+    let mut lines = vec![format!(
+        "Vote for the next map: {:.0}",
+        time_left.as_secs_f32()
+    )];
+    for (i, choice) in vote.choices.iter().enumerate() {
+        let votes = vote.votes.get(i).copied().unwrap_or(0);
+        lines.push(format!("{}. {:<24}{:>3}", i + 1, choice, votes));
+    }
+    let longest = lines.iter().map(String::len).max().unwrap_or(0);
+    let width = longest as f32 * TEXT_SCALE * CHARACTER_WIDTH + 2.0 * MARGIN;
+    let height = lines.len() as f32 * LINE_HEIGHT + 2.0 * MARGIN;
+    let left = ((overlay.width() - width) / 2.0).max(0.0);
+    let top = ((overlay.height() - height) / 2.0).max(0.0);
+    overlay.rect([left, top], [width, height], BACKGROUND_COLOR);
+    for (i, line) in lines.iter().enumerate() {
+        let y = top + MARGIN + i as f32 * LINE_HEIGHT;
+        if i > 0 && vote.ours == Some(i as u32 - 1) {
+            overlay.rect([left, y], [width, LINE_HEIGHT], OWN_ROW_COLOR);
+        }
+        let color = if i == 0 { HIGHLIGHT_COLOR } else { TEXT_COLOR };
+        overlay.text(
+            line,
+            [left + MARGIN, y],
+            TEXT_SCALE,
+            color,
+            HorizontalAlign::Left,
+        );
+    }
+}
@@ -60,6 +60,16 @@ impl InstanceBuffer {
             bytemuck::cast_slice(&instance_data),
         );
     }
+
+    /// Write just the one instance to the GPU
+    pub fn update_instance(&mut self, queue: &wgpu::Queue, index: usize) {
+        let instance_data = [self.instances[index].to_raw()];
+        queue.write_buffer(
+            &self.instance_buffer,
+            (index * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            bytemuck::cast_slice(&instance_data),
+        );
+    }
 }
 
 /// This is only temporarily pub
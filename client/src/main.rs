@@ -1,13 +1,22 @@
 use crate::camera::Camera;
 use crate::character::CharacterSet;
 use crate::character::DrawCharacterSet;
+use crate::flag::{DrawFlagSet, FlagSet};
 use crate::fpscounter::FpsCounter;
+use crate::hud::Hud;
 use crate::instance::{Instance, InstanceRaw};
 use crate::map::{DrawMap, Map};
+use crate::mapcache::MapDownload;
 use crate::model::Vertex;
-use ::network::{Connection, Packet};
+use crate::overlay::{DrawOverlay, Overlay};
+use crate::projectile::{DrawProjectileSet, ProjectileSet};
+use ::network::{
+    Connection, ConnectionClosed, Packet, PlayerState, SlotState, Weapons, Winner,
+    CROUCHED_EYE_HEIGHT, EYE_HEIGHT, TICKS_PER_SECOND, WALK_SPEED,
+};
 use cgmath::{InnerSpace, Rotation3, Zero};
 use log::*;
+use mapgen::{MapFile, Voxel};
 use std::time::{Duration, Instant};
 use wgpu::util::DeviceExt;
 use winit::{
@@ -18,10 +27,15 @@ use winit::{
 
 mod camera;
 mod character;
+mod flag;
 mod fpscounter;
+mod hud;
 mod instance;
 mod map;
+mod mapcache;
 mod model;
+mod overlay;
+mod projectile;
 mod texture;
 
 #[repr(C)]
@@ -32,13 +46,43 @@ struct LightUniform {
     color: [f32; 3],
 }
 
+const CROUCH_SPEED: f32 = 0.25;
+const JUMP_SPEED: f32 = 3.0;
+const GRAVITY: f32 = 9.8;
+
+/// How fast spectators fly around
+const FLY_SPEED: f32 = 4.0 * WALK_SPEED;
+
+/// What the server last told us about our weapons
+struct Inventory {
+    current: u32,
+    reloading: bool,
+    slots: Vec<SlotState>,
+}
+
+/// The weapon slot a number key selects
+fn slot_for_key(key: winit::event::VirtualKeyCode) -> Option<u32> {
+    use winit::event::VirtualKeyCode::*;
+    [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9]
+        .iter()
+        .position(|slot_key| *slot_key == key)
+        .map(|slot| slot as u32)
+}
+
 struct Player {
     position: cgmath::Point3<f32>,
+    velocity: cgmath::Vector3<f32>,
     moving_forward: bool,
     moving_backward: bool,
     moving_left: bool,
     moving_right: bool,
+    crouching: bool,
+    airborne: bool,
+    alive: bool,
     last_update: Instant,
+
+    /// While spectating, flying upwards
+    ascending: bool,
 }
 
 impl Player {
@@ -49,11 +93,31 @@ impl Player {
                 y: 0.0,
                 z: 0.0,
             },
+            velocity: cgmath::Vector3::zero(),
             moving_forward: false,
             moving_backward: false,
             moving_left: false,
             moving_right: false,
+            crouching: false,
+            airborne: false,
+            alive: true,
             last_update: Instant::now(),
+            ascending: false,
+        }
+    }
+
+    fn jump(&mut self) {
+        if !self.airborne {
+            self.airborne = true;
+            self.velocity.y = JUMP_SPEED;
+        }
+    }
+
+    fn eye_height(&self) -> f32 {
+        if self.crouching {
+            CROUCHED_EYE_HEIGHT
+        } else {
+            EYE_HEIGHT
         }
     }
 
@@ -72,9 +136,39 @@ impl Player {
         let right = if self.moving_right { 1.0 } else { 0.0 };
 
         let movement = at * forward - at * backward - side_vector * left + side_vector * right;
-        let speed = 0.5 * self.last_update.elapsed().as_secs_f32();
+        let speed = if self.crouching {
+            CROUCH_SPEED
+        } else {
+            WALK_SPEED
+        };
+        let dt = self.last_update.elapsed().as_secs_f32();
         self.last_update = Instant::now();
-        self.position += speed * movement;
+        if !self.alive {
+            return;
+        }
+
+        self.velocity.x = speed * movement.x;
+        self.velocity.z = speed * movement.z;
+        if self.airborne {
+            self.velocity.y -= GRAVITY * dt;
+        }
+        self.position += dt * self.velocity;
+        if self.position.y <= 0.0 {
+            self.position.y = 0.0;
+            self.velocity.y = 0.0;
+            self.airborne = false;
+        }
+    }
+
+    fn state(&self, camera: &Camera) -> PlayerState {
+        PlayerState {
+            position: self.position.into(),
+            velocity: self.velocity.into(),
+            yaw: camera.yaw(),
+            pitch: camera.pitch(),
+            crouching: self.crouching,
+            airborne: self.airborne,
+        }
     }
 }
 
@@ -91,6 +185,7 @@ struct State {
     instance_buffer: wgpu::Buffer,
     depth_texture: texture::Texture,
     obj_model: model::Model,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
 
     light_uniform: LightUniform,
     light_buffer: wgpu::Buffer,
@@ -98,16 +193,49 @@ struct State {
 
     network: Connection,
     character_set: CharacterSet,
+    projectile_set: ProjectileSet,
+    flag_set: FlagSet,
     player: Player,
     last_pos_update: Instant,
     cursor_locked: bool,
     fps: FpsCounter,
-    map: Map,
+
+    /// The id of our own character, once the server has created it
+    player_id: Option<u32>,
+
+    /// What we logged in as, to look up our own stats
+    username: String,
+
+    /// Set if we joined to watch, in which case we don't have a character
+    spectator: bool,
+
+    /// While spectating, the player whose view we're seeing, or None for the free
+    /// camera
+    following: Option<u32>,
+
+    /// The newest server tick we've seen, and when we saw it
+    server_tick: (u64, Instant),
+
+    /// None until the map has been received from the server
+    map: Option<Map>,
+    map_download: Option<MapDownload>,
+
+    /// Voxels destroyed before the map arrived, to remove once it has
+    removed_voxels: Vec<Voxel>,
+
+    weapons: Weapons,
+    inventory: Option<Inventory>,
+
+    overlay: Overlay,
+    hud: Hud,
+
+    /// Why the server hung up on us, once it has
+    disconnected: Option<String>,
 }
 
 impl State {
     // Creating some of the wgpu types requires async code
-    async fn new(window: &Window, network: Connection) -> Self {
+    async fn new(window: &Window, network: Connection, username: String, spectator: bool) -> Self {
         let size = window.inner_size();
 
         // GPU hande
@@ -301,8 +429,20 @@ impl State {
         .unwrap();
 
         let character_set = CharacterSet::new(&device, &queue, &texture_bind_group_layout);
+        let weapons = Weapons::load(res_dir.join("weapons.toml")).unwrap();
+        let projectile_set =
+            ProjectileSet::new(&device, &queue, &texture_bind_group_layout, &weapons);
+        let flag_set = FlagSet::new(&device, &queue, &texture_bind_group_layout);
+        let overlay = Overlay::new(
+            &device,
+            surface_config.format,
+            size,
+            res_dir.join("DejaVuSansMono.ttf"),
+        )
+        .unwrap();
         let player = Player::new();
-        let map = Map::new(&device, &queue, &texture_bind_group_layout);
+        let mut hud = Hud::new();
+        hud.spectating = spectator;
 
         Self {
             surface,
@@ -321,17 +461,32 @@ impl State {
             instance_buffer,
             depth_texture,
             obj_model,
+            texture_bind_group_layout,
             light_uniform,
             light_buffer,
             light_bind_group,
             network,
             character_set,
+            projectile_set,
+            flag_set,
             surface_config,
             player,
             last_pos_update: Instant::now(),
             cursor_locked: false,
             fps: FpsCounter::new(),
-            map,
+            player_id: None,
+            username,
+            spectator,
+            following: None,
+            server_tick: (0, Instant::now()),
+            map: None,
+            map_download: None,
+            removed_voxels: vec![],
+            weapons,
+            inventory: None,
+            overlay,
+            hud,
+            disconnected: None,
         }
     }
 
@@ -344,15 +499,23 @@ impl State {
 
             self.depth_texture =
                 texture::Texture::create_depth_texture(&self.device, &self.size, "depth texture");
+            self.overlay.resize(new_size);
         }
     }
 
     fn input(&mut self, event: &WindowEvent, window: &winit::window::Window) -> bool {
         let processed = match event {
             WindowEvent::KeyboardInput { input, .. } => {
-                if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Space)
-                    && input.state == winit::event::ElementState::Released
-                {
+                if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Space) {
+                    let pressed = input.state == winit::event::ElementState::Pressed;
+                    if self.spectator {
+                        self.player.ascending = pressed;
+                    } else if pressed {
+                        self.player.jump();
+                    }
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::LControl) {
+                    self.player.crouching = input.state == winit::event::ElementState::Pressed;
                     true
                 } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Q)
                     && input.state == winit::event::ElementState::Released
@@ -371,7 +534,71 @@ impl State {
                 } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::D) {
                     self.player.moving_right = input.state == winit::event::ElementState::Pressed;
                     true
-                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Tab)
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::R) {
+                    if input.state == winit::event::ElementState::Pressed {
+                        self.network.send(&Packet::Reload).unwrap();
+                    }
+                    true
+                } else if let Some(slot) = input.virtual_keycode.and_then(slot_for_key) {
+                    if input.state == winit::event::ElementState::Pressed {
+                        // The number keys choose a match, until one's been joined, and
+                        // vote between rounds
+                        if let Some(matches) = self.hud.matches.as_ref() {
+                            if let Some(summary) = matches.get(slot as usize) {
+                                self.network
+                                    .send(&Packet::JoinMatch { id: summary.id })
+                                    .unwrap();
+                                self.hud.matches = None;
+                            }
+                        } else if self.hud.is_voting() {
+                            self.hud.vote(slot);
+                            self.network.send(&Packet::Vote { choice: slot }).unwrap();
+                        } else {
+                            self.switch_weapon(slot);
+                        }
+                    }
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Return) {
+                    if input.state == winit::event::ElementState::Pressed && self.hud.in_lobby() {
+                        let ready = self
+                            .player_id
+                            .and_then(|id| self.hud.lobby_entry(id))
+                            .is_some_and(|entry| entry.ready);
+                        self.network.send(&Packet::Ready { ready: !ready }).unwrap();
+                    }
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::T) {
+                    if input.state == winit::event::ElementState::Pressed && self.hud.in_lobby() {
+                        let team = self
+                            .player_id
+                            .and_then(|id| self.hud.lobby_entry(id))
+                            .and_then(|entry| entry.team);
+                        if let Some(team) = team {
+                            self.network
+                                .send(&Packet::ChooseTeam {
+                                    team: team.opponent(),
+                                })
+                                .unwrap();
+                        }
+                    }
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::Tab) {
+                    self.hud.show_scoreboard = input.state == winit::event::ElementState::Pressed;
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::L) {
+                    let pressed = input.state == winit::event::ElementState::Pressed;
+                    // Fetched afresh each time, since it only changes between rounds
+                    if pressed && !self.hud.show_leaderboard {
+                        self.network.send(&Packet::GetLeaderboard).unwrap();
+                        self.network
+                            .send(&Packet::GetStats {
+                                username: self.username.clone(),
+                            })
+                            .unwrap();
+                    }
+                    self.hud.show_leaderboard = pressed;
+                    true
+                } else if input.virtual_keycode == Some(winit::event::VirtualKeyCode::LAlt)
                     && self.cursor_locked
                 {
                     self.cursor_locked = false;
@@ -404,10 +631,34 @@ impl State {
                         .set_cursor_position(winit::dpi::PhysicalPosition::new(100, 100))
                         .unwrap();
                     true
+                } else if *button == winit::event::MouseButton::Left
+                    && *state == winit::event::ElementState::Pressed
+                {
+                    if self.spectator {
+                        self.follow_next();
+                    } else {
+                        self.fire();
+                    }
+                    true
                 } else {
                     false
                 }
             }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let scroll = match delta {
+                    winit::event::MouseScrollDelta::LineDelta(_, y) => *y,
+                    winit::event::MouseScrollDelta::PixelDelta(position) => position.y as f32,
+                };
+                if let Some(inventory) = self.inventory.as_ref() {
+                    let count = inventory.slots.len() as u32;
+                    if scroll < 0.0 {
+                        self.switch_weapon((inventory.current + 1) % count);
+                    } else if scroll > 0.0 {
+                        self.switch_weapon((inventory.current + count - 1) % count);
+                    }
+                }
+                true
+            }
             _ => false,
         };
         if !processed {
@@ -419,7 +670,17 @@ impl State {
     }
 
     fn update(&mut self) {
-        let packets = self.network.packets().unwrap();
+        let packets = match self.network.packets() {
+            Ok(packets) => packets,
+            Err(e) => {
+                self.disconnected = Some(if e.is::<ConnectionClosed>() {
+                    "The server closed the connection".to_string()
+                } else {
+                    format!("{:#}", e)
+                });
+                return;
+            }
+        };
         for packet in packets.iter() {
             match packet {
                 Packet::CreateCharacter {
@@ -427,9 +688,15 @@ impl State {
                     username,
                     position,
                     is_owned,
+                    team,
                 } => {
+                    if let Some(team) = team {
+                        info!("Player {} is on the {:?} team", id, team);
+                    }
+                    self.hud.add_player(*id, username);
                     if *is_owned {
                         // This is the player character
+                        self.player_id = Some(*id);
                         self.player.position = cgmath::Point3 {
                             x: position[0],
                             y: position[1],
@@ -439,29 +706,287 @@ impl State {
                         self.character_set.add(*id, position.clone());
                     }
                 }
-                Packet::UpdatePosition { id, position } => {
-                    self.character_set.update_position(*id, position.clone());
+                Packet::UpdateState { id, tick, state } => {
+                    if *tick > self.server_tick.0 {
+                        self.server_tick = (*tick, Instant::now());
+                    }
+                    self.character_set.update_state(*id, state);
+                }
+                Packet::MapInfo { hash, size } => {
+                    self.unload_map();
+                    self.fetch_map(hash, *size);
+                }
+                Packet::PlayerHit {
+                    shooter,
+                    target,
+                    damage,
+                } => {
+                    if Some(*shooter) == self.player_id {
+                        info!("Hit player {} for {} damage", target, damage);
+                    } else if Some(*target) == self.player_id {
+                        info!("Hit by player {} for {} damage", shooter, damage);
+                    }
+                }
+                Packet::HealthChanged { id, health } => {
+                    if Some(*id) == self.player_id {
+                        info!("Health is now {}", health);
+                    }
+                }
+                Packet::PlayerKilled {
+                    killer,
+                    victim,
+                    weapon,
+                    assists,
+                } => {
+                    info!("Player {} killed player {}", killer, victim);
+                    let weapon = self
+                        .weapons
+                        .get(*weapon)
+                        .map_or("unknown weapon", |definition| definition.name.as_str());
+                    self.hud
+                        .player_killed(*killer, *victim, weapon, assists, self.player_id);
+                    if Some(*victim) == self.player_id {
+                        self.player.alive = false;
+                    } else {
+                        self.character_set.set_alive(*victim, false);
+                    }
+                }
+                Packet::Respawn { id, position, team } => {
+                    if let Some(team) = team {
+                        info!("Player {} is on the {:?} team", id, team);
+                    }
+                    if Some(*id) == self.player_id {
+                        self.player.alive = true;
+                        self.player.position = (*position).into();
+                        self.player.velocity = cgmath::Vector3::zero();
+                    } else {
+                        self.character_set.set_alive(*id, true);
+                        self.character_set.update_state(
+                            *id,
+                            &PlayerState {
+                                position: *position,
+                                ..PlayerState::default()
+                            },
+                        );
+                    }
+                }
+                Packet::MapSeed {
+                    version,
+                    spec,
+                    seed,
+                    hash,
+                    size,
+                } => {
+                    self.unload_map();
+                    if *version == mapgen::ALGORITHM_VERSION {
+                        let data = mapgen::generate_map(spec, *seed).to_bytes();
+                        if ::network::content_hash(&data) == *hash {
+                            info!("Generated map from seed {}", seed);
+                            self.load_map(&data);
+                            continue;
+                        }
+                        warn!("Generated map does not match the server's");
+                    } else {
+                        info!(
+                            "Server map generator is version {}, we have {}",
+                            version,
+                            mapgen::ALGORITHM_VERSION
+                        );
+                    }
+                    self.fetch_map(hash, *size);
+                }
+                Packet::MapChunk { offset, data } => {
+                    // If the map changed since we asked for it, the new one might
+                    // have been in the cache
+                    let download = match self.map_download.as_mut() {
+                        Some(download) => download,
+                        None => continue,
+                    };
+                    if let Some(data) = download.receive(*offset, data).unwrap() {
+                        if let Err(e) = mapcache::store(download.hash(), &data) {
+                            warn!("Couldn't cache map: {:?}", e);
+                        }
+                        self.map_download = None;
+                        self.load_map(&data);
+                    }
+                }
+                Packet::CreateProjectile {
+                    id,
+                    weapon,
+                    position,
+                    velocity,
+                } => {
+                    self.projectile_set.add(*id, *weapon, *position, *velocity);
+                }
+                Packet::UpdateProjectile {
+                    id,
+                    position,
+                    velocity,
+                } => {
+                    self.projectile_set
+                        .update_projectile(*id, *position, *velocity);
+                }
+                Packet::Explosion {
+                    id,
+                    position,
+                    radius,
+                } => {
+                    debug!("Explosion of radius {} at {:?}", radius, position);
+                    self.projectile_set.remove(*id);
+                }
+                Packet::VoxelsChanged { removed } => match self.map.as_mut() {
+                    Some(map) => map.remove_voxels(removed),
+                    None => self.removed_voxels.extend_from_slice(removed),
+                },
+                Packet::Inventory {
+                    current,
+                    reloading,
+                    slots,
+                } => {
+                    let slot = slots[*current as usize];
+                    let name = self
+                        .weapons
+                        .get(slot.weapon)
+                        .map_or("unknown weapon", |definition| definition.name.as_str());
+                    if *reloading {
+                        info!("Reloading {}", name);
+                    } else {
+                        info!("Holding {} with {}/{}", name, slot.magazine, slot.reserve);
+                    }
+                    self.inventory = Some(Inventory {
+                        current: *current,
+                        reloading: *reloading,
+                        slots: slots.clone(),
+                    });
+                }
+                Packet::GameMode {
+                    kind,
+                    score_limit,
+                    time_limit,
+                } => match time_limit {
+                    Some(time_limit) => info!(
+                        "Playing {:?} to {} points in {} seconds",
+                        kind, score_limit, time_limit
+                    ),
+                    None => info!("Playing {:?} to {} points", kind, score_limit),
+                },
+                Packet::RoundStarted { time_left } => {
+                    self.hud.end_vote();
+                    match time_left {
+                        Some(time_left) => info!("Round started, {} seconds left", time_left),
+                        None => info!("Round started"),
+                    }
+                }
+                Packet::RoundEnded { winner } => match winner {
+                    Winner::Player(id) if Some(*id) == self.player_id => info!("You win!"),
+                    Winner::Player(id) => info!("Player {} wins", id),
+                    Winner::Team(team) => info!("{:?} team wins", team),
+                    Winner::Draw => info!("It's a draw"),
+                },
+                Packet::PlayerScore { id, score } => {
+                    info!("Player {} has {} points", id, score);
                 }
-                Packet::Login { .. } => {
+                Packet::TeamScore { team, score } => {
+                    info!("{:?} team has {} points", team, score);
+                }
+                Packet::FlagChanged {
+                    team,
+                    position,
+                    carrier,
+                } => {
+                    match carrier {
+                        Some(carrier) => info!("Player {} has the {:?} flag", carrier, team),
+                        None => info!("The {:?} flag is at {:?}", team, position),
+                    }
+                    self.flag_set.update_flag(*team, *position, *carrier);
+                }
+                Packet::Ping { id } => {
+                    self.network.send(&Packet::Pong { id: *id }).unwrap();
+                }
+                Packet::Scoreboard { entries } => {
+                    self.hud.update_scoreboard(entries.clone());
+                }
+                Packet::PlayerLeft { id } => {
+                    info!("Player {} left", id);
+                    self.character_set.remove(*id);
+                }
+                Packet::DestroyCharacter { id } => {
+                    self.character_set.remove(*id);
+                }
+                Packet::Disconnected { reason } => {
+                    self.disconnected = Some(reason.clone());
+                    return;
+                }
+                Packet::MapVote { choices, time_left } => {
+                    self.hud.start_vote(choices.clone(), *time_left);
+                }
+                Packet::VoteTally { votes } => {
+                    self.hud.update_votes(votes.clone());
+                }
+                Packet::PhaseChanged { phase } => {
+                    info!("Match phase: {:?}", phase);
+                    self.hud.set_phase(*phase);
+                }
+                Packet::Lobby { players, min_ready } => {
+                    self.hud.set_lobby(players.clone(), *min_ready);
+                }
+                Packet::MatchList { matches } => {
+                    self.hud.matches = Some(matches.clone());
+                }
+                Packet::ServerMessage { text } => {
+                    info!("Server says: {}", text);
+                    self.hud.message(text);
+                }
+                Packet::AdminResponse { text } => {
+                    info!("{}", text);
+                }
+                Packet::Stats { stats } => {
+                    self.hud.career = Some(stats.clone());
+                }
+                Packet::Leaderboard { entries } => {
+                    self.hud.leaderboard = entries.clone();
+                }
+                Packet::Login { .. }
+                | Packet::JoinMatch { .. }
+                | Packet::Ready { .. }
+                | Packet::Vote { .. }
+                | Packet::ChooseTeam { .. }
+                | Packet::Pong { .. }
+                | Packet::AdminLogin { .. }
+                | Packet::AdminCommand { .. }
+                | Packet::GetStats { .. }
+                | Packet::GetLeaderboard
+                | Packet::RequestMap
+                | Packet::Fire { .. }
+                | Packet::SwitchWeapon { .. }
+                | Packet::Reload => {
                     panic!("Impossible packet");
                 }
             }
         }
 
-        if self.last_pos_update.elapsed() > Duration::from_millis(50) {
-            self.last_pos_update = Instant::now();
-            self.network
-                .send(&Packet::UpdatePosition {
-                    id: 0,
-                    position: self.player.position.into(),
-                })
-                .unwrap();
-        }
-
         // Update the camera
-        self.player.update(&self.camera);
-        self.camera.set_position(&self.player.position);
+        if self.spectator {
+            self.update_spectator_camera();
+        } else {
+            if self.last_pos_update.elapsed() > Duration::from_millis(50) {
+                self.last_pos_update = Instant::now();
+                self.network
+                    .send(&Packet::UpdateState {
+                        id: 0,
+                        tick: 0,
+                        state: self.player.state(&self.camera),
+                    })
+                    .unwrap();
+            }
+            self.player.update(&self.camera);
+            self.camera
+                .set_position(&self.player.position, self.player.eye_height());
+        }
         self.camera.update(&self.queue);
+        self.character_set.update();
+        self.flag_set.update(&self.character_set);
+        self.projectile_set.update();
 
         // Move the light
         let old_position: cgmath::Vector3<_> = self.light_uniform.position.into();
@@ -476,6 +1001,122 @@ impl State {
         );
     }
 
+    /// Fly the free camera through walls and all, or look out of the eyes of the
+    /// player being followed
+    fn update_spectator_camera(&mut self) {
+        let dt = self.player.last_update.elapsed().as_secs_f32();
+        self.player.last_update = Instant::now();
+        if let Some(id) = self.following {
+            // The view stays put while they're dead
+            if let Some(state) = self.character_set.state(id) {
+                self.camera
+                    .set_position(&state.position.into(), state.eye_height());
+                self.camera.look(state.yaw, state.pitch);
+            }
+            return;
+        }
+
+        let at = self.camera.at().normalize();
+        let side = at.cross(cgmath::Vector3::unit_y()).normalize();
+        let mut movement = cgmath::Vector3::zero();
+        for (moving, direction) in [
+            (self.player.moving_forward, at),
+            (self.player.moving_backward, -at),
+            (self.player.moving_left, -side),
+            (self.player.moving_right, side),
+            (self.player.ascending, cgmath::Vector3::unit_y()),
+            (self.player.crouching, -cgmath::Vector3::unit_y()),
+        ] {
+            if moving {
+                movement += direction;
+            }
+        }
+        if movement.magnitude2() > 0.0 {
+            self.camera.move_by(movement.normalize() * FLY_SPEED * dt);
+        }
+    }
+
+    /// Follow the next player along, or go back to the free camera after the last
+    fn follow_next(&mut self) {
+        let ids = self.character_set.ids();
+        self.following = match self.following {
+            None => ids.first().copied(),
+            Some(current) => ids.into_iter().find(|id| *id > current),
+        };
+        self.character_set.hide(self.following);
+        self.hud.following = self.following;
+    }
+
+    fn switch_weapon(&mut self, slot: u32) {
+        if let Some(inventory) = self.inventory.as_ref() {
+            if slot != inventory.current && (slot as usize) < inventory.slots.len() {
+                self.network.send(&Packet::SwitchWeapon { slot }).unwrap();
+            }
+        }
+    }
+
+    fn fire(&mut self) {
+        if !self.player.alive {
+            return;
+        }
+        // The server would ignore the shot anyway
+        if let Some(inventory) = self.inventory.as_ref() {
+            let slot = inventory.slots[inventory.current as usize];
+            let has_magazine = self
+                .weapons
+                .get(slot.weapon)
+                .is_some_and(|definition| definition.magazine_size.is_some());
+            if inventory.reloading || (has_magazine && slot.magazine == 0) {
+                return;
+            }
+        }
+        // Other characters are drawn extrapolated from the last state we received, so
+        // we see the world as of roughly that tick plus the time since
+        let (tick, received) = self.server_tick;
+        let view_tick = tick + (received.elapsed().as_secs_f32() * TICKS_PER_SECOND as f32) as u64;
+        self.network
+            .send(&Packet::Fire {
+                direction: self.camera.at().normalize().into(),
+                view_tick,
+            })
+            .unwrap();
+    }
+
+    /// Forget the map being played, when the server moves on to another. Voxels
+    /// destroyed on the new one are kept until it's loaded.
+    fn unload_map(&mut self) {
+        self.map = None;
+        self.map_download = None;
+        self.removed_voxels.clear();
+    }
+
+    /// Load the map from the cache, or download it if we don't have it
+    fn fetch_map(&mut self, hash: &[u8; 32], size: u32) {
+        match mapcache::load(hash) {
+            Some(data) => {
+                info!("Using cached map");
+                self.load_map(&data);
+            }
+            None => {
+                info!("Downloading {} byte map", size);
+                self.map_download = Some(MapDownload::new(*hash, size));
+                self.network.send(&Packet::RequestMap).unwrap();
+            }
+        }
+    }
+
+    fn load_map(&mut self, data: &[u8]) {
+        let map = MapFile::from_bytes(data).unwrap();
+        let mut map = Map::new(
+            &self.device,
+            &self.queue,
+            &self.texture_bind_group_layout,
+            map,
+        );
+        map.remove_voxels(&std::mem::take(&mut self.removed_voxels));
+        self.map = Some(map);
+    }
+
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         self.fps.frame();
 
@@ -522,7 +1163,29 @@ impl State {
             render_pass.draw_model_instanced(&self.obj_model, 0..self.instances.len() as u32);
 
             render_pass.draw_character_set(&self.queue, &mut self.character_set);
-            render_pass.draw_map(&self.queue, &mut self.map);
+            render_pass.draw_projectile_set(&self.queue, &mut self.projectile_set);
+            render_pass.draw_flag_set(&self.queue, &mut self.flag_set);
+            if let Some(map) = self.map.as_mut() {
+                render_pass.draw_map(&self.queue, map);
+            }
+        }
+
+        self.hud.draw(&mut self.overlay, self.player_id);
+        self.overlay.prepare(&self.device, &self.queue);
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Overlay Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.draw_overlay(&self.overlay);
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -538,16 +1201,33 @@ fn main() {
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
+    // --spectate can go anywhere, to watch instead of playing
+    let spectator = std::env::args().any(|arg| arg == "--spectate");
+    let mut args = std::env::args().skip(1).filter(|arg| arg != "--spectate");
+    let username = args.next().unwrap_or_else(|| "player".to_string());
+    let password = args.next();
+    let encoded = ::network::encode_username(&username);
     let connection = Connection::connect().unwrap();
     connection
-        .send(&Packet::Login { username: [5; 20] })
+        .send(&Packet::Login {
+            username: encoded,
+            password,
+            spectator,
+        })
         .unwrap();
 
-    let mut state = pollster::block_on(State::new(&window, connection));
+    // Long names are cut short, and the server knows us by the short version
+    let username = ::network::decode_username(&encoded);
+    let mut state = pollster::block_on(State::new(&window, connection, username, spectator));
 
     event_loop.run(move |event, _, control_flow| match event {
         Event::RedrawRequested(_) => {
             state.update();
+            if let Some(reason) = state.disconnected.as_ref() {
+                println!("Disconnected from the server: {}", reason);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
             match state.render() {
                 Ok(_) => {}
                 //Err(wgpu::SwapChainErrors::Lost) => state.resize(state.size),
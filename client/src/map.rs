@@ -2,38 +2,33 @@ use crate::instance::{Instance, InstanceBuffer};
 use crate::model::{DrawModel, Model};
 use cgmath::Rotation3;
 use log::*;
-use serde_derive::Deserialize;
+use mapgen::{MapFile, Voxel, VOXEL_SIZE};
 use std::collections::HashMap;
-use std::io::Read;
-
-#[derive(Deserialize)]
-pub struct Voxel {
-    x: u16,
-    y: u16,
-    z: u16,
-}
 
 pub struct Map {
     voxel_model: Model,
+
+    /// In the same order as their instances
     voxels: Vec<Voxel>,
+
+    /// Where each voxel is in voxels
+    indices: HashMap<Voxel, usize>,
     instance_buffer: InstanceBuffer,
+
+    /// Instances which have changed since they were last written to the GPU
+    dirty: Vec<usize>,
 }
 
 impl Map {
-    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        map: MapFile,
+    ) -> Self {
         let res_dir = std::path::Path::new(".").join("res");
 
-        let mut voxels = vec![];
-        let mut f = std::fs::File::open("map.bin").unwrap();
-        let mut len = [0; 4];
-        f.read_exact(&mut len).unwrap();
-        let len = u32::from_le_bytes(len);
-        for _ in 0..len {
-            let mut voxel = [0; 6]; // TODO: Don't hardcode this voxel data length
-            f.read_exact(&mut voxel).unwrap();
-            let voxel: Voxel = bincode::deserialize(&voxel).unwrap();
-            voxels.push(voxel);
-        }
+        let voxels = map.voxels;
 
         let mut buf = InstanceBuffer::new(device, voxels.len());
         for (i, voxel) in voxels.iter().enumerate() {
@@ -49,17 +44,39 @@ impl Map {
                 ),
             });
             buf.instances[i].position = cgmath::Vector3 {
-                x: voxel.x as f32 * 0.2,
-                y: voxel.y as f32 * 0.2,
-                z: voxel.z as f32 * 0.2,
+                x: voxel.x as f32 * VOXEL_SIZE,
+                y: voxel.y as f32 * VOXEL_SIZE,
+                z: voxel.z as f32 * VOXEL_SIZE,
             };
         }
         buf.update(queue);
 
         Self {
             voxel_model: Model::load(device, queue, layout, res_dir.join("voxel.obj")).unwrap(),
-            voxels: voxels,
+            indices: voxels.iter().enumerate().map(|(i, v)| (*v, i)).collect(),
+            voxels,
             instance_buffer: buf,
+            dirty: vec![],
+        }
+    }
+
+    /// Stop drawing the given voxels. Each one is swapped with the last instance, so
+    /// only the instances that moved need writing to the GPU again.
+    pub fn remove_voxels(&mut self, removed: &[Voxel]) {
+        for voxel in removed.iter() {
+            let index = match self.indices.remove(voxel) {
+                Some(index) => index,
+                None => {
+                    warn!("Removing voxel {:?} which isn't there", voxel);
+                    continue;
+                }
+            };
+            self.voxels.swap_remove(index);
+            self.instance_buffer.instances.swap_remove(index);
+            if let Some(moved) = self.voxels.get(index) {
+                self.indices.insert(*moved, index);
+                self.dirty.push(index);
+            }
         }
     }
 }
@@ -73,7 +90,11 @@ where
     'b: 'a,
 {
     fn draw_map(&mut self, queue: &'_ wgpu::Queue, map: &'b mut Map) {
-        map.instance_buffer.update(queue);
+        for index in map.dirty.drain(..) {
+            if index < map.voxels.len() {
+                map.instance_buffer.update_instance(queue, index);
+            }
+        }
         self.set_vertex_buffer(1, map.instance_buffer.instance_buffer.slice(..));
         self.draw_model_instanced(&map.voxel_model, 0..map.voxels.len() as u32);
     }
@@ -0,0 +1,71 @@
+use anyhow::*;
+use log::*;
+use std::path::PathBuf;
+
+fn path(hash: &[u8; 32]) -> PathBuf {
+    let name: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    std::path::Path::new(".")
+        .join("cache")
+        .join("maps")
+        .join(format!("{}.bin", name))
+}
+
+/// Returns the cached map with the given hash, if we have it and it isn't corrupt
+pub fn load(hash: &[u8; 32]) -> Option<Vec<u8>> {
+    let data = std::fs::read(path(hash)).ok()?;
+    if network::content_hash(&data) != *hash {
+        warn!("Ignoring corrupt cached map {:?}", path(hash));
+        return None;
+    }
+    Some(data)
+}
+
+pub fn store(hash: &[u8; 32], data: &[u8]) -> Result<()> {
+    let path = path(hash);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, data).with_context(|| format!("Writing {:?}", path))?;
+    Ok(())
+}
+
+/// A map being received from the server in MapChunk packets
+pub struct MapDownload {
+    hash: [u8; 32],
+    size: usize,
+    data: Vec<u8>,
+}
+
+impl MapDownload {
+    pub fn new(hash: [u8; 32], size: u32) -> Self {
+        Self {
+            hash,
+            size: size as usize,
+            data: Vec::with_capacity(size as usize),
+        }
+    }
+
+    /// Add a chunk. Once the map is complete, the verified data is returned.
+    pub fn receive(&mut self, offset: u32, chunk: &[u8]) -> Result<Option<Vec<u8>>> {
+        if offset as usize != self.data.len() {
+            bail!(
+                "Map chunk at offset {} but expected {}",
+                offset,
+                self.data.len()
+            );
+        }
+        if self.data.len() + chunk.len() > self.size {
+            bail!("Map data exceeds the advertised {} bytes", self.size);
+        }
+        self.data.extend_from_slice(chunk);
+        if self.data.len() < self.size {
+            return Ok(None);
+        }
+        if network::content_hash(&self.data) != self.hash {
+            bail!("Map data does not match its hash");
+        }
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+}
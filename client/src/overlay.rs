@@ -0,0 +1,393 @@
+use anyhow::*;
+use glyph_brush::ab_glyph::{FontArc, Rect};
+use glyph_brush::{
+    BrushAction, BrushError, GlyphBrush, GlyphBrushBuilder, GlyphVertex, HorizontalAlign, Layout,
+    Section, Text,
+};
+use std::path::Path;
+
+/// The glyph cache starts this big, and grows if it fills up
+const INITIAL_CACHE_SIZE: u32 = 256;
+
+/// Flat shapes are marked by texture coordinates outside the glyph cache
+const NO_TEXTURE: [f32; 2] = [-1.0, -1.0];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct OverlayVertex {
+    /// In pixels while queued, and clip space once uploaded
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+}
+
+// Nothing but f32s, so no padding. Implemented by hand because the derives leave
+// behind an unused padding check.
+unsafe impl bytemuck::Zeroable for OverlayVertex {}
+unsafe impl bytemuck::Pod for OverlayVertex {}
+
+impl OverlayVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<OverlayVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Two triangles covering a rectangle
+fn quad(pixels: Rect, tex_coords: Rect, color: [f32; 4]) -> [OverlayVertex; 6] {
+    // Corners are picked by their x and y, 0 for min and 1 for max
+    let corner = |x: usize, y: usize| OverlayVertex {
+        position: [
+            [pixels.min.x, pixels.max.x][x],
+            [pixels.min.y, pixels.max.y][y],
+        ],
+        tex_coords: [
+            [tex_coords.min.x, tex_coords.max.x][x],
+            [tex_coords.min.y, tex_coords.max.y][y],
+        ],
+        color,
+    };
+    [
+        corner(0, 0),
+        corner(0, 1),
+        corner(1, 1),
+        corner(0, 0),
+        corner(1, 1),
+        corner(1, 0),
+    ]
+}
+
+/// Text and flat rectangles drawn over the top of everything else, in pixels from
+/// the top left of the window. Everything is queued up each frame and drawn in the
+/// order it was queued, with all the rectangles underneath all the text.
+pub struct Overlay {
+    glyph_brush: GlyphBrush<[OverlayVertex; 6]>,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    cache: wgpu::Texture,
+    bind_group: wgpu::BindGroup,
+
+    vertex_buffer: wgpu::Buffer,
+    vertex_capacity: usize,
+    vertex_count: u32,
+
+    /// The glyphs as last laid out, which are reused while the text doesn't change
+    glyphs: Vec<[OverlayVertex; 6]>,
+    rects: Vec<[OverlayVertex; 6]>,
+
+    /// The window size in pixels
+    size: [f32; 2],
+}
+
+impl Overlay {
+    pub fn new<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+        font: P,
+    ) -> Result<Self> {
+        let font = FontArc::try_from_vec(std::fs::read(font)?)?;
+        let glyph_brush = GlyphBrushBuilder::using_font(font)
+            .initial_cache_size((INITIAL_CACHE_SIZE, INITIAL_CACHE_SIZE))
+            .build();
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("overlay_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let cache = Self::create_cache(device, INITIAL_CACHE_SIZE, INITIAL_CACHE_SIZE);
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &cache, &sampler);
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("overlay shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("overlay.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Overlay Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Overlay pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main",
+                buffers: &[OverlayVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                clamp_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        });
+
+        let vertex_capacity = 1024;
+        Ok(Self {
+            glyph_brush,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            cache,
+            bind_group,
+            vertex_buffer: Self::create_vertex_buffer(device, vertex_capacity),
+            vertex_capacity,
+            vertex_count: 0,
+            glyphs: vec![],
+            rects: vec![],
+            size: [size.width as f32, size.height as f32],
+        })
+    }
+
+    fn create_cache(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("glyph cache"),
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        cache: &wgpu::Texture,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        let view = cache.create_view(&wgpu::TextureViewDescriptor::default());
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("overlay_bind_group"),
+        })
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Overlay Vertex Buffer"),
+            size: (capacity * std::mem::size_of::<OverlayVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        self.size = [size.width as f32, size.height as f32];
+    }
+
+    pub fn width(&self) -> f32 {
+        self.size[0]
+    }
+
+    pub fn height(&self) -> f32 {
+        self.size[1]
+    }
+
+    /// Queue a filled rectangle
+    pub fn rect(&mut self, position: [f32; 2], size: [f32; 2], color: [f32; 4]) {
+        let pixels = Rect {
+            min: position.into(),
+            max: [position[0] + size[0], position[1] + size[1]].into(),
+        };
+        let tex_coords = Rect {
+            min: NO_TEXTURE.into(),
+            max: NO_TEXTURE.into(),
+        };
+        self.rects.push(quad(pixels, tex_coords, color));
+    }
+
+    /// Queue a line of text. The position is its top left, top right or top middle,
+    /// depending on the alignment.
+    pub fn text(
+        &mut self,
+        text: &str,
+        position: [f32; 2],
+        scale: f32,
+        color: [f32; 4],
+        align: HorizontalAlign,
+    ) {
+        self.glyph_brush.queue(
+            Section::default()
+                .add_text(Text::new(text).with_scale(scale).with_color(color))
+                .with_screen_position((position[0], position[1]))
+                .with_layout(Layout::default_single_line().h_align(align)),
+        );
+    }
+
+    /// Lay out the queued text and upload everything queued this frame
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let to_vertex =
+            |vertex: GlyphVertex| quad(vertex.pixel_coords, vertex.tex_coords, vertex.extra.color);
+        loop {
+            let cache = &self.cache;
+            let update_cache = |rect: glyph_brush::Rectangle<u32>, data: &[u8]| {
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: cache,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d {
+                            x: rect.min[0],
+                            y: rect.min[1],
+                            z: 0,
+                        },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    data,
+                    wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: std::num::NonZeroU32::new(rect.width()),
+                        rows_per_image: std::num::NonZeroU32::new(rect.height()),
+                    },
+                    wgpu::Extent3d {
+                        width: rect.width(),
+                        height: rect.height(),
+                        depth_or_array_layers: 1,
+                    },
+                );
+            };
+            match self.glyph_brush.process_queued(update_cache, to_vertex) {
+                Ok(BrushAction::Draw(glyphs)) => {
+                    self.glyphs = glyphs;
+                    break;
+                }
+                Ok(BrushAction::ReDraw) => break,
+                Err(BrushError::TextureTooSmall { suggested }) => {
+                    let (width, height) = suggested;
+                    self.cache = Self::create_cache(device, width, height);
+                    self.bind_group = Self::create_bind_group(
+                        device,
+                        &self.bind_group_layout,
+                        &self.cache,
+                        &self.sampler,
+                    );
+                    self.glyph_brush.resize_texture(width, height);
+                }
+            }
+        }
+
+        let [width, height] = self.size;
+        let vertices: Vec<OverlayVertex> = self
+            .rects
+            .drain(..)
+            .chain(self.glyphs.iter().copied())
+            .flatten()
+            .map(|vertex| OverlayVertex {
+                position: [
+                    vertex.position[0] / width * 2.0 - 1.0,
+                    1.0 - vertex.position[1] / height * 2.0,
+                ],
+                ..vertex
+            })
+            .collect();
+        if vertices.len() > self.vertex_capacity {
+            self.vertex_capacity = vertices.len().next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.vertex_capacity);
+        }
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        self.vertex_count = vertices.len() as u32;
+    }
+}
+
+pub trait DrawOverlay<'a> {
+    fn draw_overlay(&mut self, overlay: &'a Overlay);
+}
+
+impl<'a, 'b> DrawOverlay<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_overlay(&mut self, overlay: &'b Overlay) {
+        if overlay.vertex_count == 0 {
+            return;
+        }
+        self.set_pipeline(&overlay.pipeline);
+        self.set_bind_group(0, &overlay.bind_group, &[]);
+        self.set_vertex_buffer(0, overlay.vertex_buffer.slice(..));
+        self.draw(0..overlay.vertex_count, 0..1);
+    }
+}
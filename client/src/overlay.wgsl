@@ -0,0 +1,37 @@
+// Text and flat shapes drawn over the scene. Positions are already in clip space.
+
+struct VertexInput {
+    [[location(0)]] position: vec2<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    [[location(2)]] color: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] color: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(vertex: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(vertex.position, 0.0, 1.0);
+    out.tex_coords = vertex.tex_coords;
+    out.color = vertex.color;
+    return out;
+}
+
+// Fragment shader
+
+[[group(0), binding(0)]]
+var t_glyphs: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_glyphs: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // Flat shapes have negative texture coordinates, and are solid
+    let coverage = textureSample(t_glyphs, s_glyphs, in.tex_coords).r;
+    let alpha = select(coverage, 1.0, in.tex_coords.x < 0.0);
+    return vec4<f32>(in.color.rgb, in.color.a * alpha);
+}
@@ -0,0 +1,142 @@
+use crate::instance::{Instance, InstanceBuffer};
+use crate::model::{DrawModel, Model};
+use cgmath::Rotation3;
+use log::*;
+use network::{Firing, WeaponId, Weapons};
+use std::collections::HashMap;
+use std::time::Instant;
+
+const MAX_INSTANCES: usize = 256;
+
+/// Don't extrapolate positions further than this past the last update, in seconds
+const MAX_EXTRAPOLATION: f32 = 0.25;
+
+pub struct Projectile {
+    weapon: WeaponId,
+    position: cgmath::Point3<f32>,
+    velocity: cgmath::Vector3<f32>,
+    last_update: Instant,
+}
+
+impl Projectile {
+    fn instance(&self) -> Instance {
+        let dt = self
+            .last_update
+            .elapsed()
+            .as_secs_f32()
+            .min(MAX_EXTRAPOLATION);
+        let position = self.position + self.velocity * dt;
+        Instance {
+            position: cgmath::Vector3::new(position.x, position.y, position.z),
+            rotation: cgmath::Quaternion::from_axis_angle(
+                cgmath::Vector3::unit_y(),
+                cgmath::Deg(0.0),
+            ),
+        }
+    }
+}
+
+/// The projectiles fired by one weapon, which all look the same
+struct ProjectileGroup {
+    weapon: WeaponId,
+    model: Model,
+    instance_buffer: InstanceBuffer,
+}
+
+/// Rockets, grenades and anything else in flight. Unlike characters these come and
+/// go often, so the instances are rebuilt from scratch every frame.
+pub struct ProjectileSet {
+    groups: Vec<ProjectileGroup>,
+    projectiles: HashMap<u32, Projectile>,
+}
+
+impl ProjectileSet {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        weapons: &Weapons,
+    ) -> Self {
+        let res_dir = std::path::Path::new(".").join("res");
+        let groups = weapons
+            .iter()
+            .filter_map(|(weapon, definition)| match &definition.firing {
+                Firing::Projectile(ballistics) => Some(ProjectileGroup {
+                    weapon,
+                    model: Model::load(device, queue, layout, res_dir.join(&ballistics.model))
+                        .unwrap(),
+                    instance_buffer: InstanceBuffer::new(device, MAX_INSTANCES),
+                }),
+                _ => None,
+            })
+            .collect();
+        Self {
+            groups,
+            projectiles: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, id: u32, weapon: WeaponId, position: [f32; 3], velocity: [f32; 3]) {
+        debug!(
+            "Creating projectile from weapon {} at {:?}",
+            weapon, position
+        );
+        self.projectiles.insert(
+            id,
+            Projectile {
+                weapon,
+                position: position.into(),
+                velocity: velocity.into(),
+                last_update: Instant::now(),
+            },
+        );
+    }
+
+    pub fn update_projectile(&mut self, id: u32, position: [f32; 3], velocity: [f32; 3]) {
+        if let Some(projectile) = self.projectiles.get_mut(&id) {
+            projectile.position = position.into();
+            projectile.velocity = velocity.into();
+            projectile.last_update = Instant::now();
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if self.projectiles.remove(&id).is_some() {
+            debug!("Projectile {} exploded", id);
+        }
+    }
+
+    /// Move every projectile's instance to its extrapolated position
+    pub fn update(&mut self) {
+        for group in self.groups.iter_mut() {
+            group.instance_buffer.instances = self
+                .projectiles
+                .values()
+                .filter(|projectile| projectile.weapon == group.weapon)
+                .take(MAX_INSTANCES)
+                .map(Projectile::instance)
+                .collect();
+        }
+    }
+}
+
+pub trait DrawProjectileSet<'a> {
+    fn draw_projectile_set(&mut self, queue: &'_ wgpu::Queue, projectiles: &'a mut ProjectileSet);
+}
+
+impl<'a, 'b> DrawProjectileSet<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_projectile_set(&mut self, queue: &'_ wgpu::Queue, projectiles: &'b mut ProjectileSet) {
+        for group in projectiles.groups.iter_mut() {
+            group.instance_buffer.update(queue);
+            let group: &'b ProjectileGroup = group;
+            self.set_vertex_buffer(1, group.instance_buffer.instance_buffer.slice(..));
+            self.draw_model_instanced(
+                &group.model,
+                0..group.instance_buffer.instances.len() as u32,
+            );
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Checks that every spawn point in a map can be walked to from every other, so no
+//! player starts somewhere they can't get out of.
+//!
+//!     validate [map.bin]
+
+use anyhow::*;
+use mapgen::nav::{NavGrid, NavSettings};
+use mapgen::MapFile;
+
+fn main() -> Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "map.bin".to_string());
+    let map = MapFile::load(&path)?;
+    let nav = NavGrid::new(&map.voxels, NavSettings::default());
+    println!(
+        "{}: {} voxels, {} spawn points",
+        path,
+        map.voxels.len(),
+        map.spawn_points.len()
+    );
+
+    let mut problems = 0;
+    for (i, spawn) in map.spawn_points.iter().enumerate() {
+        if !nav.is_walkable(*spawn) {
+            println!("Spawn point {} at {:?} has no room to stand", i, spawn);
+            problems += 1;
+        }
+    }
+    // If every spawn point can get to and from the first one, they can all get to
+    // each other by way of it
+    let (first, others) = map
+        .spawn_points
+        .split_first()
+        .context("Map has no spawn points")?;
+    for (i, spawn) in others.iter().enumerate() {
+        if !nav.is_walkable(*spawn) {
+            continue;
+        }
+        if nav.find_path(*first, *spawn).is_none() {
+            println!("Spawn point {} at {:?} can't be reached", i + 1, spawn);
+            problems += 1;
+        } else if nav.find_path(*spawn, *first).is_none() {
+            println!("Spawn point {} at {:?} can't be left", i + 1, spawn);
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        bail!("{} problems found", problems);
+    }
+    println!("OK");
+    Ok(())
+}
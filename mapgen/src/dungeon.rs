@@ -0,0 +1,403 @@
+use crate::{room_center, MapFile, Voxel};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever generate_dungeon would produce a different map for the same
+/// specification and seed, so that peers know whether they can regenerate a map
+/// rather than downloading it.
+pub const ALGORITHM_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DungeonSpecification {
+    pub rooms_wide: u16,
+    pub rooms_deep: u16,
+    pub rooms_tall: u16,
+}
+
+impl DungeonSpecification {
+    fn num_rooms(&self) -> usize {
+        self.rooms_deep as usize * self.rooms_tall as usize * self.rooms_wide as usize
+    }
+
+    fn positions(&self) -> Vec<Position> {
+        let mut positions = vec![];
+        for x in 0..self.rooms_wide {
+            for y in 0..self.rooms_tall {
+                for z in 0..self.rooms_deep {
+                    positions.push(Position { x, y, z });
+                }
+            }
+        }
+        positions
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Up,
+    Down,
+    North,
+    South,
+    East,
+    West,
+}
+
+const DIRECTIONS: [Direction; 6] = [
+    Direction::Up,
+    Direction::Down,
+    Direction::North,
+    Direction::South,
+    Direction::East,
+    Direction::West,
+];
+
+const HALF_DIRECTIONS: [Direction; 3] = [Direction::Up, Direction::North, Direction::East];
+
+impl Direction {
+    fn opposite(&self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::North => Direction::South,
+            Direction::South => Direction::North,
+            Direction::East => Direction::West,
+            Direction::West => Direction::East,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Direction::Up => 0,
+            Direction::Down => 1,
+            Direction::North => 2,
+            Direction::South => 3,
+            Direction::East => 4,
+            Direction::West => 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Position {
+    x: u16,
+    y: u16,
+    z: u16,
+}
+
+impl Position {
+    fn index(&self, spec: &DungeonSpecification) -> usize {
+        self.x as usize * spec.rooms_deep as usize * spec.rooms_tall as usize
+            + self.y as usize * spec.rooms_deep as usize
+            + self.z as usize
+    }
+
+    fn in_direction(&self, direction: Direction, spec: &DungeonSpecification) -> Option<Position> {
+        match direction {
+            Direction::Up => {
+                if self.y + 1 < spec.rooms_tall {
+                    Some(Self {
+                        x: self.x,
+                        y: self.y + 1,
+                        z: self.z,
+                    })
+                } else {
+                    None
+                }
+            }
+            Direction::Down => {
+                if self.y > 0 {
+                    Some(Self {
+                        x: self.x,
+                        y: self.y - 1,
+                        z: self.z,
+                    })
+                } else {
+                    None
+                }
+            }
+            Direction::North => {
+                if self.z + 1 < spec.rooms_deep {
+                    Some(Self {
+                        x: self.x,
+                        y: self.y,
+                        z: self.z + 1,
+                    })
+                } else {
+                    None
+                }
+            }
+            Direction::South => {
+                if self.z > 0 {
+                    Some(Self {
+                        x: self.x,
+                        y: self.y,
+                        z: self.z - 1,
+                    })
+                } else {
+                    None
+                }
+            }
+            Direction::East => {
+                if self.x + 1 < spec.rooms_wide {
+                    Some(Self {
+                        x: self.x + 1,
+                        y: self.y,
+                        z: self.z,
+                    })
+                } else {
+                    None
+                }
+            }
+            Direction::West => {
+                if self.x > 0 {
+                    Some(Self {
+                        x: self.x - 1,
+                        y: self.y,
+                        z: self.z,
+                    })
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Room {
+    connectivity: [bool; 6],
+}
+
+fn build_connectivity(spec: &DungeonSpecification, rooms: &[Room]) -> Vec<usize> {
+    let mut areas = vec![];
+    for pos in spec.positions().iter() {
+        areas.push(pos.index(spec));
+    }
+
+    // Flood fill each position. Note that spec.positions() is in index order, so we
+    // are guaranteed to fill areas in ascending order (and so, if we try to flood fill
+    // from a cell that's been touched by a lower number, we can skip it)
+    for pos in spec.positions().iter() {
+        let mut stack = vec![];
+        stack.push(*pos);
+        let current_area = pos.index(spec);
+        if current_area > areas[pos.index(spec)] {
+            continue;
+        }
+        while let Some(pos) = stack.pop() {
+            areas[pos.index(spec)] = current_area;
+            for dir in DIRECTIONS.iter() {
+                if rooms[pos.index(spec)].connectivity[dir.index()] {
+                    let new_room = pos.in_direction(*dir, spec).unwrap();
+                    if areas[new_room.index(spec)] > current_area {
+                        stack.push(new_room);
+                    }
+                }
+            }
+        }
+    }
+    areas
+}
+
+fn is_connected(spec: &DungeonSpecification, rooms: &[Room]) -> bool {
+    let areas = build_connectivity(spec, rooms);
+    areas.iter().all(|area| *area == areas[0])
+}
+
+fn fill(p1: Position, p2: Position) -> Vec<Voxel> {
+    let mut voxels = vec![];
+    for x in p1.x..p2.x + 1 {
+        for y in p1.y..p2.y + 1 {
+            for z in p1.z..p2.z + 1 {
+                voxels.push(Voxel { x, y, z });
+            }
+        }
+    }
+    voxels
+}
+
+fn wall(dir: Direction) -> Vec<Voxel> {
+    match dir {
+        Direction::Up | Direction::Down => unimplemented!(),
+        Direction::North => fill(
+            Position { x: 0, y: 0, z: 11 },
+            Position {
+                x: 11,
+                y: 10,
+                z: 11,
+            },
+        ),
+        Direction::South => fill(
+            Position { x: 0, y: 0, z: 0 },
+            Position { x: 11, y: 10, z: 0 },
+        ),
+        Direction::East => fill(
+            Position { x: 11, y: 0, z: 0 },
+            Position {
+                x: 11,
+                y: 10,
+                z: 11,
+            },
+        ),
+        Direction::West => fill(
+            Position { x: 0, y: 0, z: 0 },
+            Position { x: 0, y: 10, z: 11 },
+        ),
+    }
+}
+
+fn translate(voxels: Vec<Voxel>, offset: Position) -> Vec<Voxel> {
+    voxels
+        .iter()
+        .map(|voxel| Voxel {
+            x: voxel.x + offset.x,
+            y: voxel.y + offset.y,
+            z: voxel.z + offset.z,
+        })
+        .collect()
+}
+
+/// Start with a 3D array of rooms. Then, randomly remove walls until the map is fully
+/// connected. The same specification and seed always produce the same dungeon.
+pub fn generate_dungeon(spec: &DungeonSpecification, seed: u64) -> Vec<Voxel> {
+    let mut rooms = vec![
+        Room {
+            connectivity: [false; 6]
+        };
+        spec.num_rooms()
+    ];
+
+    let mut all_walls = vec![];
+    for x in 0..spec.rooms_wide {
+        for y in 0..spec.rooms_tall {
+            for z in 0..spec.rooms_deep {
+                let pos = Position { x, y, z };
+                for dir in HALF_DIRECTIONS.iter() {
+                    if pos.in_direction(*dir, spec).is_some() {
+                        all_walls.push((pos, dir));
+                    }
+                }
+            }
+        }
+    }
+
+    // ChaCha's output is fixed for a given seed, unlike StdRng's
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    while !is_connected(spec, &rooms) {
+        // Remove a random wall
+        let maze_algo: f32 = rng.gen();
+        if maze_algo < 0.1 {
+            // Remove a wall which is separating two spaces
+            let areas = build_connectivity(spec, &rooms);
+            let separating_walls: Vec<_> = all_walls
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, (pos, dir))| {
+                    let other_pos = match pos.in_direction(**dir, spec) {
+                        Some(x) => x,
+                        None => return None,
+                    };
+                    if areas[pos.index(spec)] != areas[other_pos.index(spec)] {
+                        Some(idx)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let idx = random_index(&mut rng, separating_walls.len());
+            let idx = separating_walls[idx];
+
+            let (pos, dir) = all_walls.remove(idx);
+            rooms[pos.index(spec)].connectivity[dir.index()] = true;
+            //println!("Removing {:?} {:?}", pos, dir);
+            rooms[pos.in_direction(*dir, spec).unwrap().index(spec)].connectivity
+                [dir.opposite().index()] = true;
+        } else {
+            // Remove a random wall
+            let idx = random_index(&mut rng, all_walls.len());
+            let (pos, dir) = all_walls.remove(idx);
+            rooms[pos.index(spec)].connectivity[dir.index()] = true;
+            //println!("{:?} {:?}", pos, dir);
+            rooms[pos.in_direction(*dir, spec).unwrap().index(spec)].connectivity
+                [dir.opposite().index()] = true;
+        }
+    }
+
+    // Generate voxels based on the remaining rooms
+    // Walls are 2 voxels thick, rooms are 10 wide and 10 tall.
+    let mut voxels = vec![];
+    for x in 0..spec.rooms_wide {
+        for y in 0..spec.rooms_tall {
+            for z in 0..spec.rooms_deep {
+                let pos = Position { x, y, z };
+                for dir in DIRECTIONS.iter() {
+                    if *dir == Direction::Up || *dir == Direction::Down {
+                        continue;
+                    }
+                    if !rooms[pos.index(spec)].connectivity[dir.index()] {
+                        let wall = wall(*dir);
+                        let mut wall = translate(
+                            wall,
+                            Position {
+                                x: x * 12,
+                                y: y * 12,
+                                z: z * 12,
+                            },
+                        );
+                        voxels.append(&mut wall);
+                    }
+                }
+            }
+        }
+    }
+    voxels
+}
+
+/// Generate a dungeon along with a spawn point in every room
+pub fn generate_map(spec: &DungeonSpecification, seed: u64) -> MapFile {
+    let spawn_points = spec
+        .positions()
+        .iter()
+        .map(|pos| room_center(pos.x, pos.y, pos.z))
+        .collect();
+    MapFile {
+        voxels: generate_dungeon(spec, seed),
+        spawn_points,
+    }
+}
+
+/// Sampled as a u32 so that the result doesn't depend on the platform's usize
+fn random_index<R: Rng>(rng: &mut R, len: usize) -> usize {
+    rng.gen_range(0..len as u32) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: DungeonSpecification = DungeonSpecification {
+        rooms_wide: 4,
+        rooms_deep: 4,
+        rooms_tall: 2,
+    };
+
+    #[test]
+    fn same_seed_same_dungeon() {
+        let first = generate_map(&SPEC, 1234);
+        let second = generate_map(&SPEC, 1234);
+        assert!(!first.voxels.is_empty());
+        assert_eq!(first.voxels, second.voxels);
+        assert_eq!(first.spawn_points, second.spawn_points);
+    }
+
+    #[test]
+    fn different_seeds_different_dungeons() {
+        let dungeons: Vec<_> = (0..4).map(|seed| generate_dungeon(&SPEC, seed)).collect();
+        for (i, a) in dungeons.iter().enumerate() {
+            for b in dungeons.iter().skip(i + 1) {
+                assert_ne!(a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+
+mod dungeon;
+pub mod nav;
+mod rooms;
+
+pub use dungeon::{generate_dungeon, generate_map, DungeonSpecification, ALGORITHM_VERSION};
+pub use rooms::{Room, RoomGraph};
+
+/// The width of a voxel in world units
+pub const VOXEL_SIZE: f32 = 0.2;
+
+/// The distance between the starts of adjacent rooms, in voxels. Rooms are 10 voxels
+/// across, plus a wall on either side.
+pub const ROOM_PITCH: u16 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Voxel {
+    pub x: u16,
+    pub y: u16,
+    pub z: u16,
+}
+
+/// The world-space point on the floor in the middle of the given room
+pub fn room_center(x: u16, y: u16, z: u16) -> [f32; 3] {
+    let center = ROOM_PITCH as f32 / 2.0;
+    [
+        (x * ROOM_PITCH) as f32 * VOXEL_SIZE + center * VOXEL_SIZE,
+        (y * ROOM_PITCH) as f32 * VOXEL_SIZE,
+        (z * ROOM_PITCH) as f32 * VOXEL_SIZE + center * VOXEL_SIZE,
+    ]
+}
+
+/// The centers of every room in the grid spanned by the given voxels
+pub fn room_centers(voxels: &[Voxel]) -> Vec<[f32; 3]> {
+    let rooms_wide = voxels.iter().map(|v| v.x).max().unwrap_or(0) / ROOM_PITCH + 1;
+    let rooms_tall = voxels.iter().map(|v| v.y).max().unwrap_or(0) / ROOM_PITCH + 1;
+    let rooms_deep = voxels.iter().map(|v| v.z).max().unwrap_or(0) / ROOM_PITCH + 1;
+    let mut centers = vec![];
+    for x in 0..rooms_wide {
+        for y in 0..rooms_tall {
+            for z in 0..rooms_deep {
+                centers.push(room_center(x, y, z));
+            }
+        }
+    }
+    centers
+}
+
+/// The contents of a map.bin file.
+///
+/// The file is a little-endian u32 voxel count followed by that many voxels, then
+/// optionally a u32 spawn point count followed by that many spawn points. Files
+/// without spawn points use the center of every room instead.
+pub struct MapFile {
+    pub voxels: Vec<Voxel>,
+    pub spawn_points: Vec<[f32; 3]>,
+}
+
+impl MapFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())
+            .with_context(|| format!("Reading map {:?}", path.as_ref()))?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        let mut voxels = vec![];
+        let len = read_u32(&mut data).context("Reading voxel count")?;
+        for _ in 0..len {
+            voxels.push(bincode::deserialize_from(&mut data).context("Reading voxel")?);
+        }
+
+        let spawn_points = if data.is_empty() {
+            room_centers(&voxels)
+        } else {
+            let len = read_u32(&mut data).context("Reading spawn point count")?;
+            let mut spawn_points = vec![];
+            for _ in 0..len {
+                spawn_points
+                    .push(bincode::deserialize_from(&mut data).context("Reading spawn point")?);
+            }
+            spawn_points
+        };
+
+        Ok(Self {
+            voxels,
+            spawn_points,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&(self.voxels.len() as u32).to_le_bytes());
+        for voxel in self.voxels.iter() {
+            data.append(&mut bincode::serialize(voxel).unwrap());
+        }
+        data.extend_from_slice(&(self.spawn_points.len() as u32).to_le_bytes());
+        for spawn_point in self.spawn_points.iter() {
+            data.append(&mut bincode::serialize(spawn_point).unwrap());
+        }
+        data
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32> {
+    let mut buf = [0; 4];
+    data.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
@@ -0,0 +1,490 @@
+use crate::{Voxel, VOXEL_SIZE};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// The cost of moving one voxel across, or diagonally. Costs are kept as integers
+/// so they can be ordered in the search's heap.
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+/// The extra cost of jumping, so that walkers only jump when it saves going round
+const JUMP_COST: u32 = 20;
+
+/// What a walker can fit through and get over, in voxels
+#[derive(Clone, Debug)]
+pub struct NavSettings {
+    /// How much headroom a walker needs
+    pub height: i32,
+
+    /// How far a walker needs to keep from walls, besides the voxel it's in
+    pub radius: i32,
+
+    /// The highest ledge a walker can walk up without jumping
+    pub step_height: i32,
+
+    /// The highest ledge a walker can jump up onto
+    pub jump_height: i32,
+
+    /// The furthest a walker can jump across, so gaps up to one less than this can
+    /// be crossed
+    pub jump_distance: i32,
+
+    /// The furthest a walker will drop down
+    pub max_drop: i32,
+}
+
+impl Default for NavSettings {
+    /// A player, who is 1.8 units tall and 0.6 across, and jumps about 0.45 units up
+    /// and 0.3 along
+    fn default() -> Self {
+        Self {
+            height: 9,
+            radius: 1,
+            step_height: 1,
+            jump_height: 2,
+            jump_distance: 2,
+            max_drop: 10,
+        }
+    }
+}
+
+/// A voxel's worth of space a walker can stand in, by the coordinates of the voxel
+/// its feet are in
+type Cell = [i32; 3];
+
+/// Where in a voxel map there's room to stand, and how to get from one place to
+/// another. Everything below the map is taken to be solid ground, as the game does.
+pub struct NavGrid {
+    settings: NavSettings,
+
+    /// The heights of the solid voxels in each column, keyed by x and z
+    solid: HashMap<(i32, i32), Vec<i32>>,
+
+    /// The corners of the map's bounding box, in voxels, inclusive
+    min: (i32, i32),
+    max: (i32, i32),
+
+    /// The heights a walker can stand at in each column, lowest first
+    floors: HashMap<(i32, i32), Vec<i32>>,
+}
+
+impl NavGrid {
+    pub fn new(voxels: &[Voxel], settings: NavSettings) -> Self {
+        let mut solid: HashMap<(i32, i32), Vec<i32>> = HashMap::new();
+        for voxel in voxels {
+            solid
+                .entry((voxel.x as i32, voxel.z as i32))
+                .or_default()
+                .push(voxel.y as i32);
+        }
+        for heights in solid.values_mut() {
+            heights.sort_unstable();
+        }
+        let min = (
+            voxels.iter().map(|v| v.x as i32).min().unwrap_or(0),
+            voxels.iter().map(|v| v.z as i32).min().unwrap_or(0),
+        );
+        let max = (
+            voxels.iter().map(|v| v.x as i32).max().unwrap_or(0),
+            voxels.iter().map(|v| v.z as i32).max().unwrap_or(0),
+        );
+        let mut grid = Self {
+            settings,
+            solid,
+            min,
+            max,
+            floors: HashMap::new(),
+        };
+        for x in min.0..=max.0 {
+            for z in min.1..=max.1 {
+                grid.update_column(x, z);
+            }
+        }
+        grid
+    }
+
+    /// Take destroyed voxels out of the grid, reworking only the columns near them
+    pub fn remove_voxels(&mut self, voxels: &[Voxel]) {
+        let mut columns = HashSet::new();
+        for voxel in voxels {
+            let (x, z) = (voxel.x as i32, voxel.z as i32);
+            if let Some(heights) = self.solid.get_mut(&(x, z)) {
+                heights.retain(|y| *y != voxel.y as i32);
+            }
+            let radius = self.settings.radius;
+            for dx in -radius..=radius {
+                for dz in -radius..=radius {
+                    columns.insert((x + dx, z + dz));
+                }
+            }
+        }
+        for (x, z) in columns {
+            if self.in_bounds(x, z) {
+                self.update_column(x, z);
+            }
+        }
+    }
+
+    fn in_bounds(&self, x: i32, z: i32) -> bool {
+        x >= self.min.0 && x <= self.max.0 && z >= self.min.1 && z <= self.max.1
+    }
+
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        y < 0
+            || self
+                .solid
+                .get(&(x, z))
+                .is_some_and(|heights| heights.binary_search(&y).is_ok())
+    }
+
+    /// Whether there's nothing solid from height `from` up to (but not including)
+    /// `to`, within the walker's radius of the given column
+    fn is_clear(&self, x: i32, z: i32, from: i32, to: i32) -> bool {
+        let radius = self.settings.radius;
+        (-radius..=radius).all(|dx| {
+            (-radius..=radius).all(|dz| match self.solid.get(&(x + dx, z + dz)) {
+                Some(heights) => !heights.iter().any(|y| *y >= from && *y < to),
+                None => true,
+            })
+        })
+    }
+
+    /// Work out where a walker could stand in a column: on the ground or on top of
+    /// any voxel, wherever there's headroom
+    fn update_column(&mut self, x: i32, z: i32) {
+        let mut candidates = vec![0];
+        if let Some(heights) = self.solid.get(&(x, z)) {
+            candidates.extend(heights.iter().map(|y| y + 1));
+        }
+        let height = self.settings.height;
+        let floors: Vec<_> = candidates
+            .into_iter()
+            .filter(|y| !self.is_solid(x, *y, z) && self.is_clear(x, z, *y, y + height))
+            .collect();
+        if floors.is_empty() {
+            self.floors.remove(&(x, z));
+        } else {
+            self.floors.insert((x, z), floors);
+        }
+    }
+
+    fn floors(&self, x: i32, z: i32) -> &[i32] {
+        self.floors.get(&(x, z)).map_or(&[], Vec::as_slice)
+    }
+
+    /// The cell a world-space point (at a walker's feet) is in, if it can be stood
+    /// in. Of the heights that can be stood at there, the nearest is taken.
+    fn cell_at(&self, point: [f32; 3]) -> Option<Cell> {
+        let x = (point[0] / VOXEL_SIZE).floor() as i32;
+        let z = (point[2] / VOXEL_SIZE).floor() as i32;
+        let height = point[1] / VOXEL_SIZE;
+        let y = self
+            .floors(x, z)
+            .iter()
+            .min_by(|a, b| {
+                let a = (**a as f32 - height).abs();
+                let b = (**b as f32 - height).abs();
+                a.partial_cmp(&b).unwrap()
+            })
+            .copied()?;
+        Some([x, y, z])
+    }
+
+    /// Whether a walker could stand at the given world-space point
+    pub fn is_walkable(&self, point: [f32; 3]) -> bool {
+        self.cell_at(point).is_some()
+    }
+
+    /// The cells that can be reached in one move from the given one, and what each
+    /// move costs
+    fn moves(&self, [x, y, z]: Cell) -> Vec<(Cell, u32)> {
+        let settings = &self.settings;
+        let mut moves = vec![];
+        for dx in -1..=1 {
+            for dz in -1..=1 {
+                if dx == 0 && dz == 0 {
+                    continue;
+                }
+                let (nx, nz) = (x + dx, z + dz);
+                for ny in self.floors(nx, nz).iter().copied() {
+                    let rise = ny - y;
+                    if rise > settings.jump_height || -rise > settings.max_drop {
+                        continue;
+                    }
+                    let diagonal = dx != 0 && dz != 0;
+                    let clear = if rise == 0 {
+                        // Both cells have headroom already, so there's only the
+                        // corner to cut on diagonals
+                        !diagonal
+                            || (self.floors(nx, z).contains(&y) && self.floors(x, nz).contains(&y))
+                    } else {
+                        // Room to get over the ledge, in both columns and any corner
+                        let top = y.max(ny) + settings.height;
+                        self.is_clear(x, z, y, top)
+                            && self.is_clear(nx, nz, ny, top)
+                            && (!diagonal
+                                || (self.is_clear(nx, z, y.max(ny), top)
+                                    && self.is_clear(x, nz, y.max(ny), top)))
+                    };
+                    if !clear {
+                        continue;
+                    }
+                    let mut cost = if diagonal {
+                        DIAGONAL_COST
+                    } else {
+                        STRAIGHT_COST
+                    };
+                    if rise > settings.step_height {
+                        cost += JUMP_COST;
+                    }
+                    moves.push(([nx, ny, nz], cost));
+                }
+            }
+        }
+
+        // Jumping straight across gaps, no higher than a step up
+        for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            if self.floors(x + dx, z + dz).contains(&y) {
+                // No gap, so walking will do
+                continue;
+            }
+            for distance in 2..=settings.jump_distance {
+                let (nx, nz) = (x + dx * distance, z + dz * distance);
+                let arc_clear = (1..distance).all(|along| {
+                    self.is_clear(
+                        x + dx * along,
+                        z + dz * along,
+                        y,
+                        y + settings.height + settings.jump_height,
+                    )
+                });
+                if !arc_clear {
+                    break;
+                }
+                for ny in self.floors(nx, nz).iter().copied() {
+                    let rise = ny - y;
+                    if rise <= settings.step_height && -rise <= settings.max_drop {
+                        let cost = STRAIGHT_COST * distance as u32 + JUMP_COST;
+                        moves.push(([nx, ny, nz], cost));
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    /// A short way to walk between two world-space points, at walkers' feet, as the
+    /// points to head for in turn, not including the start. None if either end
+    /// can't be stood at, or there's no way through.
+    pub fn find_path(&self, from: [f32; 3], to: [f32; 3]) -> Option<Vec<[f32; 3]>> {
+        let start = self.cell_at(from)?;
+        let goal = self.cell_at(to)?;
+        let cells = self.search(start, goal)?;
+        Some(
+            self.smooth(&cells)
+                .into_iter()
+                .skip(1)
+                .map(|[x, y, z]| {
+                    [
+                        (x as f32 + 0.5) * VOXEL_SIZE,
+                        y as f32 * VOXEL_SIZE,
+                        (z as f32 + 0.5) * VOXEL_SIZE,
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    /// A* from one cell to another, returning every cell on the way, both ends
+    /// included
+    fn search(&self, start: Cell, goal: Cell) -> Option<Vec<Cell>> {
+        let mut open = BinaryHeap::new();
+        let mut costs: HashMap<Cell, u32> = HashMap::new();
+        let mut came_from: HashMap<Cell, Cell> = HashMap::new();
+        costs.insert(start, 0);
+        open.push(Reverse((estimate(start, goal), start)));
+
+        while let Some(Reverse((_, cell))) = open.pop() {
+            if cell == goal {
+                let mut path = vec![goal];
+                let mut cell = goal;
+                while let Some(previous) = came_from.get(&cell) {
+                    path.push(*previous);
+                    cell = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let cost = costs[&cell];
+            for (next, step) in self.moves(cell) {
+                let next_cost = cost + step;
+                if costs.get(&next).is_none_or(|known| next_cost < *known) {
+                    costs.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Reverse((next_cost + estimate(next, goal), next)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Cut out the cells that can be skipped by walking in a straight line, keeping
+    /// the ends and every change of height
+    fn smooth(&self, cells: &[Cell]) -> Vec<Cell> {
+        let mut smoothed = vec![cells[0]];
+        let mut anchor = 0;
+        while anchor < cells.len() - 1 {
+            let mut next = anchor + 1;
+            while next + 1 < cells.len() && self.is_straight_walk(cells[anchor], cells[next + 1]) {
+                next += 1;
+            }
+            smoothed.push(cells[next]);
+            anchor = next;
+        }
+        smoothed
+    }
+
+    /// Whether there's level ground the whole way in a straight line between two
+    /// cells
+    fn is_straight_walk(&self, from: Cell, to: Cell) -> bool {
+        if from[1] != to[1] {
+            return false;
+        }
+        let y = from[1];
+        let (dx, dz) = ((to[0] - from[0]) as f32, (to[2] - from[2]) as f32);
+        // Several samples per voxel crossed, so no column is skipped over
+        let samples = (dx.abs().max(dz.abs()) * 4.0).ceil() as i32;
+        (0..=samples).all(|i| {
+            let t = i as f32 / samples.max(1) as f32;
+            let x = (from[0] as f32 + 0.5 + dx * t).floor() as i32;
+            let z = (from[2] as f32 + 0.5 + dz * t).floor() as i32;
+            self.floors(x, z).contains(&y)
+        })
+    }
+}
+
+/// A lower bound on the cost between two cells, for A*: the distance across, moving
+/// diagonally as much as possible. Height is left out, since a single move can
+/// drop any distance.
+fn estimate(from: Cell, to: Cell) -> u32 {
+    let dx = (from[0] - to[0]).unsigned_abs();
+    let dz = (from[2] - to[2]).unsigned_abs();
+    let (long, short) = (dx.max(dz), dx.min(dz));
+    DIAGONAL_COST * short + STRAIGHT_COST * (long - short)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A thin walker, to keep the maps small
+    fn settings() -> NavSettings {
+        NavSettings {
+            height: 2,
+            radius: 0,
+            step_height: 1,
+            jump_height: 2,
+            jump_distance: 2,
+            max_drop: 10,
+        }
+    }
+
+    /// A 10 by 10 room, from 1 to 10 in x and z, walled in up to the given height
+    fn room(wall_height: u16) -> Vec<Voxel> {
+        let mut voxels = vec![];
+        for y in 0..wall_height {
+            for i in 0..=11 {
+                voxels.push(Voxel { x: i, y, z: 0 });
+                voxels.push(Voxel { x: i, y, z: 11 });
+                voxels.push(Voxel { x: 0, y, z: i });
+                voxels.push(Voxel { x: 11, y, z: i });
+            }
+        }
+        voxels
+    }
+
+    /// A wall across the room at x = 6, covering z from 1 up to `to`
+    fn divider(height: u16, to: u16) -> Vec<Voxel> {
+        let mut voxels = vec![];
+        for y in 0..height {
+            for z in 1..=to {
+                voxels.push(Voxel { x: 6, y, z });
+            }
+        }
+        voxels
+    }
+
+    /// The world-space point in the middle of a cell's floor
+    fn point(x: i32, y: i32, z: i32) -> [f32; 3] {
+        [
+            (x as f32 + 0.5) * VOXEL_SIZE,
+            y as f32 * VOXEL_SIZE,
+            (z as f32 + 0.5) * VOXEL_SIZE,
+        ]
+    }
+
+    #[test]
+    fn straight_across_open_ground() {
+        let grid = NavGrid::new(&room(4), settings());
+        let path = grid.find_path(point(2, 0, 2), point(9, 0, 9)).unwrap();
+        // Nothing's in the way, so the path smooths down to just the goal
+        assert_eq!(path, vec![point(9, 0, 9)]);
+    }
+
+    #[test]
+    fn around_a_wall() {
+        let mut voxels = room(4);
+        voxels.extend(divider(4, 7));
+        let grid = NavGrid::new(&voxels, settings());
+        let path = grid.find_path(point(2, 0, 2), point(9, 0, 2)).unwrap();
+        assert_eq!(path.last(), Some(&point(9, 0, 2)));
+        // Smoothing leaves a few corners rather than every cell of the way round
+        assert!(path.len() <= 3, "{:?}", path);
+        for waypoint in path.iter() {
+            assert!(grid.is_walkable(*waypoint));
+        }
+        assert!(path.iter().any(|waypoint| waypoint[2] >= 8.0 * VOXEL_SIZE));
+    }
+
+    #[test]
+    fn walled_off() {
+        let mut voxels = room(4);
+        voxels.extend(divider(4, 10));
+        let grid = NavGrid::new(&voxels, settings());
+        assert_eq!(grid.find_path(point(2, 0, 2), point(9, 0, 2)), None);
+    }
+
+    #[test]
+    fn over_a_wall_by_jumping() {
+        let mut voxels = room(4);
+        voxels.extend(divider(2, 10));
+        let grid = NavGrid::new(&voxels, settings());
+        let path = grid.find_path(point(2, 0, 2), point(9, 0, 2)).unwrap();
+        assert!(path.contains(&point(6, 2, 2)), "{:?}", path);
+
+        // Too high for someone who can only step
+        let grid = NavGrid::new(
+            &voxels,
+            NavSettings {
+                jump_height: 1,
+                ..settings()
+            },
+        );
+        assert_eq!(grid.find_path(point(2, 0, 2), point(9, 0, 2)), None);
+    }
+
+    #[test]
+    fn removed_voxels_open_a_way() {
+        let mut voxels = room(4);
+        voxels.extend(divider(4, 10));
+        let mut grid = NavGrid::new(&voxels, settings());
+        assert_eq!(grid.find_path(point(2, 0, 2), point(9, 0, 2)), None);
+
+        let hole: Vec<_> = (0..4).map(|y| Voxel { x: 6, y, z: 5 }).collect();
+        grid.remove_voxels(&hole);
+        let path = grid.find_path(point(2, 0, 2), point(9, 0, 2)).unwrap();
+        assert_eq!(path.last(), Some(&point(9, 0, 2)));
+        // The only way through is the hole, at ground level
+        assert!(path.iter().all(|waypoint| waypoint[1] == 0.0), "{:?}", path);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{room_center, Voxel, ROOM_PITCH, VOXEL_SIZE};
+use std::collections::{HashSet, VecDeque};
+
+/// Voxels from the floor a doorway has to be clear to, to fit a player through
+const DOORWAY_HEIGHT: i32 = 9;
+
+/// How many voxels wide a doorway has to be, to fit a player through
+const DOORWAY_WIDTH: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Room {
+    pub x: u16,
+    pub y: u16,
+    pub z: u16,
+}
+
+impl Room {
+    /// The world-space point on the floor in the middle of the room
+    pub fn center(&self) -> [f32; 3] {
+        room_center(self.x, self.y, self.z)
+    }
+}
+
+/// Which rooms of a dungeon lead into which, judging by the walls between them.
+/// Rooms above and below each other aren't connected, since there's no climbing
+/// between floors.
+pub struct RoomGraph {
+    size: [u16; 3],
+    neighbours: Vec<Vec<Room>>,
+}
+
+impl RoomGraph {
+    /// The graph for a dungeon `size` rooms across, where `is_solid` says whether
+    /// there's a voxel at the given coordinates
+    pub fn new(size: [u16; 3], is_solid: impl Fn(i32, i32, i32) -> bool) -> Self {
+        let mut graph = Self {
+            size,
+            neighbours: vec![vec![]; size.iter().map(|n| *n as usize).product()],
+        };
+        for room in graph.rooms() {
+            let east = Room {
+                x: room.x + 1,
+                ..room
+            };
+            if east.x < size[0] && has_doorway(room, true, &is_solid) {
+                graph.connect(room, east);
+            }
+            let north = Room {
+                z: room.z + 1,
+                ..room
+            };
+            if north.z < size[2] && has_doorway(room, false, &is_solid) {
+                graph.connect(room, north);
+            }
+        }
+        graph
+    }
+
+    /// The graph for a dungeon spanning the given voxels
+    pub fn from_voxels(voxels: &[Voxel]) -> Self {
+        let size = [
+            voxels.iter().map(|v| v.x).max().unwrap_or(0) / ROOM_PITCH + 1,
+            voxels.iter().map(|v| v.y).max().unwrap_or(0) / ROOM_PITCH + 1,
+            voxels.iter().map(|v| v.z).max().unwrap_or(0) / ROOM_PITCH + 1,
+        ];
+        let solid: HashSet<_> = voxels
+            .iter()
+            .map(|v| (v.x as i32, v.y as i32, v.z as i32))
+            .collect();
+        Self::new(size, |x, y, z| solid.contains(&(x, y, z)))
+    }
+
+    /// How many rooms wide, tall and deep the dungeon is
+    pub fn size(&self) -> [u16; 3] {
+        self.size
+    }
+
+    fn index(&self, room: Room) -> usize {
+        (room.x as usize * self.size[1] as usize + room.y as usize) * self.size[2] as usize
+            + room.z as usize
+    }
+
+    fn connect(&mut self, a: Room, b: Room) {
+        let (a_index, b_index) = (self.index(a), self.index(b));
+        self.neighbours[a_index].push(b);
+        self.neighbours[b_index].push(a);
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = Room> {
+        let [wide, tall, deep] = self.size;
+        (0..wide).flat_map(move |x| {
+            (0..tall).flat_map(move |y| (0..deep).map(move |z| Room { x, y, z }))
+        })
+    }
+
+    /// The room containing the given world-space point, if it's inside the dungeon
+    pub fn room_at(&self, point: [f32; 3]) -> Option<Room> {
+        let pitch = ROOM_PITCH as f32 * VOXEL_SIZE;
+        let mut room = [0; 3];
+        for axis in 0..3 {
+            let index = (point[axis] / pitch).floor();
+            if index < 0.0 || index >= self.size[axis] as f32 {
+                return None;
+            }
+            room[axis] = index as u16;
+        }
+        Some(Room {
+            x: room[0],
+            y: room[1],
+            z: room[2],
+        })
+    }
+
+    /// The rooms that can be walked into from the given one
+    pub fn neighbours(&self, room: Room) -> &[Room] {
+        &self.neighbours[self.index(room)]
+    }
+
+    /// The fewest rooms to walk through to get from one room to another, not
+    /// counting the first, or None if there's no way through
+    pub fn route(&self, from: Room, to: Room) -> Option<Vec<Room>> {
+        let mut came_from = vec![None; self.neighbours.len()];
+        let mut queue = VecDeque::new();
+        came_from[self.index(from)] = Some(from);
+        queue.push_back(from);
+        while let Some(room) = queue.pop_front() {
+            if room == to {
+                let mut route = vec![];
+                let mut room = to;
+                while room != from {
+                    route.push(room);
+                    room = came_from[self.index(room)].unwrap();
+                }
+                route.reverse();
+                return Some(route);
+            }
+            for next in self.neighbours(room) {
+                let index = self.index(*next);
+                if came_from[index].is_none() {
+                    came_from[index] = Some(room);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Whether there's a gap big enough for a player in the walls between a room and
+/// the next one to the east (+X) or north (+Z). Each room has its own wall on that
+/// side, so there are two layers to get through.
+fn has_doorway(room: Room, east: bool, is_solid: impl Fn(i32, i32, i32) -> bool) -> bool {
+    let pitch = ROOM_PITCH as i32;
+    let origin = [
+        room.x as i32 * pitch,
+        room.y as i32 * pitch,
+        room.z as i32 * pitch,
+    ];
+    // Measured along the wall, from this room's corner
+    let is_clear = |along: i32| {
+        (0..DOORWAY_HEIGHT).all(|up| {
+            [pitch - 1, pitch].iter().all(|across| {
+                let (x, z) = if east {
+                    (origin[0] + across, origin[2] + along)
+                } else {
+                    (origin[0] + along, origin[2] + across)
+                };
+                !is_solid(x, origin[1] + up, z)
+            })
+        })
+    };
+    let mut width = 0;
+    for along in 1..pitch - 1 {
+        width = if is_clear(along) { width + 1 } else { 0 };
+        if width >= DOORWAY_WIDTH {
+            return true;
+        }
+    }
+    false
+}
@@ -0,0 +1,188 @@
+use anyhow::*;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The latest a ban can run until. TOML integers are signed, so anything later
+/// couldn't be read back.
+const LATEST: u64 = i64::MAX as u64;
+
+/// A player kept off the server, by their username, their address or both
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub reason: Option<String>,
+
+    /// When the ban runs out, in seconds since the Unix epoch. Bans without one are
+    /// permanent.
+    pub until: Option<u64>,
+}
+
+impl Ban {
+    /// A ban for the given time, or forever. Local addresses are left out, since
+    /// banning one would turn away everyone connecting through the same machine.
+    pub fn new(
+        username: Option<String>,
+        address: Option<IpAddr>,
+        reason: Option<String>,
+        duration: Option<Duration>,
+    ) -> Self {
+        Self {
+            username,
+            address: address.filter(|address| is_bannable(*address)),
+            reason,
+            until: duration.map(|duration| now().saturating_add(duration.as_secs()).min(LATEST)),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until.is_none_or(|until| now() < until)
+    }
+
+    /// What to tell the player being turned away
+    pub fn message(&self) -> String {
+        let mut message = "You are banned from this server".to_string();
+        if let Some(until) = self.until {
+            message += &format!(
+                " for another {}",
+                format_duration(Duration::from_secs(until.saturating_sub(now())))
+            );
+        }
+        if let Some(reason) = &self.reason {
+            message += &format!(": {}", reason);
+        }
+        message
+    }
+}
+
+/// Whether an address belongs to a single remote player, rather than this machine
+pub fn is_bannable(address: IpAddr) -> bool {
+    !address.is_loopback() && !address.is_unspecified()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Roughly how long, in the biggest units that fit
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (amount, unit) = match seconds {
+        s if s >= 2 * 86400 => (s / 86400, "days"),
+        s if s >= 2 * 3600 => (s / 3600, "hours"),
+        s if s >= 2 * 60 => (s / 60, "minutes"),
+        1 => (1, "second"),
+        s => (s, "seconds"),
+    };
+    format!("{} {}", amount, unit)
+}
+
+/// The bans, kept in a TOML file so they outlast the server
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BanList {
+    #[serde(rename = "ban", default)]
+    bans: Vec<Ban>,
+
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl BanList {
+    /// Load the bans from the file, or start an empty list there if there isn't one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut list: BanList = if path.exists() {
+            let contents =
+                std::fs::read_to_string(path).with_context(|| format!("Reading {:?}", path))?;
+            toml::from_str(&contents).with_context(|| format!("Parsing {:?}", path))?
+        } else {
+            BanList::default()
+        };
+        list.path = path.to_path_buf();
+        list.bans.retain(Ban::is_active);
+        info!("{} bans in force", list.bans.len());
+        Ok(list)
+    }
+
+    fn save(&mut self) -> Result<()> {
+        self.bans.retain(Ban::is_active);
+        let contents = toml::to_string(self)?;
+        std::fs::write(&self.path, contents).with_context(|| format!("Writing {:?}", self.path))
+    }
+
+    pub fn add(&mut self, ban: Ban) -> Result<()> {
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lift any bans on a username or address, returning how many there were
+    pub fn remove(&mut self, username_or_address: &str) -> Result<usize> {
+        let address: Option<IpAddr> = username_or_address.parse().ok();
+        let before = self.bans.len();
+        self.bans.retain(|ban| {
+            ban.username.as_deref() != Some(username_or_address)
+                && (address.is_none() || ban.address != address)
+        });
+        let removed = before - self.bans.len();
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Any ban on the address. Local addresses never count as banned, whatever the
+    /// file says, so the server can't be locked out of itself.
+    pub fn find_address(&self, address: IpAddr) -> Option<&Ban> {
+        if !is_bannable(address) {
+            return None;
+        }
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active() && ban.address == Some(address))
+    }
+
+    pub fn find_username(&self, username: &str) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active() && ban.username.as_deref() == Some(username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_addresses_are_not_banned() {
+        let ban = Ban::new(
+            Some("alice".to_string()),
+            Some("127.0.0.1".parse().unwrap()),
+            None,
+            None,
+        );
+        assert_eq!(ban.address, None);
+        let ban = Ban::new(None, Some("0.0.0.0".parse().unwrap()), None, None);
+        assert_eq!(ban.address, None);
+        let remote: IpAddr = "203.0.113.7".parse().unwrap();
+        let ban = Ban::new(None, Some(remote), None, None);
+        assert_eq!(ban.address, Some(remote));
+    }
+
+    #[test]
+    fn huge_duration() {
+        let ban = Ban::new(None, None, None, Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(ban.until, Some(LATEST));
+        assert!(ban.is_active());
+
+        let path = std::env::temp_dir().join(format!("bans-{}.toml", std::process::id()));
+        let mut list = BanList::load(&path).unwrap();
+        list.add(ban).unwrap();
+        let loaded = BanList::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().bans[0].until, Some(LATEST));
+    }
+}
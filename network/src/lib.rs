@@ -1,18 +1,44 @@
 use anyhow::*;
 use lazy_static::lazy_static;
 use log::*;
+use mapgen::{DungeonSpecification, Voxel};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+mod ban;
+mod weapon;
+
+pub use ban::{format_duration, is_bannable, Ban, BanList};
+pub use weapon::{Ballistics, Firing, WeaponDefinition, WeaponId, Weapons};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Packet {
-    /// Sent from the client to the server on initial contact
-    Login { username: [u8; 20] },
+    /// Sent from the client to the server on initial contact. The password is only
+    /// needed if the server has one. Spectators don't get a character, and are sent
+    /// every player's whereabouts.
+    Login {
+        username: [u8; 20],
+        password: Option<String>,
+        spectator: bool,
+    },
 
-    /// Sent from the server to the client to create characters.
+    /// Sent from the server to the client after Login when there's more than one
+    /// match to choose from, and every so often until the client joins one. Servers
+    /// with only one match put players straight into it.
+    MatchList { matches: Vec<MatchSummary> },
+
+    /// Sent from the client to the server to join a match from the MatchList. The
+    /// server carries on as it would after Login with only one match.
+    JoinMatch { id: u32 },
+
+    /// Sent from the server to the client to create characters. Other players'
+    /// characters are created as they come into view, and may be created again after
+    /// DestroyCharacter.
     CreateCharacter {
         /// Globally unique ID
         id: u32,
@@ -21,12 +47,505 @@ pub enum Packet {
 
         /// If true, then this character is owned by the given connection
         is_owned: bool,
+
+        /// In team game modes, the side they're on
+        team: Option<Team>,
+    },
+
+    /// Sent from the server to the client when another player's character goes out
+    /// of view. The player is still in the game.
+    DestroyCharacter { id: u32 },
+
+    /// Sent from the client to the server to update the player's state
+    /// Sent from the server to the client with the id and tick filled in to update
+    /// other player's states. The tick is when the server received the state.
+    UpdateState {
+        id: u32,
+        tick: u64,
+        state: PlayerState,
+    },
+
+    /// Sent from the server to the client after Login to say which map is being
+    /// played. The client replies with RequestMap if it doesn't have it cached.
+    MapInfo { hash: [u8; 32], size: u32 },
+
+    /// Sent from the server to the client instead of MapInfo when the map was
+    /// generated. If the client has the same generator version it can regenerate the
+    /// map, and check it against the hash; otherwise it can still RequestMap.
+    MapSeed {
+        version: u32,
+        spec: DungeonSpecification,
+        seed: u64,
+        hash: [u8; 32],
+        size: u32,
     },
 
-    /// Sent from the client to the server to update the player's position
-    /// Sent from the server to the client with the id filled in to update other
-    /// player's positions.
-    UpdatePosition { id: u32, position: [f32; 3] },
+    /// Sent from the client to the server to ask for the map data
+    RequestMap,
+
+    /// Sent from the server to the client in response to RequestMap, in order,
+    /// until size bytes have been sent.
+    MapChunk { offset: u32, data: Vec<u8> },
+
+    /// Sent from the client to the server when the player pulls the trigger on their
+    /// current weapon. The shot comes from the player's eye, as the server knows it.
+    /// Hitscan shots are checked against where other players were at view_tick
+    /// (within limits).
+    Fire { direction: [f32; 3], view_tick: u64 },
+
+    /// Sent from the client to the server to change weapon. Slots count from 0.
+    SwitchWeapon { slot: u32 },
+
+    /// Sent from the client to the server to reload the current weapon
+    Reload,
+
+    /// Sent from the server to the client after Login to say what's being played.
+    /// The time limit is in seconds, if there is one.
+    GameMode {
+        kind: ModeKind,
+        score_limit: u32,
+        time_limit: Option<f32>,
+    },
+
+    /// Sent from the server to all clients when the match moves on from the lobby
+    /// to the countdown, warmup or the match proper (or back), and to clients as
+    /// they join
+    PhaseChanged { phase: MatchPhase },
+
+    /// Sent from the server to all clients while in the lobby or counting down,
+    /// whenever anyone joins, leaves, gets ready or changes team. The countdown
+    /// starts once min_ready players are ready.
+    Lobby {
+        players: Vec<LobbyEntry>,
+        min_ready: u32,
+    },
+
+    /// Sent from the client to the server in the lobby to say whether the player is
+    /// ready to start
+    Ready { ready: bool },
+
+    /// Sent from the client to the server in the lobby to pick a side, in team modes
+    ChooseTeam { team: Team },
+
+    /// Sent from the server to all clients when a round starts, and to clients
+    /// joining part way through one. Seconds, if the round has a time limit.
+    RoundStarted { time_left: Option<f32> },
+
+    /// Sent from the server to all clients when someone reaches the score limit or
+    /// time runs out. The next round starts after a short break.
+    RoundEnded { winner: Winner },
+
+    /// Sent from the server to all clients after RoundEnded, when they get to vote
+    /// on the next map, and to clients joining while the vote is on. The vote
+    /// closes when the next round starts, in time_left seconds.
+    MapVote {
+        choices: Vec<String>,
+        time_left: f32,
+    },
+
+    /// Sent from the client to the server to vote for one of the MapVote choices,
+    /// counting from 0. Players can change their minds until the vote closes.
+    Vote { choice: u32 },
+
+    /// Sent from the server to all clients whenever someone votes, with the number
+    /// of votes for each choice
+    VoteTally { votes: Vec<u32> },
+
+    /// Sent from the server to all clients when a player's score changes
+    PlayerScore { id: u32, score: i32 },
+
+    /// Sent from the server to all clients when a team's score changes
+    TeamScore { team: Team, score: u32 },
+
+    /// Sent from the server to all clients when a team's flag is picked up, dropped,
+    /// returned or captured. While it's carried, position is where it was taken from.
+    FlagChanged {
+        team: Team,
+        position: [f32; 3],
+        carrier: Option<u32>,
+    },
+
+    /// Sent from the server to a client whenever anything in their inventory
+    /// changes. Weapons without magazines have no ammo to count.
+    Inventory {
+        current: u32,
+        reloading: bool,
+        slots: Vec<SlotState>,
+    },
+
+    /// Sent from the server to all clients when a shot hits a player
+    PlayerHit {
+        shooter: u32,
+        target: u32,
+        damage: u32,
+    },
+
+    /// Sent from the server to all clients whenever a player's health changes
+    HealthChanged { id: u32, health: u32 },
+
+    /// Sent from the server to all clients when a player dies, for the kill feed.
+    /// Assists are the other players who hurt the victim shortly before.
+    PlayerKilled {
+        killer: u32,
+        victim: u32,
+        weapon: WeaponId,
+        assists: Vec<u32>,
+    },
+
+    /// Sent from the server to a dead player's own client when they come back, with
+    /// the team they're on now, which changes if the mode does. Others are told with
+    /// CreateCharacter, once the player is in view.
+    Respawn {
+        id: u32,
+        position: [f32; 3],
+        team: Option<Team>,
+    },
+
+    /// Sent from the server to clients when they first see a projectile, which is
+    /// when it's launched for the shooter, and when it comes into view for anyone
+    /// else
+    CreateProjectile {
+        id: u32,
+        weapon: WeaponId,
+        position: [f32; 3],
+        velocity: [f32; 3],
+    },
+
+    /// Sent from the server to clients who have seen the projectile every so often,
+    /// and whenever it bounces
+    UpdateProjectile {
+        id: u32,
+        position: [f32; 3],
+        velocity: [f32; 3],
+    },
+
+    /// Sent from the server to clients who have seen the projectile, or can see
+    /// where it went off, when it goes off. The projectile is gone afterwards.
+    Explosion {
+        id: u32,
+        position: [f32; 3],
+        radius: f32,
+    },
+
+    /// Sent from the server to all clients when voxels are destroyed. Also sent to
+    /// clients after MapInfo or MapSeed with everything destroyed so far, so they
+    /// may arrive before the map itself has.
+    VoxelsChanged { removed: Vec<Voxel> },
+
+    /// Sent from the server to all clients every so often to measure their ping.
+    /// Clients reply straight away with a Pong carrying the same id.
+    Ping { id: u32 },
+
+    /// Sent from the client to the server in reply to a Ping
+    Pong { id: u32 },
+
+    /// Sent from the server to all clients every second with everyone's stats
+    Scoreboard { entries: Vec<ScoreboardEntry> },
+
+    /// Sent from the server to all clients when a player leaves or is kicked
+    PlayerLeft { id: u32 },
+
+    /// Sent from the server to a client it's about to hang up on, saying why
+    Disconnected { reason: String },
+
+    /// Sent from the server to all clients when an admin says something
+    ServerMessage { text: String },
+
+    /// Sent from the client to the server to be allowed to run admin commands. The
+    /// connection doesn't need to have logged in as a player.
+    AdminLogin { password: String },
+
+    /// Sent from the client to the server to run an admin command, once logged in
+    /// with AdminLogin
+    AdminCommand { command: String },
+
+    /// Sent from the server to the client in reply to AdminLogin and AdminCommand
+    AdminResponse { text: String },
+
+    /// Sent from the client to the server to look up a player's career stats, which
+    /// come back in Stats. Works whether or not the client is in a match.
+    GetStats { username: String },
+
+    /// Sent from the server to the client in reply to GetStats. Players the server
+    /// hasn't seen have nothing but their username filled in.
+    Stats { stats: PlayerStats },
+
+    /// Sent from the client to the server to ask for the best players, which come
+    /// back in Leaderboard
+    GetLeaderboard,
+
+    /// Sent from the server to the client in reply to GetLeaderboard, best first
+    Leaderboard { entries: Vec<PlayerStats> },
+}
+
+impl Packet {
+    /// The variant's name, for counting packets by type without formatting their
+    /// contents
+    pub fn name(&self) -> &'static str {
+        match self {
+            Packet::Login { .. } => "Login",
+            Packet::MatchList { .. } => "MatchList",
+            Packet::JoinMatch { .. } => "JoinMatch",
+            Packet::CreateCharacter { .. } => "CreateCharacter",
+            Packet::DestroyCharacter { .. } => "DestroyCharacter",
+            Packet::UpdateState { .. } => "UpdateState",
+            Packet::MapInfo { .. } => "MapInfo",
+            Packet::MapSeed { .. } => "MapSeed",
+            Packet::RequestMap => "RequestMap",
+            Packet::MapChunk { .. } => "MapChunk",
+            Packet::Fire { .. } => "Fire",
+            Packet::SwitchWeapon { .. } => "SwitchWeapon",
+            Packet::Reload => "Reload",
+            Packet::GameMode { .. } => "GameMode",
+            Packet::PhaseChanged { .. } => "PhaseChanged",
+            Packet::Lobby { .. } => "Lobby",
+            Packet::Ready { .. } => "Ready",
+            Packet::ChooseTeam { .. } => "ChooseTeam",
+            Packet::RoundStarted { .. } => "RoundStarted",
+            Packet::RoundEnded { .. } => "RoundEnded",
+            Packet::MapVote { .. } => "MapVote",
+            Packet::Vote { .. } => "Vote",
+            Packet::VoteTally { .. } => "VoteTally",
+            Packet::PlayerScore { .. } => "PlayerScore",
+            Packet::TeamScore { .. } => "TeamScore",
+            Packet::FlagChanged { .. } => "FlagChanged",
+            Packet::Inventory { .. } => "Inventory",
+            Packet::PlayerHit { .. } => "PlayerHit",
+            Packet::HealthChanged { .. } => "HealthChanged",
+            Packet::PlayerKilled { .. } => "PlayerKilled",
+            Packet::Respawn { .. } => "Respawn",
+            Packet::CreateProjectile { .. } => "CreateProjectile",
+            Packet::UpdateProjectile { .. } => "UpdateProjectile",
+            Packet::Explosion { .. } => "Explosion",
+            Packet::VoxelsChanged { .. } => "VoxelsChanged",
+            Packet::Ping { .. } => "Ping",
+            Packet::Pong { .. } => "Pong",
+            Packet::Scoreboard { .. } => "Scoreboard",
+            Packet::PlayerLeft { .. } => "PlayerLeft",
+            Packet::Disconnected { .. } => "Disconnected",
+            Packet::ServerMessage { .. } => "ServerMessage",
+            Packet::AdminLogin { .. } => "AdminLogin",
+            Packet::AdminCommand { .. } => "AdminCommand",
+            Packet::AdminResponse { .. } => "AdminResponse",
+            Packet::GetStats { .. } => "GetStats",
+            Packet::Stats { .. } => "Stats",
+            Packet::GetLeaderboard => "GetLeaderboard",
+            Packet::Leaderboard { .. } => "Leaderboard",
+        }
+    }
+}
+
+/// Where a match is up to. Times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MatchPhase {
+    /// Waiting for enough players to get ready. Nobody can move or shoot.
+    Lobby,
+
+    /// Enough players are ready, and the warmup starts when time runs out
+    Countdown { time_left: f32 },
+
+    /// Everyone is in and can fight, but the scores are wiped when it's over
+    Warmup { time_left: f32 },
+
+    /// The match proper, round after round
+    Playing,
+}
+
+/// One player's line in the lobby
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyEntry {
+    pub id: u32,
+    pub username: [u8; 20],
+    pub team: Option<Team>,
+    pub ready: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeKind {
+    Deathmatch,
+    TeamDeathmatch,
+    CaptureTheFlag,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    pub const ALL: [Team; 2] = [Team::Red, Team::Blue];
+
+    pub fn opponent(self) -> Team {
+        match self {
+            Team::Red => Team::Blue,
+            Team::Blue => Team::Red,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Winner {
+    Player(u32),
+    Team(Team),
+    Draw,
+}
+
+/// One weapon in a player's inventory
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SlotState {
+    pub weapon: WeaponId,
+    pub magazine: u32,
+
+    /// Ammo besides what's in the magazine
+    pub reserve: u32,
+}
+
+/// A match the server is hosting, for players to choose between
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MatchSummary {
+    pub id: u32,
+    pub name: String,
+    pub mode: ModeKind,
+    pub players: u32,
+}
+
+/// A player's record over every round they've played on a server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub username: String,
+    pub kills: u32,
+    pub deaths: u32,
+
+    /// Shots fired, and how many of them hurt someone else
+    pub shots: u32,
+    pub hits: u32,
+
+    /// Rounds played through to the end, and how many of those they won
+    pub matches: u32,
+    pub wins: u32,
+}
+
+impl PlayerStats {
+    /// The fraction of shots that hit
+    pub fn accuracy(&self) -> f32 {
+        if self.shots == 0 {
+            0.0
+        } else {
+            self.hits as f32 / self.shots as f32
+        }
+    }
+}
+
+/// One player's line on the scoreboard
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScoreboardEntry {
+    pub id: u32,
+    pub username: [u8; 20],
+    pub team: Option<Team>,
+
+    /// As the game mode counts it
+    pub score: i32,
+
+    pub kills: u32,
+    pub deaths: u32,
+    pub assists: u32,
+
+    /// Round trip time in milliseconds
+    pub ping: u32,
+}
+
+/// The rate at which the server simulates the game
+pub const TICKS_PER_SECOND: u64 = 60;
+
+/// The most map data sent in a single MapChunk
+pub const MAP_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The most voxels listed in a single VoxelsChanged
+pub const MAX_VOXELS_PER_PACKET: usize = 4096;
+
+/// The hash used to identify maps
+pub fn content_hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Usernames are sent as UTF-8 padded out with zeroes. Longer names are cut short.
+pub fn encode_username(name: &str) -> [u8; 20] {
+    let mut username = [0; 20];
+    let mut length = name.len().min(username.len());
+    while !name.is_char_boundary(length) {
+        length -= 1;
+    }
+    username[..length].copy_from_slice(&name.as_bytes()[..length]);
+    username
+}
+
+pub fn decode_username(username: &[u8; 20]) -> String {
+    let length = username
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(username.len());
+    String::from_utf8_lossy(&username[..length]).into_owned()
+}
+
+/// Everything other clients need to know to draw (and extrapolate) a player.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub position: [f32; 3],
+
+    /// In units per second
+    pub velocity: [f32; 3],
+
+    /// Heading in degrees around the Y axis, where 0 faces +Z
+    pub yaw: f32,
+
+    /// Degrees above the horizon
+    pub pitch: f32,
+
+    pub crouching: bool,
+    pub airborne: bool,
+}
+
+pub const MAX_HEALTH: u32 = 100;
+
+// Player dimensions in world units, measured from the player's feet
+pub const EYE_HEIGHT: f32 = 1.7;
+pub const CROUCHED_EYE_HEIGHT: f32 = 1.1;
+pub const PLAYER_HEIGHT: f32 = 1.8;
+pub const CROUCHED_PLAYER_HEIGHT: f32 = 1.2;
+
+/// Half the width of the player's hitbox
+pub const PLAYER_RADIUS: f32 = 0.3;
+
+/// How fast players walk, in units per second
+pub const WALK_SPEED: f32 = 0.5;
+
+impl PlayerState {
+    pub fn eye_height(&self) -> f32 {
+        if self.crouching {
+            CROUCHED_EYE_HEIGHT
+        } else {
+            EYE_HEIGHT
+        }
+    }
+
+    pub fn height(&self) -> f32 {
+        if self.crouching {
+            CROUCHED_PLAYER_HEIGHT
+        } else {
+            PLAYER_HEIGHT
+        }
+    }
+
+    /// The opposite corners of the player's bounding box
+    pub fn hitbox(&self) -> ([f32; 3], [f32; 3]) {
+        let [x, y, z] = self.position;
+        (
+            [x - PLAYER_RADIUS, y, z - PLAYER_RADIUS],
+            [x + PLAYER_RADIUS, y + self.height(), z + PLAYER_RADIUS],
+        )
+    }
 }
 
 pub struct Connection {
@@ -36,6 +555,13 @@ pub struct Connection {
 
     /// The buffer for bytes read from reliable
     buffer: RefCell<Vec<u8>>,
+
+    /// Bytes waiting for room in the socket's send buffer
+    outgoing: RefCell<Vec<u8>>,
+
+    /// Set once the other end has hung up, so that the packets it sent before then
+    /// are handed over before the close is reported
+    closed: Cell<bool>,
 }
 
 lazy_static! {
@@ -56,57 +582,107 @@ impl Connection {
     pub fn connect() -> Result<Self> {
         let stream = TcpStream::connect("127.0.0.1:3419")?;
         stream.set_nonblocking(true)?;
-        Ok(Self {
+        Ok(Self::new(stream))
+    }
+
+    /// The address of the other end, if it's still connected
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.reliable.borrow().peer_addr().ok()
+    }
+
+    fn new(stream: TcpStream) -> Self {
+        Self {
             uid: get_next_uid(),
             reliable: RefCell::new(stream),
             buffer: RefCell::new(vec![]),
-        })
+            outgoing: RefCell::new(vec![]),
+            closed: Cell::new(false),
+        }
     }
 
+    /// Queue the packet and send as much of the queue as the socket will take. Whatever
+    /// doesn't fit is sent by later calls to flush, send or update.
     pub fn send(&self, packet: &Packet) -> Result<()> {
         let encoded: Vec<u8> = bincode::serialize(packet)?;
+        if encoded.len() > u16::MAX as usize {
+            bail!("Packet too large ({} bytes)", encoded.len());
+        }
         let size = encoded.len() as u16;
-        self.reliable.borrow_mut().write(&size.to_le_bytes())?;
-        self.reliable.borrow_mut().write(&encoded)?;
+        {
+            let mut outgoing = self.outgoing.borrow_mut();
+            outgoing.extend_from_slice(&size.to_le_bytes());
+            outgoing.extend_from_slice(&encoded);
+        }
+        self.flush()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        while !outgoing.is_empty() {
+            let result = self.reliable.borrow_mut().write(&outgoing);
+            match result {
+                Ok(0) => {
+                    return Err(Error::new(ConnectionClosed));
+                }
+                Ok(n) => {
+                    outgoing.drain(..n);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) => {
+                    return Err(network_error(e));
+                }
+            }
+        }
         Ok(())
     }
 
     pub fn update<F: FnMut(&Packet) -> Result<()>>(&self, mut cb: F) -> Result<()> {
+        if self.closed.get() {
+            return Err(Error::new(ConnectionClosed));
+        }
+        self.flush()?;
         loop {
-            let mut data = [0; 64];
-            match self.reliable.borrow_mut().read(&mut data) {
+            let mut data = [0; 4096];
+            let result = self.reliable.borrow_mut().read(&mut data);
+            match result {
+                Ok(0) => {
+                    self.closed.set(true);
+                    return Ok(());
+                }
                 Ok(n) => {
-                    for b in &data[..n] {
-                        self.buffer.borrow_mut().push(*b);
-                    }
-                    if n == 0 {
-                        return Ok(());
-                    }
+                    self.buffer.borrow_mut().extend_from_slice(&data[..n]);
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     return Ok(());
                 }
                 Err(e) => {
-                    return Err(Error::new(e).context("Network"));
+                    return Err(network_error(e));
                 }
             };
 
-            if self.buffer.borrow().len() < 2 {
-                continue;
+            // Parse every complete packet we have
+            while let Some(packet) = self.next_packet()? {
+                cb(&packet)?;
             }
-            let size =
-                u16::from_le_bytes([self.buffer.borrow()[0], self.buffer.borrow()[1]]) as usize;
-            if self.buffer.borrow().len() < 2 + size {
-                continue;
-            }
-
-            // Parse this packet!
-            let packet: Packet = bincode::deserialize(&self.buffer.borrow()[2..2 + size])
-                .context("Decoding packet")?;
-            cb(&packet)?;
+        }
+    }
 
-            self.buffer.borrow_mut().drain(..2 + size);
+    fn next_packet(&self) -> Result<Option<Packet>> {
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let size = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+        if buffer.len() < 2 + size {
+            return Ok(None);
         }
+
+        let packet: Packet =
+            bincode::deserialize(&buffer[2..2 + size]).context("Decoding packet")?;
+        buffer.drain(..2 + size);
+        Ok(Some(packet))
     }
 
     pub fn packets(&self) -> Result<Vec<Packet>> {
@@ -119,8 +695,38 @@ impl Connection {
     }
 }
 
+/// What a connection fails with when the other end has hung up, rather than
+/// because anything went wrong
+#[derive(Debug)]
+pub struct ConnectionClosed;
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Connection closed")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+/// Errors that only mean the other end went away are ConnectionClosed
+fn network_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::ConnectionReset
+        | std::io::ErrorKind::ConnectionAborted
+        | std::io::ErrorKind::BrokenPipe
+        | std::io::ErrorKind::UnexpectedEof => Error::new(ConnectionClosed),
+        _ => Error::new(e).context("Network"),
+    }
+}
+
+/// How long a connection that's been turned away gets to read why
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct ConnectionListener {
     listener: TcpListener,
+
+    /// Banned connections that have been told so, and when
+    rejected: Vec<(Connection, Instant)>,
 }
 
 impl ConnectionListener {
@@ -129,26 +735,67 @@ impl ConnectionListener {
         tcp_listener.set_nonblocking(true).unwrap();
         Ok(Self {
             listener: tcp_listener,
+            rejected: vec![],
         })
     }
 
-    pub fn update(&mut self) -> Result<Option<Connection>> {
+    /// Accept a new connection, unless its address is banned
+    pub fn update(&mut self, bans: &BanList) -> Result<Option<Connection>> {
+        self.rejected.retain(|(cxn, since)| {
+            since.elapsed() < REJECT_TIMEOUT && cxn.update(|_| Ok(())).is_ok()
+        });
+
         match self.listener.accept() {
             Ok((stream, addr)) => {
                 info!("Connection received from {:?}", addr);
                 stream.set_nonblocking(true)?;
-                return Ok(Some(Connection {
-                    uid: get_next_uid(),
-                    reliable: RefCell::new(stream),
-                    buffer: RefCell::new(vec![]),
-                }));
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                return Ok(None);
-            }
-            Err(e) => {
-                return Err(Error::new(e));
+                let cxn = Connection::new(stream);
+                if let Some(ban) = bans.find_address(addr.ip()) {
+                    info!("Turning away banned address {}", addr.ip());
+                    let reason = ban.message();
+                    if cxn.send(&Packet::Disconnected { reason }).is_ok() {
+                        self.rejected.push((cxn, Instant::now()));
+                    }
+                    return Ok(None);
+                }
+                Ok(Some(cxn))
             }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(Error::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_packets_before_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let client = Connection::new(stream);
+        let server = Connection::new(listener.accept().unwrap().0);
+        server
+            .send(&Packet::Disconnected {
+                reason: "Bye".to_string(),
+            })
+            .unwrap();
+        drop(server);
+
+        let start = Instant::now();
+        let mut packets = vec![];
+        while packets.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            packets = client.packets().unwrap();
         }
+        assert!(matches!(packets[..], [Packet::Disconnected { .. }]));
+        let error = loop {
+            match client.packets() {
+                Ok(more) => assert!(more.is_empty()),
+                Err(e) => break e,
+            }
+        };
+        assert!(error.is::<ConnectionClosed>());
     }
 }
@@ -0,0 +1,295 @@
+use crate::TICKS_PER_SECOND;
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The longest any time in a weapon definition may be, in seconds, so that tick
+/// counts stay well within range
+const MAX_SECONDS: f32 = 3600.0;
+
+/// The furthest any weapon may reach or blast, in world units. Much bigger spheres
+/// of destruction would take the server more than a tick to carve out.
+const MAX_DISTANCE: f32 = 64.0;
+
+/// Spread is the angle away from the aim, so it has to stay short of a right angle
+const MAX_SPREAD: f32 = 89.0;
+
+/// Weapons are referred to by their index in the weapon definitions file
+pub type WeaponId = u32;
+
+/// Everything about how a weapon behaves, as loaded from res/weapons.toml
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WeaponDefinition {
+    pub name: String,
+
+    /// The model in res/ drawn in the player's hands, if any
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// For hitscan weapons, the damage per hit. For projectiles, the damage at the
+    /// center of the explosion, falling off linearly to nothing at its edge.
+    #[serde(default)]
+    pub damage: u32,
+
+    pub rounds_per_minute: f32,
+
+    /// The widest angle, in degrees, shots may stray from where the player aimed
+    #[serde(default)]
+    pub spread: f32,
+
+    /// Shots before the weapon needs reloading, or None if it never does
+    #[serde(default)]
+    pub magazine_size: Option<u32>,
+
+    /// Ammo carried besides what's in the magazine, when the player spawns
+    #[serde(default)]
+    pub spare_ammo: u32,
+
+    /// In seconds
+    #[serde(default)]
+    pub reload_time: f32,
+
+    /// How long it takes to get the weapon out before it can fire, in seconds
+    #[serde(default)]
+    pub switch_time: f32,
+
+    pub firing: Firing,
+}
+
+impl WeaponDefinition {
+    /// The time between shots
+    pub fn cooldown_ticks(&self) -> u64 {
+        (60.0 * TICKS_PER_SECOND as f32 / self.rounds_per_minute).round() as u64
+    }
+
+    pub fn reload_ticks(&self) -> u64 {
+        (self.reload_time * TICKS_PER_SECOND as f32).round() as u64
+    }
+
+    pub fn switch_ticks(&self) -> u64 {
+        (self.switch_time * TICKS_PER_SECOND as f32).round() as u64
+    }
+
+    /// Check the numbers make sense, so that nothing downstream has to
+    fn validate(&self) -> Result<()> {
+        if !(self.rounds_per_minute.is_finite() && self.rounds_per_minute >= 60.0 / MAX_SECONDS) {
+            bail!(
+                "rounds_per_minute must be at least {}, not {}",
+                60.0 / MAX_SECONDS,
+                self.rounds_per_minute
+            );
+        }
+        check_range("spread", self.spread, 0.0, MAX_SPREAD)?;
+        check_range("reload_time", self.reload_time, 0.0, MAX_SECONDS)?;
+        check_range("switch_time", self.switch_time, 0.0, MAX_SECONDS)?;
+        match &self.firing {
+            Firing::Hitscan { range } => check_range("range", *range, 0.0, MAX_DISTANCE)?,
+            Firing::Projectile(ballistics) => ballistics.validate()?,
+            Firing::Dig { range, radius } => {
+                check_range("range", *range, 0.0, MAX_DISTANCE)?;
+                check_range("radius", *radius, 0.0, MAX_DISTANCE)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Firing {
+    /// Hits the first thing along the line of fire, immediately
+    Hitscan { range: f32 },
+
+    /// Launches a projectile, which does the damage when it explodes
+    Projectile(Ballistics),
+
+    /// Destroys the voxels within radius of where it hits a wall, if that's within
+    /// range
+    Dig { range: f32, radius: f32 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ballistics {
+    /// The model in res/ drawn for the projectile in flight
+    pub model: String,
+
+    /// Launch speed in units per second
+    pub speed: f32,
+
+    /// In units per second per second
+    #[serde(default)]
+    pub gravity: f32,
+
+    /// The fraction of its speed a projectile keeps after bouncing, or None if it
+    /// explodes on contact with anything instead
+    #[serde(default)]
+    pub restitution: Option<f32>,
+
+    /// How long until the projectile explodes by itself, in seconds
+    pub fuse_time: f32,
+
+    pub splash_radius: f32,
+
+    /// Voxels within this distance of the explosion are destroyed
+    #[serde(default)]
+    pub destroy_radius: f32,
+}
+
+impl Ballistics {
+    pub fn fuse_ticks(&self) -> u64 {
+        (self.fuse_time * TICKS_PER_SECOND as f32).round() as u64
+    }
+
+    fn validate(&self) -> Result<()> {
+        check_range("speed", self.speed, 0.0, f32::MAX)?;
+        check_range("gravity", self.gravity, -f32::MAX, f32::MAX)?;
+        if let Some(restitution) = self.restitution {
+            check_range("restitution", restitution, 0.0, 1.0)?;
+        }
+        check_range("fuse_time", self.fuse_time, 0.0, MAX_SECONDS)?;
+        check_range("splash_radius", self.splash_radius, 0.0, MAX_DISTANCE)?;
+        check_range("destroy_radius", self.destroy_radius, 0.0, MAX_DISTANCE)
+    }
+}
+
+/// Fails unless the value is a number from min to max inclusive
+fn check_range(name: &str, value: f32, min: f32, max: f32) -> Result<()> {
+    if !(min..=max).contains(&value) {
+        bail!("{} must be from {} to {}, not {}", name, min, max, value);
+    }
+    Ok(())
+}
+
+/// The contents of a weapon definitions file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Weapons {
+    #[serde(rename = "weapon")]
+    definitions: Vec<WeaponDefinition>,
+}
+
+impl Weapons {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Reading weapons {:?}", path.as_ref()))?;
+        Self::parse(&data).with_context(|| format!("Loading weapons {:?}", path.as_ref()))
+    }
+
+    fn parse(data: &str) -> Result<Self> {
+        let weapons: Self = toml::from_str(data)?;
+        if weapons.definitions.is_empty() {
+            bail!("No weapons defined");
+        }
+        for definition in weapons.definitions.iter() {
+            definition
+                .validate()
+                .with_context(|| format!("Weapon {:?}", definition.name))?;
+        }
+        Ok(weapons)
+    }
+
+    pub fn get(&self, id: WeaponId) -> Option<&WeaponDefinition> {
+        self.definitions.get(id as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (WeaponId, &WeaponDefinition)> {
+        self.definitions
+            .iter()
+            .enumerate()
+            .map(|(id, definition)| (id as WeaponId, definition))
+    }
+
+    pub fn len(&self) -> usize {
+        self.definitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIFLE: &str = r#"
+        [[weapon]]
+        name = "Rifle"
+        rounds_per_minute = 300
+        reload_time = 2.0
+        firing = { type = "hitscan", range = 50.0 }
+    "#;
+
+    fn error(data: &str) -> String {
+        format!("{:#}", Weapons::parse(data).unwrap_err())
+    }
+
+    #[test]
+    fn valid() {
+        let weapons = Weapons::parse(RIFLE).unwrap();
+        assert_eq!(
+            weapons.get(0).unwrap().cooldown_ticks(),
+            TICKS_PER_SECOND / 5
+        );
+    }
+
+    #[test]
+    fn shipped_weapons() {
+        Weapons::load("../res/weapons.toml").unwrap();
+    }
+
+    #[test]
+    fn no_weapons() {
+        assert!(error("weapon = []").contains("No weapons"));
+    }
+
+    #[test]
+    fn zero_rate_of_fire() {
+        let data = RIFLE.replace("rounds_per_minute = 300", "rounds_per_minute = 0");
+        let error = error(&data);
+        assert!(error.contains("Rifle"), "{}", error);
+        assert!(error.contains("rounds_per_minute"), "{}", error);
+    }
+
+    #[test]
+    fn negative_or_nan_times() {
+        let negative = RIFLE.replace("reload_time = 2.0", "reload_time = -1.0");
+        assert!(error(&negative).contains("reload_time"));
+        let nan = RIFLE.replace("reload_time = 2.0", "reload_time = nan");
+        assert!(error(&nan).contains("reload_time"));
+        let spread = RIFLE.replace("firing", "spread = nan\n        firing");
+        assert!(error(&spread).contains("spread"));
+    }
+
+    #[test]
+    fn negative_fuse_time() {
+        let data = RIFLE.replace(
+            r#"{ type = "hitscan", range = 50.0 }"#,
+            r#"{ type = "projectile", model = "voxel.obj", speed = 15.0, fuse_time = -5.0, splash_radius = 1.5 }"#,
+        );
+        assert!(error(&data).contains("fuse_time"));
+    }
+
+    #[test]
+    fn out_of_reach() {
+        let range = RIFLE.replace("range = 50.0", "range = 1e30");
+        assert!(error(&range).contains("range"));
+        let dig = RIFLE.replace(
+            r#"{ type = "hitscan", range = 50.0 }"#,
+            r#"{ type = "dig", range = 3.0, radius = 1e30 }"#,
+        );
+        assert!(error(&dig).contains("radius"));
+        let destroy = RIFLE.replace(
+            r#"{ type = "hitscan", range = 50.0 }"#,
+            r#"{ type = "projectile", model = "voxel.obj", speed = 15.0, fuse_time = 5.0, splash_radius = 1.5, destroy_radius = 1e30 }"#,
+        );
+        assert!(error(&destroy).contains("destroy_radius"));
+    }
+
+    #[test]
+    fn right_angle_spread() {
+        let data = RIFLE.replace("firing", "spread = 90\n        firing");
+        assert!(error(&data).contains("spread"));
+        let data = RIFLE.replace("firing", "spread = 45\n        firing");
+        Weapons::parse(&data).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+use anyhow::*;
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+pub const HELP: &str = "\
+kick <player> [reason]      Disconnect a player, by id or username. With more
+                            than one match, <match>:<player> says which.
+ban <player> [time] [reason]
+                            Kick a player and keep them out, for a time such as
+                            30m, 12h or 7d, or forever. Players who aren't on can
+                            be banned by username or address.
+unban <player>              Lift the bans on a username or address
+status                      List the players and what's being played
+stats <username>            Show a player's career stats
+leaderboard                 List the players with the most wins
+say <message>               Send a message to everyone
+changemap [file]            Load a new map, or the configured one again
+set <setting> <value>       Change a config value, such as mode.time_limit
+match <match> <command>     Run status, changemap or set on one match, by number
+                            or name. Otherwise they act on the first match,
+                            except status, which lists them all.
+shutdown                    Disconnect everyone and stop the server
+help                        Show this list";
+
+/// Something an admin has asked the server to do, from the console or remotely
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Kick {
+        player: String,
+        reason: Option<String>,
+    },
+    Ban {
+        player: String,
+        /// Forever if not given
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    Unban {
+        player: String,
+    },
+    Status,
+    Stats {
+        username: String,
+    },
+    Leaderboard,
+    Say {
+        message: String,
+    },
+    ChangeMap {
+        file: Option<String>,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Another command, run on one match instead of the first
+    Match {
+        name: String,
+        command: Box<Command>,
+    },
+    Shutdown,
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self> {
+        let (name, rest) = split_word(line);
+        let command = match name {
+            "kick" => {
+                let (player, reason) = split_word(rest);
+                if player.is_empty() {
+                    bail!("Usage: kick <player> [reason]");
+                }
+                Command::Kick {
+                    player: player.to_string(),
+                    reason: non_empty(reason),
+                }
+            }
+            "ban" => {
+                let (player, rest) = split_word(rest);
+                if player.is_empty() {
+                    bail!("Usage: ban <player> [time] [reason]");
+                }
+                // The time is optional, so a reason can start straight away
+                let (time, reason) = split_word(rest);
+                let (duration, reason) = match parse_duration(time) {
+                    Some(duration) => (Some(duration), reason),
+                    None => (None, rest),
+                };
+                Command::Ban {
+                    player: player.to_string(),
+                    duration,
+                    reason: non_empty(reason),
+                }
+            }
+            "unban" => {
+                let (player, rest) = split_word(rest);
+                if player.is_empty() || !rest.is_empty() {
+                    bail!("Usage: unban <player>");
+                }
+                Command::Unban {
+                    player: player.to_string(),
+                }
+            }
+            "status" => Command::Status,
+            "stats" => {
+                let (username, rest) = split_word(rest);
+                if username.is_empty() || !rest.is_empty() {
+                    bail!("Usage: stats <username>");
+                }
+                Command::Stats {
+                    username: username.to_string(),
+                }
+            }
+            "leaderboard" => Command::Leaderboard,
+            "say" => match non_empty(rest) {
+                Some(message) => Command::Say { message },
+                None => bail!("Usage: say <message>"),
+            },
+            "changemap" => {
+                let (file, rest) = split_word(rest);
+                if !rest.is_empty() {
+                    bail!("Usage: changemap [file]");
+                }
+                Command::ChangeMap {
+                    file: non_empty(file),
+                }
+            }
+            "set" => {
+                let (key, value) = split_word(rest);
+                if key.is_empty() || value.is_empty() {
+                    bail!("Usage: set <setting> <value>");
+                }
+                Command::Set {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                }
+            }
+            "match" => {
+                let (name, rest) = split_word(rest);
+                if name.is_empty() || rest.is_empty() {
+                    bail!("Usage: match <match> <command>");
+                }
+                let command = Command::parse(rest)?;
+                if !matches!(
+                    command,
+                    Command::Status | Command::ChangeMap { .. } | Command::Set { .. }
+                ) {
+                    bail!("Only status, changemap and set can be run on a match");
+                }
+                Command::Match {
+                    name: name.to_string(),
+                    command: Box::new(command),
+                }
+            }
+            "shutdown" => Command::Shutdown,
+            "help" => Command::Help,
+            "" => bail!("No command given"),
+            _ => bail!("Unknown command {:?}, try help", name),
+        };
+        Ok(command)
+    }
+}
+
+/// Read lines from stdin on another thread, so the game doesn't wait for them
+pub fn console() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// The first word, and the rest with surrounding whitespace trimmed off
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Settings whose values are kept out of logs
+const SECRET_SETTINGS: [&str; 2] = ["password", "admin_password"];
+
+/// The command as it's safe to write down, with the values of secret settings
+/// hidden
+pub fn redact(line: &str) -> String {
+    let (name, rest) = split_word(line);
+    match name {
+        "set" => {
+            let (key, value) = split_word(rest);
+            if SECRET_SETTINGS.contains(&key) && !value.is_empty() {
+                return format!("set {} <hidden>", key);
+            }
+        }
+        "match" => {
+            let (name, rest) = split_word(rest);
+            if !rest.is_empty() {
+                return format!("match {} {}", name, redact(rest));
+            }
+        }
+        _ => {}
+    }
+    line.to_string()
+}
+
+/// A number of seconds, minutes, hours, days or weeks, like 90s or 7d
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        _ => return None,
+    };
+    let amount: u64 = text[..text.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(amount.checked_mul(unit)?))
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kick_with_and_without_reason() {
+        assert_eq!(
+            Command::parse("kick bob").unwrap(),
+            Command::Kick {
+                player: "bob".to_string(),
+                reason: None,
+            }
+        );
+        assert_eq!(
+            Command::parse("kick 3 being  rude ").unwrap(),
+            Command::Kick {
+                player: "3".to_string(),
+                reason: Some("being  rude".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn ban() {
+        assert_eq!(
+            Command::parse("ban alice aimbot").unwrap(),
+            Command::Ban {
+                player: "alice".to_string(),
+                duration: None,
+                reason: Some("aimbot".to_string()),
+            }
+        );
+        assert_eq!(
+            Command::parse("ban 10.0.0.7").unwrap(),
+            Command::Ban {
+                player: "10.0.0.7".to_string(),
+                duration: None,
+                reason: None,
+            }
+        );
+    }
+
+    #[test]
+    fn timed_ban() {
+        assert_eq!(
+            Command::parse("ban alice 2h spawn camping").unwrap(),
+            Command::Ban {
+                player: "alice".to_string(),
+                duration: Some(Duration::from_secs(7200)),
+                reason: Some("spawn camping".to_string()),
+            }
+        );
+        assert_eq!(
+            Command::parse("ban bob 7d").unwrap(),
+            Command::Ban {
+                player: "bob".to_string(),
+                duration: Some(Duration::from_secs(7 * 86400)),
+                reason: None,
+            }
+        );
+        // Not a time, so part of the reason
+        assert_eq!(
+            Command::parse("ban bob 3rd warning").unwrap(),
+            Command::Ban {
+                player: "bob".to_string(),
+                duration: None,
+                reason: Some("3rd warning".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(1209600)));
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+    }
+
+    #[test]
+    fn unban() {
+        assert_eq!(
+            Command::parse("unban alice").unwrap(),
+            Command::Unban {
+                player: "alice".to_string(),
+            }
+        );
+        assert!(Command::parse("unban").is_err());
+        assert!(Command::parse("unban alice bob").is_err());
+    }
+
+    #[test]
+    fn kick_and_ban_need_a_player() {
+        assert!(Command::parse("kick").is_err());
+        assert!(Command::parse("ban   ").is_err());
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(Command::parse("status").unwrap(), Command::Status);
+        assert_eq!(Command::parse("  shutdown\n").unwrap(), Command::Shutdown);
+        assert_eq!(Command::parse("help").unwrap(), Command::Help);
+        assert_eq!(Command::parse("leaderboard").unwrap(), Command::Leaderboard);
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(
+            Command::parse("stats alice").unwrap(),
+            Command::Stats {
+                username: "alice".to_string(),
+            }
+        );
+        assert!(Command::parse("stats").is_err());
+        assert!(Command::parse("stats alice bob").is_err());
+    }
+
+    #[test]
+    fn say_keeps_the_whole_message() {
+        assert_eq!(
+            Command::parse("say  Back in 5 minutes, don't  go away\n").unwrap(),
+            Command::Say {
+                message: "Back in 5 minutes, don't  go away".to_string(),
+            }
+        );
+        assert!(Command::parse("say").is_err());
+    }
+
+    #[test]
+    fn changemap() {
+        assert_eq!(
+            Command::parse("changemap").unwrap(),
+            Command::ChangeMap { file: None }
+        );
+        assert_eq!(
+            Command::parse("changemap maps/arena.bin").unwrap(),
+            Command::ChangeMap {
+                file: Some("maps/arena.bin".to_string()),
+            }
+        );
+        assert!(Command::parse("changemap a.bin b.bin").is_err());
+    }
+
+    #[test]
+    fn set() {
+        assert_eq!(
+            Command::parse("set mode.time_limit 300").unwrap(),
+            Command::Set {
+                key: "mode.time_limit".to_string(),
+                value: "300".to_string(),
+            }
+        );
+        assert_eq!(
+            Command::parse("set admin_password \"two words\"").unwrap(),
+            Command::Set {
+                key: "admin_password".to_string(),
+                value: "\"two words\"".to_string(),
+            }
+        );
+        assert!(Command::parse("set mode.time_limit").is_err());
+        assert!(Command::parse("set").is_err());
+    }
+
+    #[test]
+    fn redact_secrets() {
+        assert_eq!(redact("set password hunter2"), "set password <hidden>");
+        assert_eq!(
+            redact("  set admin_password  two words"),
+            "set admin_password <hidden>"
+        );
+        assert_eq!(
+            redact("match 1 set password hunter2"),
+            "match 1 set password <hidden>"
+        );
+        assert_eq!(redact("set bots 4"), "set bots 4");
+        assert_eq!(redact("say password hunter2"), "say password hunter2");
+    }
+
+    #[test]
+    fn match_commands() {
+        assert_eq!(
+            Command::parse("match arena set bots 4").unwrap(),
+            Command::Match {
+                name: "arena".to_string(),
+                command: Box::new(Command::Set {
+                    key: "bots".to_string(),
+                    value: "4".to_string(),
+                }),
+            }
+        );
+        assert_eq!(
+            Command::parse("match 1 status").unwrap(),
+            Command::Match {
+                name: "1".to_string(),
+                command: Box::new(Command::Status),
+            }
+        );
+        assert!(Command::parse("match arena").is_err());
+        assert!(Command::parse("match arena kick bob").is_err());
+        assert!(Command::parse("match 1 match 2 status").is_err());
+    }
+
+    #[test]
+    fn unknown_and_empty_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("   ").is_err());
+        assert!(Command::parse("restart").is_err());
+        assert!(Command::parse("KICK bob").is_err());
+    }
+}
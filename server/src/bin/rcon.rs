@@ -0,0 +1,62 @@
+//! Runs admin commands on a server over the network. The password is the server's
+//! admin_password setting.
+//!
+//!     rcon <password> [command]
+//!
+//! Without a command, commands are read from stdin, one per line.
+
+use anyhow::*;
+use network::{Connection, Packet};
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the server to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let password = args.next().context("Usage: rcon <password> [command]")?;
+    let command: Vec<_> = args.collect();
+
+    let connection = Connection::connect()?;
+    connection.send(&Packet::AdminLogin { password })?;
+    let response = wait_for_response(&connection)?;
+    if response != "Logged in" {
+        bail!("{}", response);
+    }
+
+    if !command.is_empty() {
+        run(&connection, &command.join(" "))?;
+        return Ok(());
+    }
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            run(&connection, &line)?;
+        }
+    }
+    Ok(())
+}
+
+fn run(connection: &Connection, command: &str) -> Result<()> {
+    connection.send(&Packet::AdminCommand {
+        command: command.to_string(),
+    })?;
+    println!("{}", wait_for_response(connection)?);
+    Ok(())
+}
+
+fn wait_for_response(connection: &Connection) -> Result<String> {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        for packet in connection.packets()? {
+            match packet {
+                Packet::AdminResponse { text } => return Ok(text),
+                Packet::Disconnected { reason } => bail!("Disconnected: {}", reason),
+                _ => {}
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    bail!("No response from the server")
+}
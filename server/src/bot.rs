@@ -0,0 +1,258 @@
+use crate::game::Player;
+use crate::map::Map;
+use cgmath::{InnerSpace, Point3, Vector3, Zero};
+use mapgen::Room;
+use network::{PlayerState, TICKS_PER_SECOND, WALK_SPEED};
+use rand::prelude::*;
+use std::collections::HashMap;
+
+/// Enemies further away than this go unnoticed
+const VIEW_RANGE: f32 = 30.0;
+
+/// How often a bot picks someone to hunt and works out how to get to them
+const REPLAN_TICKS: u64 = 2 * TICKS_PER_SECOND;
+
+/// How often a bot's aim wanders
+const AIM_ERROR_TICKS: u64 = TICKS_PER_SECOND / 2;
+
+/// How close a bot has to get to the middle of a room on its route before heading
+/// for the next one
+const WAYPOINT_RADIUS: f32 = 0.3;
+
+/// Bots close in on enemies they can see, until they're this close
+const KEEP_DISTANCE: f32 = 3.0;
+
+/// A player run by the server, which hunts down its enemies room by room and shoots
+/// them when it sees them
+pub struct Bot {
+    /// From 0, hopeless, to 1, as good as it gets
+    skill: f32,
+
+    /// The rooms still to walk through, next first
+    route: Vec<Room>,
+    replan_tick: u64,
+
+    /// The points to walk through to get to the middle of the next room on the
+    /// route, next first
+    path: Vec<[f32; 3]>,
+
+    /// The enemy in sight, and the tick they came into view
+    target: Option<(u32, u64)>,
+
+    /// How far off the bot's aim is, in degrees of yaw and pitch, until aim_error_tick
+    aim_error: (f32, f32),
+    aim_error_tick: u64,
+}
+
+/// What a bot does in a tick
+pub struct Action {
+    pub state: PlayerState,
+
+    /// The direction to shoot in, if it's shooting
+    pub fire: Option<Vector3<f32>>,
+}
+
+impl Bot {
+    pub fn new(skill: f32) -> Self {
+        Self {
+            skill: skill.clamp(0.0, 1.0),
+            route: vec![],
+            replan_tick: 0,
+            path: vec![],
+            target: None,
+            aim_error: (0.0, 0.0),
+            aim_error_tick: 0,
+        }
+    }
+
+    pub fn set_skill(&mut self, skill: f32) {
+        self.skill = skill.clamp(0.0, 1.0);
+    }
+
+    /// Somewhere between the worst and best values, depending on skill
+    fn by_skill(&self, worst: f32, best: f32) -> f32 {
+        worst + (best - worst) * self.skill
+    }
+
+    /// Forget what it was doing, after dying
+    pub fn reset(&mut self) {
+        self.route.clear();
+        self.path.clear();
+        self.replan_tick = 0;
+        self.target = None;
+    }
+
+    pub fn think<R: Rng>(
+        &mut self,
+        me: &Player,
+        players: &HashMap<u32, Player>,
+        map: &Map,
+        tick: u64,
+        rng: &mut R,
+    ) -> Action {
+        let mut state = me.state;
+        let eye = Point3::from(state.position) + Vector3::unit_y() * state.eye_height();
+        let enemies: Vec<&Player> = players
+            .values()
+            .filter(|player| {
+                player.id != me.id
+                    && player.is_alive()
+                    && (me.team.is_none() || player.team != me.team)
+            })
+            .collect();
+        let in_sight = enemies
+            .iter()
+            .map(|enemy| (enemy.id, chest(&enemy.state)))
+            .filter(|(_, chest)| {
+                (chest - eye).magnitude() < VIEW_RANGE && map.line_of_sight(eye, *chest)
+            })
+            .min_by(|a, b| {
+                let a = (a.1 - eye).magnitude();
+                let b = (b.1 - eye).magnitude();
+                a.partial_cmp(&b).unwrap()
+            });
+
+        let (heading, fire) = match in_sight {
+            Some((id, chest)) => {
+                let seen_since = match self.target {
+                    Some((target, since)) if target == id => since,
+                    _ => tick,
+                };
+                self.target = Some((id, seen_since));
+                if tick >= self.aim_error_tick {
+                    let error = self.by_skill(8.0, 0.5);
+                    self.aim_error = (
+                        rng.gen_range(-error..=error),
+                        rng.gen_range(-error..=error) / 2.0,
+                    );
+                    self.aim_error_tick = tick + AIM_ERROR_TICKS;
+                }
+
+                let offset = chest - eye;
+                let (yaw, pitch) = angles(offset);
+                let on_target =
+                    self.turn_towards(&mut state, yaw + self.aim_error.0, pitch + self.aim_error.1);
+                let reaction_ticks = self.by_skill(0.75, 0.15) * TICKS_PER_SECOND as f32;
+                let fire = if on_target && tick >= seen_since + reaction_ticks as u64 {
+                    Some(direction(state.yaw, state.pitch))
+                } else {
+                    None
+                };
+                let heading = if offset.magnitude() > KEEP_DISTANCE {
+                    Some(offset)
+                } else {
+                    None
+                };
+                (heading, fire)
+            }
+            None => {
+                self.target = None;
+                let heading = self.follow_route(&state, &enemies, map, tick, rng);
+                if let Some(heading) = heading {
+                    self.turn_towards(&mut state, angles(heading).0, 0.0);
+                }
+                (heading, None)
+            }
+        };
+
+        let mut velocity = Vector3::zero();
+        if let Some(heading) = heading {
+            let flat = Vector3::new(heading.x, 0.0, heading.z);
+            if flat.magnitude() > 0.01 {
+                velocity = flat.normalize() * WALK_SPEED;
+            }
+        }
+        let position = Point3::from(state.position) + velocity / TICKS_PER_SECOND as f32;
+        state.position = position.into();
+        state.velocity = velocity.into();
+        Action { state, fire }
+    }
+
+    /// Which way to walk to get to the next room on the route, working out a new
+    /// route to the nearest enemy (or anywhere, if there are none) every so often.
+    /// The way through each room is found on the map's nav grid, to get round
+    /// anything in the way.
+    fn follow_route<R: Rng>(
+        &mut self,
+        state: &PlayerState,
+        enemies: &[&Player],
+        map: &Map,
+        tick: u64,
+        rng: &mut R,
+    ) -> Option<Vector3<f32>> {
+        let rooms = map.rooms();
+        let here = rooms.room_at(state.position)?;
+        let position = Point3::from(state.position);
+        if self.route.is_empty() || tick >= self.replan_tick {
+            self.replan_tick = tick + REPLAN_TICKS;
+            let nearest = enemies.iter().min_by(|a, b| {
+                let a = (Point3::from(a.state.position) - position).magnitude();
+                let b = (Point3::from(b.state.position) - position).magnitude();
+                a.partial_cmp(&b).unwrap()
+            });
+            let goal = match nearest.and_then(|enemy| rooms.room_at(enemy.state.position)) {
+                Some(room) => room,
+                None => rooms.rooms().choose(rng)?,
+            };
+            self.route = rooms.route(here, goal).unwrap_or_default();
+            self.path.clear();
+        }
+
+        loop {
+            let next = self.route.first()?.center();
+            if self.path.is_empty() {
+                // Straight there, if the nav grid doesn't know a way
+                self.path = map
+                    .nav()
+                    .find_path(state.position, next)
+                    .unwrap_or_else(|| vec![next]);
+            }
+            while let Some(waypoint) = self.path.first() {
+                let mut offset = Point3::from(*waypoint) - position;
+                offset.y = 0.0;
+                if offset.magnitude() >= WAYPOINT_RADIUS {
+                    return Some(offset);
+                }
+                self.path.remove(0);
+            }
+            self.route.remove(0);
+        }
+    }
+
+    /// Turn as far as the bot can in a tick towards the given yaw and pitch,
+    /// returning whether it got there
+    fn turn_towards(&self, state: &mut PlayerState, yaw: f32, pitch: f32) -> bool {
+        let max_turn = self.by_skill(180.0, 720.0) / TICKS_PER_SECOND as f32;
+        let yaw_left = angle_between(state.yaw, yaw);
+        let pitch_left = pitch - state.pitch;
+        state.yaw = angle_between(0.0, state.yaw + yaw_left.clamp(-max_turn, max_turn));
+        state.pitch += pitch_left.clamp(-max_turn, max_turn);
+        yaw_left.abs() <= max_turn && pitch_left.abs() <= max_turn
+    }
+}
+
+/// What bots aim for
+fn chest(state: &PlayerState) -> Point3<f32> {
+    Point3::from(state.position) + Vector3::unit_y() * state.height() * 0.7
+}
+
+/// The yaw and pitch, in degrees, that look along the given vector
+fn angles(vector: Vector3<f32>) -> (f32, f32) {
+    let yaw = vector.x.atan2(vector.z).to_degrees();
+    let pitch = (vector.y / vector.magnitude()).asin().to_degrees();
+    (yaw, pitch)
+}
+
+fn direction(yaw: f32, pitch: f32) -> Vector3<f32> {
+    let (yaw, pitch) = (yaw.to_radians(), pitch.to_radians());
+    Vector3::new(
+        pitch.cos() * yaw.sin(),
+        pitch.sin(),
+        pitch.cos() * yaw.cos(),
+    )
+}
+
+/// The shortest turn from one heading to another, in degrees
+fn angle_between(from: f32, to: f32) -> f32 {
+    (to - from + 180.0).rem_euclid(360.0) - 180.0
+}
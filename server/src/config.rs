@@ -0,0 +1,300 @@
+use anyhow::*;
+use log::*;
+use mapgen::DungeonSpecification;
+use network::ModeKind;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The first match, whose settings sit at the top of the file
+    #[serde(flatten)]
+    pub game: MatchConfig,
+
+    /// More matches to host alongside the first, each with its own players
+    #[serde(rename = "match")]
+    pub matches: Vec<MatchConfig>,
+
+    /// The weapon definitions file. Clients load their own copy, which must match.
+    pub weapons: String,
+
+    /// Remote admin commands are refused unless this is set
+    pub admin_password: Option<String>,
+
+    /// If set, players need it to join
+    pub password: Option<String>,
+
+    /// Where bans are kept between runs
+    pub ban_file: String,
+
+    /// Where players' career stats are kept
+    pub stats_file: String,
+
+    /// If set, the state of every match is saved here now and then, and picked up
+    /// again when the server starts
+    pub snapshot_file: Option<String>,
+
+    /// Seconds between snapshots
+    pub snapshot_interval: f32,
+
+    /// If set, metrics are served on this port at http://127.0.0.1:<port>/metrics
+    pub metrics_port: Option<u16>,
+
+    /// If set, joins, kills, admin commands and so on are appended to this file as
+    /// JSON lines
+    pub event_log: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            game: MatchConfig::default(),
+            matches: vec![],
+            weapons: "res/weapons.toml".to_string(),
+            admin_password: None,
+            password: None,
+            ban_file: "bans.toml".to_string(),
+            stats_file: "stats.toml".to_string(),
+            snapshot_file: None,
+            snapshot_interval: 30.0,
+            metrics_port: None,
+            event_log: None,
+        }
+    }
+}
+
+/// The settings for one game the server hosts
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MatchConfig {
+    /// What players choose it by
+    pub name: String,
+
+    pub map: MapSource,
+
+    /// More maps to play in turn after the first, moving on after every round
+    pub maps: Vec<RotationMap>,
+
+    /// How many maps from the rotation players choose between at the end of each
+    /// round. With less than 2, the maps are played in order.
+    pub vote_choices: u32,
+
+    pub mode: ModeConfig,
+    pub lobby: LobbyConfig,
+
+    /// The furthest back in time shots are checked against, to make up for the
+    /// shooter's latency. Capped at a second.
+    pub max_rewind_ms: u64,
+
+    /// How many players the server runs itself
+    pub bots: u32,
+
+    /// How well bots aim, from 0 to 1
+    pub bot_skill: f32,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            map: MapSource::File {
+                file: "map.bin".to_string(),
+            },
+            maps: vec![],
+            vote_choices: 0,
+            mode: ModeConfig::default(),
+            lobby: LobbyConfig::default(),
+            max_rewind_ms: 200,
+            bots: 0,
+            bot_skill: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModeConfig {
+    #[serde(rename = "type")]
+    pub kind: ModeKind,
+
+    /// If not given, each mode has its own
+    pub score_limit: Option<u32>,
+
+    /// The length of a round in seconds, or 0 for no limit
+    pub time_limit: f32,
+}
+
+impl Default for ModeConfig {
+    fn default() -> Self {
+        Self {
+            kind: ModeKind::Deathmatch,
+            score_limit: None,
+            time_limit: 600.0,
+        }
+    }
+}
+
+/// What happens before a match starts
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LobbyConfig {
+    /// How many players, counting bots, have to be ready for the countdown to
+    /// start. 0 skips the lobby, countdown and warmup.
+    pub min_ready: u32,
+
+    /// Seconds from enough players being ready to the warmup
+    pub countdown: f32,
+
+    /// Seconds of play that don't count before the match proper, or 0 for none
+    pub warmup: f32,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self {
+            min_ready: 2,
+            countdown: 10.0,
+            warmup: 30.0,
+        }
+    }
+}
+
+/// Either `file = "..."` or a `generate` table
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MapSource {
+    /// A map file, which is streamed to clients
+    File { file: String },
+
+    /// A dungeon generated at startup, which clients regenerate from the seed
+    Generate { generate: GenerateMap },
+}
+
+impl MapSource {
+    /// What to call the map when players vote on it, if it isn't given a name
+    pub fn default_name(&self) -> String {
+        match self {
+            MapSource::File { file } => Path::new(file)
+                .file_stem()
+                .map_or_else(|| file.clone(), |stem| stem.to_string_lossy().into_owned()),
+            MapSource::Generate { generate } => match generate.seed {
+                Some(seed) => format!("dungeon {}", seed),
+                None => "random dungeon".to_string(),
+            },
+        }
+    }
+}
+
+/// A map in the rotation: a map source, and optionally what to call it
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RotationMap {
+    pub name: Option<String>,
+
+    #[serde(flatten)]
+    pub source: MapSource,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct GenerateMap {
+    pub spec: DungeonSpecification,
+
+    /// If not given, a random seed is used
+    pub seed: Option<u64>,
+}
+
+impl Config {
+    /// Load the config file, or use the defaults if there isn't one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            info!("No config at {:?}, using defaults", path);
+            return Ok(Self::default());
+        }
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Parsing {:?}", path))
+    }
+
+    /// Change one setting, given by its dotted path such as `mode.time_limit`. The
+    /// value is TOML, but strings don't need quoting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        set(self, key, value)
+    }
+
+    /// The current value of a setting, as TOML
+    pub fn get(&self, key: &str) -> Option<String> {
+        get(self, key)
+    }
+
+    /// Every match to host, the first first
+    pub fn all_matches(&self) -> impl Iterator<Item = &MatchConfig> {
+        std::iter::once(&self.game).chain(self.matches.iter())
+    }
+}
+
+impl MatchConfig {
+    /// The maps to play in turn, starting with `map`, with their names
+    pub fn rotation(&self) -> Vec<(String, MapSource)> {
+        let first = (self.map.default_name(), self.map.clone());
+        std::iter::once(first)
+            .chain(self.maps.iter().map(|map| {
+                let name = map
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| map.source.default_name());
+                (name, map.source.clone())
+            }))
+            .collect()
+    }
+
+    /// Change one of the match's settings, as with Config::set
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        set(self, key, value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        get(self, key)
+    }
+}
+
+fn set<T: serde::Serialize + DeserializeOwned>(
+    target: &mut T,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let value = match format!("value = {}", value).parse::<toml::Value>() {
+        Ok(toml::Value::Table(mut table)) => table.remove("value").unwrap(),
+        _ => toml::Value::String(value.to_string()),
+    };
+    let mut tree = toml::Value::try_from(&*target)?;
+    let mut path: Vec<&str> = key.split('.').collect();
+    let name = path.pop().unwrap();
+    let mut table = tree.as_table_mut().unwrap();
+    for part in path {
+        table = table
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .with_context(|| format!("{} isn't a table", part))?;
+    }
+    table.insert(name.to_string(), value);
+    let changed: T = tree.try_into().context("Invalid value")?;
+
+    // Settings that don't exist are quietly ignored when deserializing
+    if lookup(&toml::Value::try_from(&changed)?, key).is_none() {
+        bail!("Unknown setting {}", key);
+    }
+    *target = changed;
+    Ok(())
+}
+
+fn get<T: serde::Serialize>(target: &T, key: &str) -> Option<String> {
+    let tree = toml::Value::try_from(target).ok()?;
+    lookup(&tree, key).map(|value| value.to_string())
+}
+
+fn lookup<'a>(tree: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.').try_fold(tree, |value, part| value.get(part))
+}
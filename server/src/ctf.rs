@@ -0,0 +1,287 @@
+use crate::game::Player;
+use crate::map::Map;
+use crate::mode::{self, GameMode};
+use anyhow::*;
+use network::{ModeKind, Packet, Team, Winner, TICKS_PER_SECOND};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How close, horizontally, a player needs to be to pick up a flag
+const TOUCH_DISTANCE: f32 = 1.0;
+
+/// How far above or below a flag a player can be and still pick it up
+const TOUCH_HEIGHT: f32 = 2.0;
+
+/// How long a dropped flag lies around before it goes home by itself
+const RETURN_TICKS: u64 = 30 * TICKS_PER_SECOND;
+
+/// Respawning takes longer than in deathmatch, so that killing a defender buys time
+const RESPAWN_TICKS: u64 = 5 * TICKS_PER_SECOND;
+
+#[derive(Deserialize, Serialize)]
+struct Flag {
+    team: Team,
+    base: [f32; 3],
+    position: [f32; 3],
+    carrier: Option<u32>,
+
+    /// When a dropped flag was dropped
+    dropped_tick: Option<u64>,
+}
+
+impl Flag {
+    fn is_home(&self) -> bool {
+        self.carrier.is_none() && self.position == self.base
+    }
+
+    fn go_home(&mut self) {
+        self.position = self.base;
+        self.carrier = None;
+        self.dropped_tick = None;
+    }
+
+    fn packet(&self) -> Packet {
+        Packet::FlagChanged {
+            team: self.team,
+            position: self.position,
+            carrier: self.carrier,
+        }
+    }
+}
+
+/// Each team has a flag at their base. Carrying the other team's flag back to your
+/// own, while your own flag is there, scores a capture.
+#[derive(Deserialize, Serialize)]
+pub struct CaptureTheFlag {
+    score_limit: u32,
+    flags: Vec<Flag>,
+    captures: HashMap<Team, u32>,
+
+    /// Kills and captures, for the players' own satisfaction
+    player_scores: HashMap<u32, i32>,
+}
+
+impl CaptureTheFlag {
+    /// The bases go at the two spawn points furthest from each other
+    pub fn new(map: &Map, score_limit: u32) -> Self {
+        let spawns = map.spawn_points();
+        let mut bases = (spawns[0], spawns[0]);
+        let mut furthest = 0.0;
+        for (i, a) in spawns.iter().enumerate() {
+            for b in spawns[i + 1..].iter() {
+                let distance = distance_squared(a, b);
+                if distance > furthest {
+                    furthest = distance;
+                    bases = (*a, *b);
+                }
+            }
+        }
+        let flag = |team, base| Flag {
+            team,
+            base,
+            position: base,
+            carrier: None,
+            dropped_tick: None,
+        };
+        Self {
+            score_limit,
+            flags: vec![flag(Team::Red, bases.0), flag(Team::Blue, bases.1)],
+            captures: HashMap::new(),
+            player_scores: HashMap::new(),
+        }
+    }
+
+    fn flag(&self, team: Team) -> &Flag {
+        self.flags.iter().find(|flag| flag.team == team).unwrap()
+    }
+
+    fn add_player_score(&mut self, id: u32, points: i32) -> Packet {
+        let score = self.player_scores.entry(id).or_insert(0);
+        *score += points;
+        Packet::PlayerScore { id, score: *score }
+    }
+}
+
+impl GameMode for CaptureTheFlag {
+    fn kind(&self) -> ModeKind {
+        ModeKind::CaptureTheFlag
+    }
+
+    fn score_limit(&self) -> u32 {
+        self.score_limit
+    }
+
+    fn state(&self) -> Vec<Packet> {
+        let mut packets: Vec<_> = self.flags.iter().map(Flag::packet).collect();
+        packets.extend(self.captures.iter().map(|(team, score)| Packet::TeamScore {
+            team: *team,
+            score: *score,
+        }));
+        packets.extend(
+            self.player_scores
+                .iter()
+                .map(|(id, score)| Packet::PlayerScore {
+                    id: *id,
+                    score: *score,
+                }),
+        );
+        packets
+    }
+
+    fn player_score(&self, id: u32) -> i32 {
+        self.player_scores.get(&id).copied().unwrap_or(0)
+    }
+
+    fn choose_team(&mut self, players: &HashMap<u32, Player>) -> Option<Team> {
+        Some(mode::smaller_team(players))
+    }
+
+    /// Players spawn on their own half of the map
+    fn is_spawn_for(&self, team: Option<Team>, spawn: [f32; 3]) -> bool {
+        match team {
+            Some(team) => {
+                distance_squared(&spawn, &self.flag(team).base)
+                    <= distance_squared(&spawn, &self.flag(team.opponent()).base)
+            }
+            None => true,
+        }
+    }
+
+    fn respawn_ticks(&self) -> u64 {
+        RESPAWN_TICKS
+    }
+
+    fn player_killed(&mut self, killer: &Player, victim: &Player) -> Vec<Packet> {
+        let mut packets = vec![];
+        if killer.id != victim.id {
+            packets.push(self.add_player_score(killer.id, 1));
+        }
+        for flag in self.flags.iter_mut() {
+            if flag.carrier == Some(victim.id) {
+                flag.carrier = None;
+                flag.position = victim.state.position;
+                flag.dropped_tick = None;
+                packets.push(flag.packet());
+            }
+        }
+        packets
+    }
+
+    fn update(&mut self, players: &HashMap<u32, Player>, tick: u64) -> Vec<Packet> {
+        let mut packets = vec![];
+        let mut captured = vec![];
+        let home: HashMap<Team, bool> = self
+            .flags
+            .iter()
+            .map(|flag| (flag.team, flag.is_home()))
+            .collect();
+        let bases: HashMap<Team, [f32; 3]> = self
+            .flags
+            .iter()
+            .map(|flag| (flag.team, flag.base))
+            .collect();
+
+        for flag in self.flags.iter_mut() {
+            if let Some(carrier) = flag.carrier.and_then(|id| players.get(&id)) {
+                // Carried flags score once they reach the carrier's base, if their own
+                // flag is safe at home
+                let team = flag.team.opponent();
+                if home[&team] && touching(&carrier.state.position, &bases[&team]) {
+                    flag.go_home();
+                    packets.push(flag.packet());
+                    captured.push((team, carrier.id));
+                }
+                continue;
+            }
+            if flag.carrier.is_some() {
+                // The carrier has gone
+                flag.carrier = None;
+                packets.push(flag.packet());
+            }
+
+            if flag.dropped_tick.is_none() && !flag.is_home() {
+                flag.dropped_tick = Some(tick);
+            }
+            if matches!(flag.dropped_tick, Some(dropped) if dropped + RETURN_TICKS <= tick) {
+                flag.go_home();
+                packets.push(flag.packet());
+                continue;
+            }
+
+            let toucher = players.values().find(|player| {
+                player.is_alive() && touching(&player.state.position, &flag.position)
+            });
+            match toucher {
+                Some(player) if player.team == Some(flag.team) && !flag.is_home() => {
+                    flag.go_home();
+                    packets.push(flag.packet());
+                }
+                Some(player) if player.team == Some(flag.team.opponent()) => {
+                    flag.carrier = Some(player.id);
+                    flag.dropped_tick = None;
+                    packets.push(flag.packet());
+                }
+                _ => {}
+            }
+        }
+
+        for (team, carrier) in captured {
+            let score = self.captures.entry(team).or_insert(0);
+            *score += 1;
+            packets.push(Packet::TeamScore {
+                team,
+                score: *score,
+            });
+            packets.push(self.add_player_score(carrier, 1));
+        }
+        packets
+    }
+
+    fn winner(&self, time_up: bool) -> Option<Winner> {
+        match mode::leader(self.captures.iter().map(|(team, score)| (*team, *score))) {
+            Some((team, score)) if time_up || score >= self.score_limit => Some(Winner::Team(team)),
+            _ if time_up => Some(Winner::Draw),
+            _ => None,
+        }
+    }
+
+    fn reset(&mut self) -> Vec<Packet> {
+        let mut packets = vec![];
+        for flag in self.flags.iter_mut() {
+            flag.go_home();
+            packets.push(flag.packet());
+        }
+        packets.extend(self.captures.keys().map(|team| Packet::TeamScore {
+            team: *team,
+            score: 0,
+        }));
+        packets.extend(
+            self.player_scores
+                .keys()
+                .map(|id| Packet::PlayerScore { id: *id, score: 0 }),
+        );
+        self.captures.clear();
+        self.player_scores.clear();
+        packets
+    }
+
+    fn save(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<()> {
+        *self = bincode::deserialize(data)?;
+        Ok(())
+    }
+}
+
+fn touching(player: &[f32; 3], flag: &[f32; 3]) -> bool {
+    let dx = player[0] - flag[0];
+    let dz = player[2] - flag[2];
+    dx * dx + dz * dz <= TOUCH_DISTANCE * TOUCH_DISTANCE
+        && (player[1] - flag[1]).abs() <= TOUCH_HEIGHT
+}
+
+fn distance_squared(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
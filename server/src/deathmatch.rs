@@ -0,0 +1,139 @@
+use crate::game::Player;
+use crate::mode::{self, GameMode};
+use anyhow::*;
+use network::{ModeKind, Packet, Team, Winner};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Every kill scores a point, either for the killer or for their team. Suicides
+/// lose the player a point.
+#[derive(Deserialize, Serialize)]
+pub struct Deathmatch {
+    teams: bool,
+    score_limit: u32,
+    player_scores: HashMap<u32, i32>,
+    team_scores: HashMap<Team, u32>,
+}
+
+impl Deathmatch {
+    pub fn new(teams: bool, score_limit: u32) -> Self {
+        Self {
+            teams,
+            score_limit,
+            player_scores: HashMap::new(),
+            team_scores: HashMap::new(),
+        }
+    }
+
+    fn add_player_score(&mut self, id: u32, points: i32) -> Packet {
+        let score = self.player_scores.entry(id).or_insert(0);
+        *score += points;
+        Packet::PlayerScore { id, score: *score }
+    }
+}
+
+impl GameMode for Deathmatch {
+    fn kind(&self) -> ModeKind {
+        if self.teams {
+            ModeKind::TeamDeathmatch
+        } else {
+            ModeKind::Deathmatch
+        }
+    }
+
+    fn score_limit(&self) -> u32 {
+        self.score_limit
+    }
+
+    fn state(&self) -> Vec<Packet> {
+        let players = self
+            .player_scores
+            .iter()
+            .map(|(id, score)| Packet::PlayerScore {
+                id: *id,
+                score: *score,
+            });
+        let teams = self
+            .team_scores
+            .iter()
+            .map(|(team, score)| Packet::TeamScore {
+                team: *team,
+                score: *score,
+            });
+        players.chain(teams).collect()
+    }
+
+    fn player_score(&self, id: u32) -> i32 {
+        self.player_scores.get(&id).copied().unwrap_or(0)
+    }
+
+    fn choose_team(&mut self, players: &HashMap<u32, Player>) -> Option<Team> {
+        if self.teams {
+            Some(mode::smaller_team(players))
+        } else {
+            None
+        }
+    }
+
+    fn player_killed(&mut self, killer: &Player, victim: &Player) -> Vec<Packet> {
+        if killer.id == victim.id {
+            return vec![self.add_player_score(killer.id, -1)];
+        }
+        let mut packets = vec![self.add_player_score(killer.id, 1)];
+        if let Some(team) = killer.team {
+            let score = self.team_scores.entry(team).or_insert(0);
+            *score += 1;
+            packets.push(Packet::TeamScore {
+                team,
+                score: *score,
+            });
+        }
+        packets
+    }
+
+    fn winner(&self, time_up: bool) -> Option<Winner> {
+        if self.teams {
+            let leader = mode::leader(self.team_scores.iter().map(|(team, score)| (*team, *score)));
+            match leader {
+                Some((team, score)) if time_up || score >= self.score_limit => {
+                    Some(Winner::Team(team))
+                }
+                _ if time_up => Some(Winner::Draw),
+                _ => None,
+            }
+        } else {
+            let leader = mode::leader(self.player_scores.iter().map(|(id, score)| (*id, *score)));
+            match leader {
+                Some((id, score)) if time_up || score >= self.score_limit as i32 => {
+                    Some(Winner::Player(id))
+                }
+                _ if time_up => Some(Winner::Draw),
+                _ => None,
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Vec<Packet> {
+        let mut packets: Vec<_> = self
+            .player_scores
+            .keys()
+            .map(|id| Packet::PlayerScore { id: *id, score: 0 })
+            .collect();
+        packets.extend(self.team_scores.keys().map(|team| Packet::TeamScore {
+            team: *team,
+            score: 0,
+        }));
+        self.player_scores.clear();
+        self.team_scores.clear();
+        packets
+    }
+
+    fn save(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<()> {
+        *self = bincode::deserialize(data)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::*;
+use log::*;
+use network::Winner;
+use serde_derive::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Something that happened in a match, worth keeping for looking over afterwards
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Join {
+        player: u32,
+        username: String,
+        bot: bool,
+    },
+    Leave {
+        player: u32,
+        username: String,
+    },
+    Spectate {
+        username: String,
+    },
+    Spawn {
+        player: u32,
+        position: [f32; 3],
+    },
+    /// The direction is where the shot went, after spread
+    Shot {
+        player: u32,
+        weapon: String,
+        origin: [f32; 3],
+        direction: [f32; 3],
+        view_tick: u64,
+    },
+    Hit {
+        shooter: u32,
+        target: u32,
+        weapon: String,
+        damage: u32,
+        health: u32,
+    },
+    Kill {
+        killer: u32,
+        victim: u32,
+        weapon: String,
+        assists: Vec<u32>,
+    },
+    RoundStart {
+        map: String,
+    },
+    RoundEnd {
+        winner: Winner,
+    },
+    Chat {
+        from: String,
+        text: String,
+    },
+    Admin {
+        source: String,
+        command: String,
+    },
+}
+
+/// One line of the log. Events from outside any match have no tick or match.
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since the Unix epoch
+    time: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tick: Option<u64>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    match_name: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Appends events to a file as JSON, one per line, if there's a file to write to
+pub struct EventLog {
+    file: Option<BufWriter<File>>,
+}
+
+impl EventLog {
+    pub fn open<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let path = path.as_ref();
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Opening {:?}", path))?;
+                info!("Logging events to {:?}", path);
+                Some(BufWriter::new(file))
+            }
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    pub fn write(&mut self, tick: Option<u64>, match_name: Option<&str>, event: &Event) {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let record = Record {
+            time,
+            tick,
+            match_name,
+            event,
+        };
+        let result = serde_json::to_writer(&mut *file, &record)
+            .map_err(Error::from)
+            .and_then(|()| Ok(file.write_all(b"\n")?));
+        if let Err(e) = result {
+            error!("Couldn't log an event: {:#}", e);
+        }
+    }
+
+    /// Write out what's buffered, so the file is never more than a tick behind
+    pub fn flush(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.flush() {
+                error!("Couldn't write the event log: {}", e);
+            }
+        }
+    }
+}
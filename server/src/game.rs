@@ -0,0 +1,2190 @@
+use crate::bot::Bot;
+use crate::config::{LobbyConfig, MapSource, MatchConfig, ModeConfig};
+use crate::events::Event;
+use crate::history::HitboxHistory;
+use crate::inventory::Inventory;
+use crate::map::{Map, MapSnapshot};
+use crate::metrics::MatchGauges;
+use crate::mode::{self, GameMode};
+use crate::projectile::{Projectile, SavedProjectile, Step};
+use crate::raycast;
+use crate::stats::RoundResult;
+use crate::weapon;
+use anyhow::*;
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3};
+use log::*;
+use mapgen::VOXEL_SIZE;
+use network::{
+    Firing, LobbyEntry, MatchPhase, MatchSummary, ModeKind, Packet, PlayerState, ScoreboardEntry,
+    Team, WeaponId, Weapons, Winner, MAX_HEALTH, MAX_VOXELS_PER_PACKET, TICKS_PER_SECOND,
+};
+use rand::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// The break between one round ending and the next starting
+const INTERMISSION_TICKS: u64 = 10 * TICKS_PER_SECOND;
+
+/// Projectile positions are sent to clients this often, besides when they bounce
+const PROJECTILE_UPDATE_TICKS: u64 = TICKS_PER_SECOND / 10;
+
+/// How often the scoreboard is sent out
+const SCOREBOARD_TICKS: u64 = TICKS_PER_SECOND;
+
+/// How often clients are pinged
+const PING_TICKS: u64 = 2 * TICKS_PER_SECOND;
+
+/// Damage done this recently before a kill earns an assist
+const ASSIST_TICKS: u64 = 10 * TICKS_PER_SECOND;
+
+/// The most max_rewind_ms can stretch to, so nobody keeps a huge history
+const MAX_REWIND_TICKS: u64 = TICKS_PER_SECOND;
+
+/// How often bots' movements are sent to clients
+const BOT_UPDATE_TICKS: u64 = TICKS_PER_SECOND / 20;
+
+/// How often we work out who each client should know about
+const RELEVANCE_TICKS: u64 = TICKS_PER_SECOND / 10;
+
+/// How long a player stays relevant after going out of view, so they don't flicker
+/// in and out at corners
+const RELEVANCE_GRACE_TICKS: u64 = TICKS_PER_SECOND / 2;
+
+/// Where the match is up to, with the tick each timed phase ends
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+enum Phase {
+    Lobby,
+    Countdown { end_tick: u64 },
+    Warmup { end_tick: u64 },
+    Playing,
+}
+
+/// Players choosing the next map, between rounds
+#[derive(Clone, Deserialize, Serialize)]
+struct Vote {
+    /// Indices into the rotation, the next map in order first
+    choices: Vec<usize>,
+
+    /// Each player's choice, as an index into choices, by player id
+    votes: HashMap<u32, usize>,
+}
+
+/// Someone watching the game without taking part
+struct Spectator {
+    username: [u8; 20],
+
+    /// The players their client has been told about. Spectators know about every
+    /// living player, wherever they are.
+    known: HashSet<u32>,
+}
+
+/// Everything about a match that should survive the server restarting. Ticks are
+/// kept as they were, so the times things happen at carry on from where they were.
+#[derive(Deserialize, Serialize)]
+pub struct GameSnapshot {
+    map: MapSnapshot,
+    rotation_index: usize,
+
+    /// What the mode was made from, and the mode's own state
+    mode_config: ModeConfig,
+    mode: Vec<u8>,
+
+    phase: Phase,
+    round_end_tick: Option<u64>,
+    intermission_end_tick: Option<u64>,
+    vote: Option<Vote>,
+    players: Vec<SavedPlayer>,
+    next_id: u32,
+    projectiles: Vec<SavedProjectile>,
+    next_projectile_id: u32,
+    tick: u64,
+}
+
+/// A player as kept in a snapshot
+#[derive(Deserialize, Serialize)]
+struct SavedPlayer {
+    id: u32,
+    bot: bool,
+    username: [u8; 20],
+    team: Option<Team>,
+    state: PlayerState,
+    health: u32,
+    inventory: Inventory,
+    respawn_tick: Option<u64>,
+    kills: u32,
+    deaths: u32,
+    assists: u32,
+    shots: u32,
+    hits: u32,
+}
+
+/// Which connections a packet from the game should be sent to, by connection uid
+pub enum Audience {
+    Everyone,
+    Only(u32),
+    Group(Vec<u32>),
+}
+
+impl Audience {
+    pub fn includes(&self, uid: u32) -> bool {
+        match self {
+            Audience::Everyone => true,
+            Audience::Only(included) => uid == *included,
+            Audience::Group(included) => included.contains(&uid),
+        }
+    }
+}
+
+/// A hitscan shot, once the shooter's weapon has fired
+struct Shot {
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    view_tick: u64,
+    weapon: WeaponId,
+    damage: u32,
+    range: f32,
+}
+
+pub struct Player {
+    pub id: u32,
+
+    /// The connection's uid, or None for bots
+    pub connection: Option<u32>,
+
+    pub username: [u8; 20],
+    pub team: Option<Team>,
+    pub state: PlayerState,
+    pub health: u32,
+    inventory: Inventory,
+
+    /// In the lobby, whether they're ready to start. Bots always are.
+    ready: bool,
+
+    /// While dead, the tick at which the player respawns
+    respawn_tick: Option<u64>,
+
+    history: HitboxHistory,
+
+    /// This round's tally
+    kills: u32,
+    deaths: u32,
+    assists: u32,
+
+    /// This round's shots fired, and how many of them hurt someone else
+    shots: u32,
+    hits: u32,
+
+    /// The last measured round trip time, in milliseconds
+    ping: u32,
+
+    /// Who has hurt this player since they spawned, and the last tick they did
+    attackers: HashMap<u32, u64>,
+
+    /// The other players this player's client has been told about, and the last
+    /// tick each was in view
+    relevant: HashMap<u32, u64>,
+}
+
+impl Player {
+    pub fn is_alive(&self) -> bool {
+        self.respawn_tick.is_none()
+    }
+}
+
+pub struct Game {
+    map: Map,
+
+    /// The maps to play in turn, with their names, and which is being played
+    rotation: Vec<(String, MapSource)>,
+    rotation_index: usize,
+    vote_choices: u32,
+
+    /// Between rounds, if players are choosing the next map
+    vote: Option<Vote>,
+
+    weapons: Weapons,
+    mode: Box<dyn GameMode>,
+
+    /// What the mode was made from, and whether it's been changed since, in which
+    /// case the new mode takes over at the start of the next round
+    mode_config: ModeConfig,
+    mode_changed: bool,
+
+    phase: Phase,
+    lobby_config: LobbyConfig,
+
+    /// The length of each round, if they're limited
+    time_limit_ticks: Option<u64>,
+
+    /// When the current round runs out of time, if it will
+    round_end_tick: Option<u64>,
+
+    /// Between rounds, when the next one starts
+    intermission_end_tick: Option<u64>,
+
+    /// Keyed by player id
+    players: HashMap<u32, Player>,
+    next_id: u32,
+
+    /// Keyed by connection uid
+    spectators: HashMap<u32, Spectator>,
+
+    /// Players restored from a snapshot who haven't reconnected yet, by username.
+    /// They get their place back if they return before the round is over.
+    returning: HashMap<[u8; 20], Player>,
+
+    /// The players the server runs itself, keyed by player id
+    bots: HashMap<u32, Bot>,
+    bot_count: u32,
+    bot_skill: f32,
+
+    /// Keyed by projectile id
+    projectiles: HashMap<u32, Projectile>,
+    next_projectile_id: u32,
+
+    /// The number of ticks since the server started
+    tick: u64,
+
+    /// How far back shots may be checked against
+    max_rewind_ticks: u64,
+
+    /// The id of the last Ping sent, and when it was sent
+    ping: (u32, Instant),
+
+    rng: ThreadRng,
+    outgoing: Vec<(Audience, Packet)>,
+
+    /// How players did, as rounds end or they leave part way through
+    results: Vec<RoundResult>,
+
+    /// What's happened since the last call to drain_events, with the tick each
+    /// happened on
+    events: Vec<(u64, Event)>,
+}
+
+impl Game {
+    pub fn new(map: Map, weapons: Weapons, config: &MatchConfig) -> Self {
+        let mode = mode::from_config(&config.mode, &map);
+        info!("Playing {:?}", mode.kind());
+        let time_limit_ticks = time_limit_ticks(&config.mode);
+        let phase = if config.lobby.min_ready == 0 {
+            Phase::Playing
+        } else {
+            Phase::Lobby
+        };
+        let mut game = Self {
+            map,
+            rotation: config.rotation(),
+            rotation_index: 0,
+            vote_choices: config.vote_choices,
+            vote: None,
+            weapons,
+            mode,
+            mode_config: config.mode.clone(),
+            mode_changed: false,
+            phase,
+            lobby_config: config.lobby.clone(),
+            time_limit_ticks,
+            round_end_tick: time_limit_ticks.filter(|_| phase == Phase::Playing),
+            intermission_end_tick: None,
+            players: HashMap::new(),
+            next_id: 0,
+            spectators: HashMap::new(),
+            returning: HashMap::new(),
+            bots: HashMap::new(),
+            bot_count: config.bots,
+            bot_skill: config.bot_skill,
+            projectiles: HashMap::new(),
+            next_projectile_id: 0,
+            tick: 0,
+            max_rewind_ticks: rewind_ticks(config),
+            ping: (0, Instant::now()),
+            rng: rand::thread_rng(),
+            outgoing: vec![],
+            results: vec![],
+            events: vec![],
+        };
+        game.update_bot_count();
+        game
+    }
+
+    /// Packets generated since the last call, to be sent to clients
+    pub fn drain_outgoing(&mut self) -> Vec<(Audience, Packet)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Players' results since the last call, for their career stats
+    pub fn drain_results(&mut self) -> Vec<RoundResult> {
+        std::mem::take(&mut self.results)
+    }
+
+    /// Events since the last call, for the event log
+    pub fn drain_events(&mut self) -> Vec<(u64, Event)> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn log(&mut self, event: Event) {
+        self.events.push((self.tick, event));
+    }
+
+    fn weapon_name(&self, weapon: WeaponId) -> String {
+        self.weapons
+            .get(weapon)
+            .map(|definition| definition.name.clone())
+            .unwrap_or_default()
+    }
+
+    fn send(&mut self, audience: Audience, packet: Packet) {
+        self.outgoing.push((audience, packet));
+    }
+
+    /// Pick up settings changed since the game started. Mode changes wait for the
+    /// next round, and longer rewinds for players' histories to fill up.
+    pub fn configure(&mut self, config: &MatchConfig) {
+        let max_rewind_ticks = rewind_ticks(config);
+        if max_rewind_ticks != self.max_rewind_ticks {
+            self.max_rewind_ticks = max_rewind_ticks;
+            let length = max_rewind_ticks as usize + 1;
+            for player in self.players.values_mut().chain(self.returning.values_mut()) {
+                player.history.resize(length);
+            }
+        }
+        self.time_limit_ticks = time_limit_ticks(&config.mode);
+        if config.mode != self.mode_config {
+            self.mode_config = config.mode.clone();
+            self.mode_changed = true;
+        }
+        self.lobby_config = config.lobby.clone();
+        self.rotation = config.rotation();
+        if self.rotation_index >= self.rotation.len() {
+            self.rotation_index = 0;
+        }
+        self.vote_choices = config.vote_choices;
+        self.bot_count = config.bots;
+        self.bot_skill = config.bot_skill;
+        for bot in self.bots.values_mut() {
+            bot.set_skill(config.bot_skill);
+        }
+        self.update_bot_count();
+    }
+
+    /// Everything needed to carry on from here after a restart
+    pub fn snapshot(&self) -> Result<GameSnapshot> {
+        let mut players: Vec<_> = self
+            .players
+            .values()
+            .chain(self.returning.values())
+            .map(|player| SavedPlayer {
+                id: player.id,
+                bot: self.bots.contains_key(&player.id),
+                username: player.username,
+                team: player.team,
+                state: player.state,
+                health: player.health,
+                inventory: player.inventory.clone(),
+                respawn_tick: player.respawn_tick,
+                kills: player.kills,
+                deaths: player.deaths,
+                assists: player.assists,
+                shots: player.shots,
+                hits: player.hits,
+            })
+            .collect();
+        players.sort_by_key(|player| player.id);
+        Ok(GameSnapshot {
+            map: self.map.snapshot(),
+            rotation_index: self.rotation_index,
+            mode_config: self.mode_config.clone(),
+            mode: self.mode.save()?,
+            phase: self.phase,
+            round_end_tick: self.round_end_tick,
+            intermission_end_tick: self.intermission_end_tick,
+            vote: self.vote.clone(),
+            players,
+            next_id: self.next_id,
+            projectiles: self
+                .projectiles
+                .values()
+                .map(SavedProjectile::from)
+                .collect(),
+            next_projectile_id: self.next_projectile_id,
+            tick: self.tick,
+        })
+    }
+
+    /// Carry on from a snapshot, before anyone has connected. Bots carry on at once,
+    /// and everyone else when they reconnect with the same username. A mode changed
+    /// in the config since takes over at the next round.
+    pub fn restore(&mut self, snapshot: GameSnapshot) -> Result<()> {
+        let map = Map::restore(snapshot.map)?;
+        let mut mode = mode::from_config(&snapshot.mode_config, &map);
+        mode.restore(&snapshot.mode).context("Restoring the mode")?;
+        self.map = map;
+        self.mode = mode;
+        self.mode_changed = snapshot.mode_config != self.mode_config;
+        if snapshot.rotation_index < self.rotation.len() {
+            self.rotation_index = snapshot.rotation_index;
+        }
+        self.phase = snapshot.phase;
+        self.round_end_tick = snapshot.round_end_tick;
+        self.intermission_end_tick = snapshot.intermission_end_tick;
+        let rotation_len = self.rotation.len();
+        self.vote = snapshot
+            .vote
+            .filter(|vote| vote.choices.iter().all(|index| *index < rotation_len));
+        self.next_id = snapshot.next_id;
+        // The weapons may have changed since, so anything fired from one that's gone
+        // is dropped, and anyone carrying one starts with a fresh inventory
+        let weapons = &self.weapons;
+        self.projectiles = snapshot
+            .projectiles
+            .into_iter()
+            .map(Projectile::from)
+            .filter(|projectile| {
+                let firing = weapons.get(projectile.weapon).map(|weapon| &weapon.firing);
+                matches!(firing, Some(Firing::Projectile(_)))
+            })
+            .map(|projectile| (projectile.id, projectile))
+            .collect();
+        self.next_projectile_id = snapshot.next_projectile_id;
+        self.tick = snapshot.tick;
+
+        self.players.clear();
+        self.bots.clear();
+        self.returning.clear();
+        for saved in snapshot.players {
+            let inventory = if saved.inventory.fits(&self.weapons) {
+                saved.inventory
+            } else {
+                Inventory::new(&self.weapons)
+            };
+            let player = Player {
+                id: saved.id,
+                connection: None,
+                username: saved.username,
+                team: saved.team,
+                state: saved.state,
+                health: saved.health,
+                inventory,
+                ready: saved.bot,
+                respawn_tick: saved.respawn_tick,
+                history: HitboxHistory::new(self.max_rewind_ticks as usize + 1),
+                kills: saved.kills,
+                deaths: saved.deaths,
+                assists: saved.assists,
+                shots: saved.shots,
+                hits: saved.hits,
+                ping: 0,
+                attackers: HashMap::new(),
+                relevant: HashMap::new(),
+            };
+            if saved.bot {
+                self.bots.insert(player.id, Bot::new(self.bot_skill));
+                self.players.insert(player.id, player);
+            } else {
+                self.returning.insert(player.username, player);
+            }
+        }
+        self.update_bot_count();
+        info!(
+            "Restored {:?} on {} at tick {}, with {} players to come back",
+            self.mode.kind(),
+            self.rotation[self.rotation_index].0,
+            self.tick,
+            self.returning.len()
+        );
+        Ok(())
+    }
+
+    /// Add or remove bots until there are as many as configured
+    fn update_bot_count(&mut self) {
+        while self.bots.len() < self.bot_count as usize {
+            let name = format!("Bot{}", self.bots.len() + 1);
+            let id = self.add_player(None, network::encode_username(&name));
+            self.bots.insert(id, Bot::new(self.bot_skill));
+        }
+        while self.bots.len() > self.bot_count as usize {
+            // The newest first, so the names stay numbered in order
+            let id = *self.bots.keys().max().unwrap();
+            self.bots.remove(&id);
+            self.remove_player(id);
+        }
+    }
+
+    /// Switch to a new map, starting a new round on it straight away
+    pub fn change_map(&mut self, map: Map) {
+        self.map = map;
+        self.send(Audience::Everyone, self.map.info());
+        // Capture the flag bases depend on the map
+        if self.mode.kind() == ModeKind::CaptureTheFlag {
+            self.mode_changed = true;
+        }
+        match self.phase {
+            Phase::Playing => self.start_round(),
+            Phase::Warmup { .. } => self.reset_world(),
+            // Everyone is put on the new map when the warmup starts
+            Phase::Lobby | Phase::Countdown { .. } => {}
+        }
+    }
+
+    /// A player by id, or failing that by username
+    pub fn find_player(&self, name: &str) -> Option<&Player> {
+        let by_id = name.parse().ok().and_then(|id: u32| self.players.get(&id));
+        by_id.or_else(|| {
+            self.players
+                .values()
+                .find(|player| network::decode_username(&player.username) == name)
+        })
+    }
+
+    /// What's being played, for players choosing a match
+    pub fn summary(&self, id: u32, name: &str) -> MatchSummary {
+        MatchSummary {
+            id,
+            name: name.to_string(),
+            mode: self.mode.kind(),
+            players: self.players.len() as u32,
+        }
+    }
+
+    /// How many are playing and watching, for the metrics
+    pub fn gauges(&self, name: &str) -> MatchGauges {
+        MatchGauges {
+            name: name.to_string(),
+            players: self.players.len() - self.bots.len(),
+            bots: self.bots.len(),
+            spectators: self.spectators.len(),
+        }
+    }
+
+    /// A summary of the game, for admins
+    pub fn status(&self) -> String {
+        let mut status = format!(
+            "Playing {:?} on {}",
+            self.mode.kind(),
+            self.rotation[self.rotation_index].0
+        );
+        match (self.phase, self.intermission_end_tick, self.time_left()) {
+            (Phase::Lobby, _, _) => {
+                status += &format!(
+                    ", in the lobby with {} of {} ready",
+                    self.players.values().filter(|player| player.ready).count(),
+                    self.lobby_config.min_ready
+                )
+            }
+            (Phase::Countdown { end_tick }, _, _) => {
+                status += &format!(", starting in {:.0} seconds", self.seconds_until(end_tick))
+            }
+            (Phase::Warmup { end_tick }, _, _) => {
+                status += &format!(
+                    ", warming up for {:.0} more seconds",
+                    self.seconds_until(end_tick)
+                )
+            }
+            (Phase::Playing, Some(_), _) => status += ", between rounds",
+            (Phase::Playing, None, Some(time_left)) => {
+                status += &format!(", {:.0} seconds left", time_left)
+            }
+            (Phase::Playing, None, None) => {}
+        }
+        status += &format!("\n{} players", self.players.len());
+        let mut players: Vec<_> = self.players.values().collect();
+        players.sort_by_key(|player| player.id);
+        for player in players {
+            status += &format!(
+                "\n{:>4} {:<20} {:>4} kills {:>4} deaths {:>5} ms",
+                player.id,
+                network::decode_username(&player.username),
+                player.kills,
+                player.deaths,
+                player.ping,
+            );
+            if let Some(team) = player.team {
+                status += &format!(" {:?}", team);
+            }
+            if player.connection.is_none() {
+                status += " (bot)";
+            } else if self.in_lobby() && player.ready {
+                status += " (ready)";
+            }
+        }
+        if !self.spectators.is_empty() {
+            let mut names: Vec<_> = self
+                .spectators
+                .values()
+                .map(|spectator| network::decode_username(&spectator.username))
+                .collect();
+            names.sort();
+            status += &format!("\nSpectating: {}", names.join(", "));
+        }
+        if !self.returning.is_empty() {
+            let mut names: Vec<_> = self
+                .returning
+                .values()
+                .map(|player| network::decode_username(&player.username))
+                .collect();
+            names.sort();
+            status += &format!("\nWaiting for: {}", names.join(", "));
+        }
+        status
+    }
+
+    /// Remove the player on a connection that has gone
+    pub fn disconnect(&mut self, uid: u32) {
+        if let Some(id) = self.player_id_for_connection(uid) {
+            self.remove_player(id);
+        }
+        self.spectators.remove(&uid);
+    }
+
+    fn remove_player(&mut self, id: u32) {
+        let player = self.players.remove(&id).unwrap();
+        for other in self.players.values_mut() {
+            other.relevant.remove(&id);
+        }
+        for spectator in self.spectators.values_mut() {
+            spectator.known.remove(&id);
+        }
+        info!(
+            "Player {} ({}) left",
+            id,
+            network::decode_username(&player.username)
+        );
+        self.send(Audience::Everyone, Packet::PlayerLeft { id });
+        self.log(Event::Leave {
+            player: id,
+            username: network::decode_username(&player.username),
+        });
+        // What they did before leaving still counts, unless it was only warming up
+        if player.connection.is_some()
+            && self.phase == Phase::Playing
+            && self.intermission_end_tick.is_none()
+        {
+            self.results.push(round_result(&player, false, false));
+        }
+
+        if let Some(vote) = self.vote.as_mut() {
+            if vote.votes.remove(&id).is_some() {
+                self.send(Audience::Everyone, self.vote_tally());
+            }
+        }
+
+        let anyone_left = self
+            .players
+            .values()
+            .any(|player| player.connection.is_some());
+        if !anyone_left && self.phase != Phase::Lobby && self.lobby_config.min_ready > 0 {
+            info!("Everyone has left, back to the lobby");
+            self.set_phase(Phase::Lobby);
+            self.intermission_end_tick = None;
+            self.round_end_tick = None;
+            self.vote = None;
+            self.projectiles.clear();
+        } else if self.in_lobby() {
+            self.send(Audience::Everyone, self.lobby());
+        }
+    }
+
+    fn player_id_for_connection(&self, uid: u32) -> Option<u32> {
+        self.players
+            .values()
+            .find(|player| player.connection == Some(uid))
+            .map(|player| player.id)
+    }
+
+    /// Act on a packet from a player. Errors mean the client is broken or up to no
+    /// good, and should be dropped.
+    pub fn handle_packet(&mut self, uid: u32, packet: &Packet) -> Result<()> {
+        // Nothing moves until the lobby's done
+        if self.in_lobby() && matches!(packet, Packet::UpdateState { .. } | Packet::Fire { .. }) {
+            return Ok(());
+        }
+        match packet {
+            Packet::UpdateState { state, .. } => {
+                let id = match self.player_id_for_connection(uid) {
+                    Some(id) => id,
+                    None => return Ok(()),
+                };
+                // Anywhere else is a bug or a cheat, and would make for long raycasts
+                if !self.map.contains(state.position) {
+                    debug!(
+                        "Ignoring player {} moving outside the map, to {:?}",
+                        id, state.position
+                    );
+                    return Ok(());
+                }
+                let player = self.players.get_mut(&id).unwrap();
+                if !player.is_alive() {
+                    return Ok(());
+                }
+                player.state = *state;
+                self.send(
+                    self.observers(id),
+                    Packet::UpdateState {
+                        id,
+                        tick: self.tick,
+                        state: *state,
+                    },
+                );
+            }
+            Packet::RequestMap => {
+                let chunks: Vec<_> = self.map.chunks().collect();
+                for chunk in chunks {
+                    self.send(Audience::Only(uid), chunk);
+                }
+            }
+            Packet::Fire {
+                direction,
+                view_tick,
+            } => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.fire(id, (*direction).into(), *view_tick);
+                }
+            }
+            Packet::SwitchWeapon { slot } => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.switch_weapon(id, *slot as usize);
+                }
+            }
+            Packet::Reload => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.reload(id);
+                }
+            }
+            Packet::Ready { ready } => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.set_ready(id, *ready);
+                }
+            }
+            Packet::Vote { choice } => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.vote(id, *choice as usize);
+                }
+            }
+            Packet::ChooseTeam { team } => {
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    self.choose_team(id, *team);
+                }
+            }
+            Packet::Pong { id } => {
+                let (ping_id, sent) = self.ping;
+                if *id != ping_id {
+                    return Ok(());
+                }
+                if let Some(id) = self.player_id_for_connection(uid) {
+                    let player = self.players.get_mut(&id).unwrap();
+                    player.ping = sent.elapsed().as_millis() as u32;
+                }
+            }
+            Packet::CreateCharacter { .. }
+            | Packet::DestroyCharacter { .. }
+            | Packet::MapInfo { .. }
+            | Packet::MapSeed { .. }
+            | Packet::MapChunk { .. }
+            | Packet::PlayerHit { .. }
+            | Packet::HealthChanged { .. }
+            | Packet::PlayerKilled { .. }
+            | Packet::Respawn { .. }
+            | Packet::CreateProjectile { .. }
+            | Packet::UpdateProjectile { .. }
+            | Packet::Explosion { .. }
+            | Packet::VoxelsChanged { .. }
+            | Packet::Inventory { .. }
+            | Packet::GameMode { .. }
+            | Packet::RoundStarted { .. }
+            | Packet::RoundEnded { .. }
+            | Packet::PlayerScore { .. }
+            | Packet::TeamScore { .. }
+            | Packet::FlagChanged { .. }
+            | Packet::Ping { .. }
+            | Packet::Scoreboard { .. }
+            | Packet::PlayerLeft { .. }
+            | Packet::Disconnected { .. }
+            | Packet::ServerMessage { .. }
+            | Packet::AdminResponse { .. }
+            | Packet::MatchList { .. }
+            | Packet::PhaseChanged { .. }
+            | Packet::Lobby { .. }
+            | Packet::MapVote { .. }
+            | Packet::VoteTally { .. }
+            | Packet::Stats { .. }
+            | Packet::Leaderboard { .. } => {
+                bail!("Sent a {} packet, which only servers send", packet.name());
+            }
+            Packet::Login { .. }
+            | Packet::JoinMatch { .. }
+            | Packet::GetStats { .. }
+            | Packet::GetLeaderboard
+            | Packet::AdminLogin { .. }
+            | Packet::AdminCommand { .. } => {
+                bail!("The server should have handled a {} packet", packet.name());
+            }
+        }
+        Ok(())
+    }
+
+    /// Put a logged in connection into the game, back in their old place if they
+    /// were playing before the server restarted
+    pub fn join(&mut self, uid: u32, username: [u8; 20]) {
+        self.send_game_state(uid);
+        let id = match self.returning.remove(&username) {
+            Some(mut player) => {
+                player.connection = Some(uid);
+                self.insert_player(player)
+            }
+            None => self.add_player(Some(uid), username),
+        };
+        self.send(Audience::Only(uid), self.players[&id].inventory.packet());
+        self.send(Audience::Only(uid), self.scoreboard());
+    }
+
+    /// Let a logged in connection watch the game without taking part
+    pub fn spectate(&mut self, uid: u32, username: [u8; 20]) {
+        info!("{} is spectating", network::decode_username(&username));
+        self.log(Event::Spectate {
+            username: network::decode_username(&username),
+        });
+        self.send_game_state(uid);
+        self.spectators.insert(
+            uid,
+            Spectator {
+                username,
+                known: HashSet::new(),
+            },
+        );
+        if self.in_lobby() {
+            self.send(Audience::Only(uid), self.lobby());
+        }
+        self.send(Audience::Only(uid), self.scoreboard());
+    }
+
+    /// Everything a client needs to know on joining, besides the players
+    fn send_game_state(&mut self, uid: u32) {
+        self.send(Audience::Only(uid), self.map.info());
+        let changes: Vec<_> = self.map.changes().collect();
+        for packet in changes {
+            self.send(Audience::Only(uid), packet);
+        }
+
+        // Send them the current game state
+        self.send(
+            Audience::Only(uid),
+            Packet::GameMode {
+                kind: self.mode.kind(),
+                score_limit: self.mode.score_limit(),
+                time_limit: self
+                    .time_limit_ticks
+                    .map(|ticks| ticks as f32 / TICKS_PER_SECOND as f32),
+            },
+        );
+        self.send(
+            Audience::Only(uid),
+            Packet::PhaseChanged {
+                phase: self.match_phase(),
+            },
+        );
+        if self.phase == Phase::Playing && self.intermission_end_tick.is_none() {
+            self.send(
+                Audience::Only(uid),
+                Packet::RoundStarted {
+                    time_left: self.time_left(),
+                },
+            );
+        }
+        if self.vote.is_some() {
+            self.send(Audience::Only(uid), self.map_vote());
+            self.send(Audience::Only(uid), self.vote_tally());
+        }
+        // Players are sent as they come into view
+        for packet in self.mode.state() {
+            self.send(Audience::Only(uid), packet);
+        }
+    }
+
+    /// Create a player, for a connection or a bot, and tell everyone about them
+    fn add_player(&mut self, connection: Option<u32>, username: [u8; 20]) -> u32 {
+        let team = self.mode.choose_team(&self.players);
+        let player = Player {
+            id: self.next_id,
+            connection,
+            username,
+            team,
+            state: PlayerState {
+                position: self.choose_spawn(team),
+                ..PlayerState::default()
+            },
+            health: MAX_HEALTH,
+            inventory: Inventory::new(&self.weapons),
+            ready: connection.is_none(),
+            respawn_tick: None,
+            history: HitboxHistory::new(self.max_rewind_ticks as usize + 1),
+            kills: 0,
+            deaths: 0,
+            assists: 0,
+            shots: 0,
+            hits: 0,
+            ping: 0,
+            attackers: HashMap::new(),
+            relevant: HashMap::new(),
+        };
+        self.next_id += 1;
+        self.insert_player(player)
+    }
+
+    /// Tell a player's client about them, and add them to the game
+    fn insert_player(&mut self, player: Player) -> u32 {
+        info!(
+            "Player {} ({}) joined",
+            player.id,
+            network::decode_username(&player.username)
+        );
+        // Everyone else finds out about them when they come into view
+        if let Some(uid) = player.connection {
+            self.send(
+                Audience::Only(uid),
+                Packet::CreateCharacter {
+                    id: player.id,
+                    username: player.username,
+                    position: player.state.position,
+                    is_owned: true,
+                    team: player.team,
+                },
+            );
+        }
+        let id = player.id;
+        self.log(Event::Join {
+            player: id,
+            username: network::decode_username(&player.username),
+            bot: player.connection.is_none(),
+        });
+        if player.is_alive() {
+            self.log(Event::Spawn {
+                player: id,
+                position: player.state.position,
+            });
+        }
+        self.players.insert(id, player);
+        if self.in_lobby() {
+            self.send(Audience::Everyone, self.lobby());
+        }
+        id
+    }
+
+    /// Somewhere for a player on the given team to spawn, away from their enemies
+    fn choose_spawn(&mut self, team: Option<Team>) -> [f32; 3] {
+        let others: Vec<_> = self
+            .players
+            .values()
+            .filter(|player| player.is_alive() && (team.is_none() || player.team != team))
+            .map(|player| player.state.position)
+            .collect();
+        let mode = &self.mode;
+        self.map.choose_spawn(
+            &others,
+            |spawn| mode.is_spawn_for(team, spawn),
+            &mut self.rng,
+        )
+    }
+
+    /// The seconds left in the current round, if it has a time limit
+    fn time_left(&self) -> Option<f32> {
+        self.round_end_tick
+            .map(|end| end.saturating_sub(self.tick) as f32 / TICKS_PER_SECOND as f32)
+    }
+
+    fn scoreboard(&self) -> Packet {
+        let mut entries: Vec<_> = self
+            .players
+            .values()
+            .map(|player| ScoreboardEntry {
+                id: player.id,
+                username: player.username,
+                team: player.team,
+                score: self.mode.player_score(player.id),
+                kills: player.kills,
+                deaths: player.deaths,
+                assists: player.assists,
+                ping: player.ping,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.id);
+        Packet::Scoreboard { entries }
+    }
+
+    fn send_inventory(&mut self, id: u32) {
+        let player = &self.players[&id];
+        if let Some(uid) = player.connection {
+            let packet = player.inventory.packet();
+            self.send(Audience::Only(uid), packet);
+        }
+    }
+
+    fn switch_weapon(&mut self, id: u32, slot: usize) {
+        let weapons = &self.weapons;
+        let player = self.players.get_mut(&id).unwrap();
+        let definition = match player
+            .inventory
+            .slot_weapon(slot)
+            .and_then(|weapon| weapons.get(weapon))
+        {
+            Some(definition) => definition,
+            None => return,
+        };
+        if player.inventory.switch(slot, definition, self.tick) {
+            self.send_inventory(id);
+        }
+    }
+
+    fn reload(&mut self, id: u32) {
+        let player = self.players.get_mut(&id).unwrap();
+        let definition = self.weapons.get(player.inventory.current_weapon()).unwrap();
+        if player.is_alive() && player.inventory.start_reload(definition, self.tick) {
+            self.send_inventory(id);
+        }
+    }
+
+    fn fire(&mut self, shooter_id: u32, direction: Vector3<f32>, view_tick: u64) {
+        let direction = direction.normalize();
+        if !direction.x.is_finite() || !direction.y.is_finite() || !direction.z.is_finite() {
+            return;
+        }
+        let shooter = self.players.get_mut(&shooter_id).unwrap();
+        let weapon = shooter.inventory.current_weapon();
+        let definition = self.weapons.get(weapon).unwrap();
+        if !shooter.is_alive() || !shooter.inventory.try_fire(definition, self.tick) {
+            return;
+        }
+        // Reload automatically once the magazine runs dry
+        if shooter.inventory.needs_reload(definition) {
+            shooter.inventory.start_reload(definition, self.tick);
+        }
+        let origin =
+            Point3::from(shooter.state.position) + Vector3::unit_y() * shooter.state.eye_height();
+        let direction = weapon::spread(direction, definition.spread, &mut self.rng);
+        let damage = definition.damage;
+        let firing = definition.firing.clone();
+        if !matches!(firing, Firing::Dig { .. }) {
+            shooter.shots += 1;
+        }
+        self.send_inventory(shooter_id);
+        self.log(Event::Shot {
+            player: shooter_id,
+            weapon: self.weapon_name(weapon),
+            origin: origin.into(),
+            direction: direction.into(),
+            view_tick,
+        });
+
+        match firing {
+            Firing::Hitscan { range } => {
+                let shot = Shot {
+                    origin,
+                    direction,
+                    view_tick,
+                    weapon,
+                    damage,
+                    range,
+                };
+                self.fire_hitscan(shooter_id, shot);
+            }
+            Firing::Projectile(ballistics) => {
+                let projectile = Projectile {
+                    id: self.next_projectile_id,
+                    owner: shooter_id,
+                    weapon,
+                    position: origin,
+                    velocity: direction * ballistics.speed,
+                    explode_tick: self.tick + ballistics.fuse_ticks(),
+                    known: HashSet::new(),
+                };
+                self.next_projectile_id += 1;
+                let id = projectile.id;
+                self.projectiles.insert(id, projectile);
+                self.update_projectile_viewers(id, false);
+            }
+            Firing::Dig { range, radius } => {
+                if let Some(hit) = self.map.raycast(origin, direction, range) {
+                    // Center the hole a little way into the wall, so digging straight
+                    // ahead makes a tunnel rather than a dent
+                    let center = origin + direction * (hit.distance + VOXEL_SIZE);
+                    self.destroy(center, radius);
+                }
+            }
+        }
+    }
+
+    /// Shoot at where everyone was at view_tick, as far as the rewind limit allows
+    fn fire_hitscan(&mut self, shooter_id: u32, shot: Shot) {
+        let Shot {
+            origin, direction, ..
+        } = shot;
+        // Nothing behind a wall can be hit
+        let range = self
+            .map
+            .raycast(origin, direction, shot.range)
+            .map(|hit| hit.distance)
+            .unwrap_or(shot.range);
+        let rewind_to = shot
+            .view_tick
+            .clamp(self.tick.saturating_sub(self.max_rewind_ticks), self.tick);
+        let target = self
+            .players
+            .values()
+            .filter(|player| player.id != shooter_id && player.is_alive())
+            .filter_map(|player| {
+                let (min, max) = player
+                    .history
+                    .at(rewind_to)
+                    .unwrap_or_else(|| player.state.hitbox());
+                raycast::ray_box(origin, direction, min.into(), max.into(), range)
+                    .map(|distance| (distance, player.id))
+            })
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        if let Some((_, target)) = target {
+            if self.damage(target, shooter_id, shot.weapon, shot.damage) {
+                self.players.get_mut(&shooter_id).unwrap().hits += 1;
+            }
+        }
+    }
+
+    fn explode(&mut self, projectile: Projectile, position: Point3<f32>) {
+        let definition = self.weapons.get(projectile.weapon).unwrap();
+        let splash_damage = definition.damage;
+        let ballistics = match &definition.firing {
+            Firing::Projectile(ballistics) => ballistics.clone(),
+            firing => unreachable!("Projectile fired by {:?}", firing),
+        };
+        // Whoever was following it, and whoever can see where it went off
+        let mut audience = self.viewers(position, None);
+        audience.extend(projectile.known.iter().copied());
+        audience.sort_unstable();
+        audience.dedup();
+        self.send(
+            Audience::Group(audience),
+            Packet::Explosion {
+                id: projectile.id,
+                position: position.into(),
+                radius: ballistics.splash_radius,
+            },
+        );
+
+        let damaged: Vec<_> = self
+            .players
+            .values()
+            .filter(|player| player.is_alive())
+            .filter_map(|player| {
+                // Measure to the nearest part of the player, but only if there's a
+                // clear path to their middle
+                let (min, max) = player.state.hitbox();
+                let nearest = Point3::new(
+                    position.x.clamp(min[0], max[0]),
+                    position.y.clamp(min[1], max[1]),
+                    position.z.clamp(min[2], max[2]),
+                );
+                let distance = (nearest - position).magnitude();
+                let center = Point3::from(min).midpoint(Point3::from(max));
+                if distance >= ballistics.splash_radius || !self.map.line_of_sight(position, center)
+                {
+                    return None;
+                }
+                let falloff = 1.0 - distance / ballistics.splash_radius;
+                let damage = (splash_damage as f32 * falloff).round() as u32;
+                if damage > 0 {
+                    Some((player.id, damage))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // Whoever fired it may have left since
+        if self.players.contains_key(&projectile.owner) {
+            let mut hit = false;
+            for (id, damage) in damaged {
+                if self.damage(id, projectile.owner, projectile.weapon, damage) {
+                    hit |= id != projectile.owner;
+                }
+            }
+            if hit {
+                self.players.get_mut(&projectile.owner).unwrap().hits += 1;
+            }
+        }
+        self.destroy(position, ballistics.destroy_radius);
+    }
+
+    fn destroy(&mut self, center: Point3<f32>, radius: f32) {
+        let removed = self.map.remove_sphere(center, radius);
+        for removed in removed.chunks(MAX_VOXELS_PER_PACKET) {
+            self.send(
+                Audience::Everyone,
+                Packet::VoxelsChanged {
+                    removed: removed.to_vec(),
+                },
+            );
+        }
+    }
+
+    /// Hurt a player, returning whether it was allowed to
+    fn damage(&mut self, target: u32, attacker: u32, weapon: WeaponId, amount: u32) -> bool {
+        // No friendly fire, and nobody gets hurt between rounds
+        let attacker_team = self.players[&attacker].team;
+        if (attacker != target
+            && attacker_team.is_some()
+            && attacker_team == self.players[&target].team)
+            || self.intermission_end_tick.is_some()
+        {
+            return false;
+        }
+        let respawn_ticks = self.mode.respawn_ticks();
+        let tick = self.tick;
+        let player = self.players.get_mut(&target).unwrap();
+        player.health = player.health.saturating_sub(amount);
+        let health = player.health;
+        if attacker != target {
+            player.attackers.insert(attacker, tick);
+        }
+        let mut assists = vec![];
+        if health == 0 {
+            player.respawn_tick = Some(tick + respawn_ticks);
+            player.deaths += 1;
+            assists = player
+                .attackers
+                .drain()
+                .filter(|(id, last)| *id != attacker && last + ASSIST_TICKS >= tick)
+                .map(|(id, _)| id)
+                .collect();
+            assists.sort_unstable();
+        }
+
+        self.send(
+            Audience::Everyone,
+            Packet::PlayerHit {
+                shooter: attacker,
+                target,
+                damage: amount,
+            },
+        );
+        self.send(
+            Audience::Everyone,
+            Packet::HealthChanged { id: target, health },
+        );
+        self.log(Event::Hit {
+            shooter: attacker,
+            target,
+            weapon: self.weapon_name(weapon),
+            damage: amount,
+            health,
+        });
+        if health == 0 {
+            info!("Player {} killed player {}", attacker, target);
+            if attacker != target {
+                self.players.get_mut(&attacker).unwrap().kills += 1;
+            }
+            for id in assists.iter() {
+                if let Some(player) = self.players.get_mut(id) {
+                    player.assists += 1;
+                }
+            }
+            self.log(Event::Kill {
+                killer: attacker,
+                victim: target,
+                weapon: self.weapon_name(weapon),
+                assists: assists.clone(),
+            });
+            self.send(
+                Audience::Everyone,
+                Packet::PlayerKilled {
+                    killer: attacker,
+                    victim: target,
+                    weapon,
+                    assists,
+                },
+            );
+            let packets = self
+                .mode
+                .player_killed(&self.players[&attacker], &self.players[&target]);
+            for packet in packets {
+                self.send(Audience::Everyone, packet);
+            }
+        }
+        true
+    }
+
+    fn respawn(&mut self, id: u32) {
+        let position = self.choose_spawn(self.players[&id].team);
+        let player = self.players.get_mut(&id).unwrap();
+        player.respawn_tick = None;
+        player.health = MAX_HEALTH;
+        player.history.clear();
+        player.attackers.clear();
+        player.inventory = Inventory::new(&self.weapons);
+        player.state = PlayerState {
+            position,
+            ..PlayerState::default()
+        };
+        let connection = player.connection;
+        let team = player.team;
+        // Anyone who could see them before has to see them come back into view, so
+        // as not to give away where they've gone
+        let mut observers = vec![];
+        for other in self.players.values_mut() {
+            if other.relevant.remove(&id).is_some() {
+                observers.extend(other.connection);
+            }
+        }
+        for (uid, spectator) in self.spectators.iter_mut() {
+            if spectator.known.remove(&id) {
+                observers.push(*uid);
+            }
+        }
+        self.send(Audience::Group(observers), Packet::DestroyCharacter { id });
+        if let Some(uid) = connection {
+            self.send(Audience::Only(uid), Packet::Respawn { id, position, team });
+        }
+        self.log(Event::Spawn {
+            player: id,
+            position,
+        });
+        self.send(
+            Audience::Everyone,
+            Packet::HealthChanged {
+                id,
+                health: MAX_HEALTH,
+            },
+        );
+        self.send_inventory(id);
+    }
+
+    /// Let each bot move, aim and shoot
+    fn update_bots(&mut self) {
+        let mut ids: Vec<_> = self.bots.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let bot = self.bots.get_mut(&id).unwrap();
+            let player = &self.players[&id];
+            if !player.is_alive() {
+                bot.reset();
+                continue;
+            }
+            let action = bot.think(player, &self.players, &self.map, self.tick, &mut self.rng);
+            self.players.get_mut(&id).unwrap().state = action.state;
+            if self.tick.is_multiple_of(BOT_UPDATE_TICKS) {
+                self.send(
+                    self.observers(id),
+                    Packet::UpdateState {
+                        id,
+                        tick: self.tick,
+                        state: action.state,
+                    },
+                );
+            }
+            if let Some(direction) = action.fire {
+                self.arm_bot(id);
+                self.fire(id, direction, self.tick);
+            }
+        }
+    }
+
+    /// Switch a bot to the first weapon with ammo, unless it's holding one. Digging
+    /// doesn't count.
+    fn arm_bot(&mut self, id: u32) {
+        let weapons = &self.weapons;
+        let inventory = &self.players[&id].inventory;
+        let usable = |slot: usize| {
+            let definition = inventory
+                .slot_weapon(slot)
+                .and_then(|weapon| weapons.get(weapon));
+            matches!(definition, Some(definition)
+                if !matches!(definition.firing, Firing::Dig { .. })
+                    && inventory.has_ammo(slot, definition))
+        };
+        if usable(inventory.current_slot()) {
+            return;
+        }
+        if let Some(slot) = (0..weapons.len()).find(|slot| usable(*slot)) {
+            self.switch_weapon(id, slot);
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.in_lobby() {
+            // The world stands still until the warmup
+            self.tick += 1;
+            self.update_lobby();
+        } else {
+            self.update_world();
+        }
+
+        if self.tick.is_multiple_of(PING_TICKS) {
+            let id = self.ping.0.wrapping_add(1);
+            self.ping = (id, Instant::now());
+            self.send(Audience::Everyone, Packet::Ping { id });
+        }
+        if self.tick.is_multiple_of(SCOREBOARD_TICKS) {
+            self.send(Audience::Everyone, self.scoreboard());
+        }
+        if self.tick.is_multiple_of(RELEVANCE_TICKS) && !self.in_lobby() {
+            self.update_relevance();
+        }
+    }
+
+    fn update_world(&mut self) {
+        self.update_bots();
+
+        // Record where everyone was as of the states received this tick
+        for player in self.players.values_mut() {
+            if player.is_alive() {
+                player.history.record(self.tick, player.state.hitbox());
+            }
+        }
+        self.tick += 1;
+
+        let respawning: Vec<_> = self
+            .players
+            .values()
+            .filter(|player| matches!(player.respawn_tick, Some(tick) if tick <= self.tick))
+            .map(|player| player.id)
+            .collect();
+        for id in respawning {
+            self.respawn(id);
+        }
+
+        let weapons = &self.weapons;
+        let tick = self.tick;
+        let reloaded: Vec<_> = self
+            .players
+            .values_mut()
+            .filter_map(|player| {
+                if player.inventory.update(weapons, tick) {
+                    Some(player.id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for id in reloaded {
+            self.send_inventory(id);
+        }
+
+        self.update_projectiles();
+        self.update_round();
+    }
+
+    /// The connections that know about the given player, besides their own
+    fn observers(&self, id: u32) -> Audience {
+        let players = self
+            .players
+            .values()
+            .filter(|player| player.relevant.contains_key(&id))
+            .filter_map(|player| player.connection);
+        let spectators = self
+            .spectators
+            .iter()
+            .filter(|(_, spectator)| spectator.known.contains(&id))
+            .map(|(uid, _)| *uid);
+        Audience::Group(players.chain(spectators).collect())
+    }
+
+    /// Whether one player should know about another: if they're in the same or
+    /// neighbouring rooms, or in sight of each other
+    fn is_relevant(&self, observer: &Player, target: &Player) -> bool {
+        if !target.is_alive() {
+            return false;
+        }
+        let position = Point3::from(target.state.position);
+        [target.state.eye_height(), target.state.height() / 2.0]
+            .iter()
+            .any(|height| self.in_view(observer, position + Vector3::unit_y() * *height))
+    }
+
+    /// Whether a player should know about something at the given point: if it's in
+    /// the same or a neighbouring room, or in sight
+    fn in_view(&self, observer: &Player, point: Point3<f32>) -> bool {
+        let rooms = self.map.rooms();
+        let nearby = match (
+            rooms.room_at(observer.state.position),
+            rooms.room_at(point.into()),
+        ) {
+            (Some(from), Some(to)) => from == to || rooms.neighbours(from).contains(&to),
+            _ => false,
+        };
+        let eye =
+            Point3::from(observer.state.position) + Vector3::unit_y() * observer.state.eye_height();
+        nearby || self.map.line_of_sight(eye, point)
+    }
+
+    /// The connections that should know about something at the given point: the
+    /// players who can see it, the owner if it has one, and every spectator
+    fn viewers(&self, point: Point3<f32>, owner: Option<u32>) -> Vec<u32> {
+        let players = self
+            .players
+            .values()
+            .filter(|player| Some(player.id) == owner || self.in_view(player, point))
+            .filter_map(|player| player.connection);
+        let mut viewers: Vec<_> = players.chain(self.spectators.keys().copied()).collect();
+        viewers.sort_unstable();
+        viewers
+    }
+
+    /// Tell anyone who's just seen a projectile about it, and optionally bring
+    /// everyone who's seen it before up to date
+    fn update_projectile_viewers(&mut self, id: u32, send_update: bool) {
+        let projectile = &self.projectiles[&id];
+        let viewers = self.viewers(projectile.position, Some(projectile.owner));
+        let projectile = self.projectiles.get_mut(&id).unwrap();
+        let (weapon, position, velocity) = (
+            projectile.weapon,
+            projectile.position.into(),
+            projectile.velocity.into(),
+        );
+        let mut known: Vec<_> = projectile.known.iter().copied().collect();
+        known.sort_unstable();
+        let new: Vec<_> = viewers
+            .into_iter()
+            .filter(|uid| projectile.known.insert(*uid))
+            .collect();
+        if !new.is_empty() {
+            self.send(
+                Audience::Group(new),
+                Packet::CreateProjectile {
+                    id,
+                    weapon,
+                    position,
+                    velocity,
+                },
+            );
+        }
+        if send_update && !known.is_empty() {
+            self.send(
+                Audience::Group(known),
+                Packet::UpdateProjectile {
+                    id,
+                    position,
+                    velocity,
+                },
+            );
+        }
+    }
+
+    /// Tell each client about the players that have come into view, and forget the
+    /// ones that have been out of view for a while
+    fn update_relevance(&mut self) {
+        let mut seen = vec![];
+        let mut forgotten = vec![];
+        for observer in self.players.values() {
+            if observer.connection.is_none() {
+                continue;
+            }
+            for target in self.players.values() {
+                if target.id == observer.id {
+                    continue;
+                }
+                if self.is_relevant(observer, target) {
+                    seen.push((observer.id, target.id));
+                } else if matches!(observer.relevant.get(&target.id),
+                    Some(last) if last + RELEVANCE_GRACE_TICKS < self.tick)
+                {
+                    forgotten.push((observer.id, target.id));
+                }
+            }
+        }
+
+        for (observer, id) in seen {
+            let tick = self.tick;
+            let player = self.players.get_mut(&observer).unwrap();
+            if player.relevant.insert(id, tick).is_some() {
+                continue;
+            }
+            let uid = player.connection.unwrap();
+            self.introduce(uid, id);
+        }
+        for (observer, id) in forgotten {
+            let player = self.players.get_mut(&observer).unwrap();
+            player.relevant.remove(&id);
+            let uid = player.connection.unwrap();
+            self.send(Audience::Only(uid), Packet::DestroyCharacter { id });
+        }
+
+        // Spectators see everyone, wherever they are
+        let mut introductions = vec![];
+        let mut departures = vec![];
+        for (uid, spectator) in self.spectators.iter_mut() {
+            for player in self.players.values() {
+                if player.is_alive() {
+                    if spectator.known.insert(player.id) {
+                        introductions.push((*uid, player.id));
+                    }
+                } else if spectator.known.remove(&player.id) {
+                    departures.push((*uid, player.id));
+                }
+            }
+        }
+        for (uid, id) in introductions {
+            self.introduce(uid, id);
+        }
+        for (uid, id) in departures {
+            self.send(Audience::Only(uid), Packet::DestroyCharacter { id });
+        }
+    }
+
+    /// Tell a client about a player that's come into view
+    fn introduce(&mut self, uid: u32, id: u32) {
+        let target = &self.players[&id];
+        let packets = [
+            Packet::CreateCharacter {
+                id,
+                username: target.username,
+                position: target.state.position,
+                is_owned: false,
+                team: target.team,
+            },
+            Packet::UpdateState {
+                id,
+                tick: self.tick,
+                state: target.state,
+            },
+        ];
+        for packet in packets {
+            self.send(Audience::Only(uid), packet);
+        }
+    }
+
+    /// Whether players are still waiting in the lobby, or counting down to the warmup
+    fn in_lobby(&self) -> bool {
+        matches!(self.phase, Phase::Lobby | Phase::Countdown { .. })
+    }
+
+    fn seconds_until(&self, tick: u64) -> f32 {
+        tick.saturating_sub(self.tick) as f32 / TICKS_PER_SECOND as f32
+    }
+
+    /// The phase, as clients are told it
+    fn match_phase(&self) -> MatchPhase {
+        match self.phase {
+            Phase::Lobby => MatchPhase::Lobby,
+            Phase::Countdown { end_tick } => MatchPhase::Countdown {
+                time_left: self.seconds_until(end_tick),
+            },
+            Phase::Warmup { end_tick } => MatchPhase::Warmup {
+                time_left: self.seconds_until(end_tick),
+            },
+            Phase::Playing => MatchPhase::Playing,
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.send(
+            Audience::Everyone,
+            Packet::PhaseChanged {
+                phase: self.match_phase(),
+            },
+        );
+    }
+
+    fn lobby(&self) -> Packet {
+        let mut players: Vec<_> = self
+            .players
+            .values()
+            .map(|player| LobbyEntry {
+                id: player.id,
+                username: player.username,
+                team: player.team,
+                ready: player.ready,
+            })
+            .collect();
+        players.sort_by_key(|entry| entry.id);
+        Packet::Lobby {
+            players,
+            min_ready: self.lobby_config.min_ready,
+        }
+    }
+
+    fn set_ready(&mut self, id: u32, ready: bool) {
+        if !self.in_lobby() {
+            return;
+        }
+        let player = self.players.get_mut(&id).unwrap();
+        if player.ready != ready {
+            player.ready = ready;
+            self.send(Audience::Everyone, self.lobby());
+        }
+    }
+
+    /// Move a player to the other side, if they're still in the lobby and the mode
+    /// has teams
+    fn choose_team(&mut self, id: u32, team: Team) {
+        if !self.in_lobby() {
+            return;
+        }
+        let player = self.players.get_mut(&id).unwrap();
+        if player.team.is_none() || player.team == Some(team) {
+            return;
+        }
+        player.team = Some(team);
+        let packet = Packet::CreateCharacter {
+            id,
+            username: player.username,
+            position: player.state.position,
+            is_owned: true,
+            team: Some(team),
+        };
+        if let Some(uid) = player.connection {
+            self.send(Audience::Only(uid), packet);
+        }
+        self.send(Audience::Everyone, self.lobby());
+    }
+
+    /// Start counting down once enough players are ready, at least one of them a
+    /// real one, and stop if some of them change their minds
+    fn update_lobby(&mut self) {
+        if self.lobby_config.min_ready == 0 {
+            // The lobby has been turned off
+            self.set_phase(Phase::Playing);
+            self.start_round();
+            return;
+        }
+        let ready: Vec<_> = self
+            .players
+            .values()
+            .filter(|player| player.ready)
+            .collect();
+        let enough = ready.len() as u32 >= self.lobby_config.min_ready
+            && ready.iter().any(|player| player.connection.is_some());
+        match self.phase {
+            Phase::Lobby if enough => {
+                let end_tick = self.tick + seconds_to_ticks(self.lobby_config.countdown);
+                info!("Enough players are ready, starting the countdown");
+                self.set_phase(Phase::Countdown { end_tick });
+            }
+            Phase::Countdown { .. } if !enough => {
+                info!("Not enough players are ready any more, back to the lobby");
+                self.set_phase(Phase::Lobby);
+            }
+            Phase::Countdown { end_tick } if self.tick >= end_tick => self.start_warmup(),
+            _ => {}
+        }
+    }
+
+    /// Put everyone into the world to warm up, or straight into the match if there's
+    /// no warmup
+    fn start_warmup(&mut self) {
+        for player in self.players.values_mut() {
+            player.ready = player.connection.is_none();
+        }
+        if self.lobby_config.warmup <= 0.0 {
+            self.set_phase(Phase::Playing);
+            self.start_round();
+            return;
+        }
+        info!("Warming up");
+        let end_tick = self.tick + seconds_to_ticks(self.lobby_config.warmup);
+        self.set_phase(Phase::Warmup { end_tick });
+        self.round_end_tick = None;
+        self.reset_world();
+    }
+
+    fn update_round(&mut self) {
+        if let Phase::Warmup { end_tick } = self.phase {
+            // Anything goes, but nobody wins
+            let packets = self.mode.update(&self.players, self.tick);
+            for packet in packets {
+                self.send(Audience::Everyone, packet);
+            }
+            if self.tick >= end_tick {
+                self.set_phase(Phase::Playing);
+                self.start_round();
+            }
+            return;
+        }
+        if let Some(end) = self.intermission_end_tick {
+            if self.tick >= end {
+                match self.next_map() {
+                    Some(index) if index != self.rotation_index => self.load_map(index),
+                    _ => self.start_round(),
+                }
+            }
+            return;
+        }
+
+        let packets = self.mode.update(&self.players, self.tick);
+        for packet in packets {
+            self.send(Audience::Everyone, packet);
+        }
+        let time_up = matches!(self.round_end_tick, Some(end) if self.tick >= end);
+        if let Some(winner) = self.mode.winner(time_up) {
+            self.end_round(winner);
+        }
+    }
+
+    fn end_round(&mut self, winner: Winner) {
+        info!("Round over, winner: {:?}", winner);
+        self.intermission_end_tick = Some(self.tick + INTERMISSION_TICKS);
+        self.round_end_tick = None;
+        for player in self.players.values() {
+            if player.connection.is_some() {
+                let won = match winner {
+                    Winner::Player(id) => id == player.id,
+                    Winner::Team(team) => player.team == Some(team),
+                    Winner::Draw => false,
+                };
+                self.results.push(round_result(player, true, won));
+            }
+        }
+        self.send(Audience::Everyone, Packet::RoundEnded { winner });
+        self.log(Event::RoundEnd { winner });
+        self.start_vote();
+    }
+
+    /// Let players choose between the next map in the rotation and some others, if
+    /// there's a choice and voting is turned on
+    fn start_vote(&mut self) {
+        let count = self.rotation.len();
+        if count < 2 || self.vote_choices < 2 {
+            return;
+        }
+        let next = (self.rotation_index + 1) % count;
+        let mut others: Vec<_> = (0..count)
+            .filter(|index| *index != next && *index != self.rotation_index)
+            .collect();
+        others.shuffle(&mut self.rng);
+        // The same map again, only if there's nothing else to choose
+        others.push(self.rotation_index);
+        let mut choices = vec![next];
+        choices.extend(others);
+        choices.truncate(self.vote_choices as usize);
+        self.vote = Some(Vote {
+            choices,
+            votes: HashMap::new(),
+        });
+        self.send(Audience::Everyone, self.map_vote());
+    }
+
+    fn map_vote(&self) -> Packet {
+        let vote = self.vote.as_ref().unwrap();
+        Packet::MapVote {
+            choices: vote
+                .choices
+                .iter()
+                .map(|index| self.rotation[*index].0.clone())
+                .collect(),
+            time_left: self.seconds_until(self.intermission_end_tick.unwrap_or(self.tick)),
+        }
+    }
+
+    fn vote_tally(&self) -> Packet {
+        let vote = self.vote.as_ref().unwrap();
+        let mut votes = vec![0; vote.choices.len()];
+        for choice in vote.votes.values() {
+            votes[*choice] += 1;
+        }
+        Packet::VoteTally { votes }
+    }
+
+    fn vote(&mut self, id: u32, choice: usize) {
+        let vote = match self.vote.as_mut() {
+            Some(vote) if choice < vote.choices.len() => vote,
+            _ => return,
+        };
+        if vote.votes.insert(id, choice) != Some(choice) {
+            self.send(Audience::Everyone, self.vote_tally());
+        }
+    }
+
+    /// The map to play next: the vote's winner, or else the next in the rotation.
+    /// Ties go to the one that comes first in the vote.
+    fn next_map(&mut self) -> Option<usize> {
+        if let Some(vote) = self.vote.take() {
+            let mut votes = vec![0; vote.choices.len()];
+            for choice in vote.votes.values() {
+                votes[*choice] += 1;
+            }
+            let most = votes.iter().max().copied().unwrap_or(0);
+            let winner = votes.iter().position(|count| *count == most).unwrap_or(0);
+            return Some(vote.choices[winner]);
+        }
+        if self.rotation.len() > 1 {
+            Some((self.rotation_index + 1) % self.rotation.len())
+        } else {
+            None
+        }
+    }
+
+    /// Move on to a map from the rotation, or stay put if it won't load
+    fn load_map(&mut self, index: usize) {
+        let (name, source) = &self.rotation[index];
+        info!("Moving on to {}", name);
+        match Map::from_source(source, &mut self.rng) {
+            Ok(map) => {
+                self.rotation_index = index;
+                self.change_map(map);
+            }
+            Err(e) => {
+                error!("Couldn't load {}: {:#}", name, e);
+                self.start_round();
+            }
+        }
+    }
+
+    /// Reset the scores and put everyone back at a spawn point
+    fn start_round(&mut self) {
+        info!("Starting a new round");
+        self.intermission_end_tick = None;
+        self.vote = None;
+        // Anyone who hasn't come back since a restart has missed their chance
+        self.returning.clear();
+        self.round_end_tick = self.time_limit_ticks.map(|ticks| self.tick + ticks);
+        self.log(Event::RoundStart {
+            map: self.rotation[self.rotation_index].0.clone(),
+        });
+        self.reset_world();
+        self.send(
+            Audience::Everyone,
+            Packet::RoundStarted {
+                time_left: self.time_left(),
+            },
+        );
+    }
+
+    /// Clear the scores and projectiles, and respawn everyone
+    fn reset_world(&mut self) {
+        if self.mode_changed {
+            self.change_mode();
+        }
+        self.projectiles.clear();
+        let packets = self.mode.reset();
+        for packet in packets {
+            self.send(Audience::Everyone, packet);
+        }
+        // Respawn everyone at once, so they're spread out from each other
+        for player in self.players.values_mut() {
+            player.respawn_tick = Some(self.tick);
+            player.kills = 0;
+            player.deaths = 0;
+            player.assists = 0;
+            player.shots = 0;
+            player.hits = 0;
+        }
+        let ids: Vec<_> = self.players.keys().copied().collect();
+        for id in ids {
+            self.respawn(id);
+        }
+    }
+
+    /// Replace the mode with one made from the current config. Only a different kind
+    /// of mode puts everyone back on teams, so the sides picked in the lobby last.
+    fn change_mode(&mut self) {
+        let kind = self.mode.kind();
+        self.mode = mode::from_config(&self.mode_config, &self.map);
+        self.mode_changed = false;
+        info!("Playing {:?}", self.mode.kind());
+        self.send(
+            Audience::Everyone,
+            Packet::GameMode {
+                kind: self.mode.kind(),
+                score_limit: self.mode.score_limit(),
+                time_limit: self
+                    .time_limit_ticks
+                    .map(|ticks| ticks as f32 / TICKS_PER_SECOND as f32),
+            },
+        );
+        if self.mode.kind() == kind {
+            return;
+        }
+        for player in self.players.values_mut() {
+            player.team = None;
+        }
+        let mut ids: Vec<_> = self.players.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let team = self.mode.choose_team(&self.players);
+            self.players.get_mut(&id).unwrap().team = team;
+        }
+        self.send(Audience::Everyone, self.scoreboard());
+        if self.in_lobby() {
+            self.send(Audience::Everyone, self.lobby());
+        }
+    }
+
+    fn update_projectiles(&mut self) {
+        let targets: Vec<_> = self
+            .players
+            .values()
+            .filter(|player| player.is_alive())
+            .map(|player| (player.id, player.state.hitbox()))
+            .collect();
+        let mut exploded = vec![];
+        let mut flying = vec![];
+        for projectile in self.projectiles.values_mut() {
+            let ballistics = match &self.weapons.get(projectile.weapon).unwrap().firing {
+                Firing::Projectile(ballistics) => ballistics,
+                firing => unreachable!("Projectile fired by {:?}", firing),
+            };
+            match projectile.step(ballistics, &self.map, &targets, self.tick) {
+                Step::Flying { bounced } => {
+                    if bounced || self.tick.is_multiple_of(PROJECTILE_UPDATE_TICKS) {
+                        flying.push(projectile.id);
+                    }
+                }
+                Step::Exploded(position) => {
+                    exploded.push((projectile.id, position));
+                }
+            }
+        }
+        flying.sort_unstable();
+        for id in flying {
+            self.update_projectile_viewers(id, true);
+        }
+        for (id, position) in exploded {
+            let projectile = self.projectiles.remove(&id).unwrap();
+            self.explode(projectile, position);
+        }
+    }
+}
+
+/// A time from the config in whole ticks, treating negative times as none
+fn seconds_to_ticks(seconds: f32) -> u64 {
+    (seconds.max(0.0) * TICKS_PER_SECOND as f32) as u64
+}
+
+/// How far back shots may be checked, in ticks, within MAX_REWIND_TICKS
+fn rewind_ticks(config: &MatchConfig) -> u64 {
+    (config.max_rewind_ms.saturating_mul(TICKS_PER_SECOND) / 1000).min(MAX_REWIND_TICKS)
+}
+
+/// The length of each round in ticks, if there's a limit
+fn time_limit_ticks(config: &ModeConfig) -> Option<u64> {
+    if config.time_limit > 0.0 {
+        Some(seconds_to_ticks(config.time_limit))
+    } else {
+        None
+    }
+}
+
+fn round_result(player: &Player, finished: bool, won: bool) -> RoundResult {
+    RoundResult {
+        username: network::decode_username(&player.username),
+        kills: player.kills,
+        deaths: player.deaths,
+        shots: player.shots,
+        hits: player.hits,
+        finished,
+        won,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapgen::DungeonSpecification;
+
+    const RIFLE: &str = r#"
+        [[weapon]]
+        name = "Rifle"
+        damage = 25
+        rounds_per_minute = 300
+        magazine_size = 30
+        reload_time = 2.0
+        firing = { type = "hitscan", range = 50.0 }
+    "#;
+
+    const ROCKET_LAUNCHER: &str = r#"
+        [[weapon]]
+        name = "Rocket launcher"
+        damage = 90
+        rounds_per_minute = 60
+        firing = { type = "projectile", model = "voxel.obj", speed = 15.0, fuse_time = 5.0, splash_radius = 1.5 }
+    "#;
+
+    fn game(weapons: &str) -> Game {
+        game_of(weapons, ModeKind::Deathmatch)
+    }
+
+    fn map(seed: u64) -> Map {
+        let spec = DungeonSpecification {
+            rooms_wide: 2,
+            rooms_deep: 2,
+            rooms_tall: 1,
+        };
+        Map::generate(&spec, seed).unwrap()
+    }
+
+    fn game_of(weapons: &str, kind: ModeKind) -> Game {
+        let map = map(7);
+        let weapons: Weapons = toml::from_str(weapons).unwrap();
+        let config = MatchConfig {
+            bots: 2,
+            mode: ModeConfig {
+                kind,
+                ..ModeConfig::default()
+            },
+            ..MatchConfig::default()
+        };
+        Game::new(map, weapons, &config)
+    }
+
+    #[test]
+    fn restore_with_fewer_weapons() {
+        let mut before = game(&format!("{}{}", ROCKET_LAUNCHER, RIFLE));
+        let owner = *before.players.keys().next().unwrap();
+        for player in before.players.values_mut() {
+            // Carrying the rifle, which won't be in the same slot afterwards
+            assert!(player
+                .inventory
+                .switch(1, before.weapons.get(1).unwrap(), 0));
+        }
+        before.projectiles.insert(
+            0,
+            Projectile {
+                id: 0,
+                owner,
+                weapon: 0,
+                position: Point3::new(1.0, 1.0, 1.0),
+                velocity: Vector3::unit_x(),
+                explode_tick: 100,
+                known: HashSet::new(),
+            },
+        );
+        let snapshot = before.snapshot().unwrap();
+
+        let mut after = game(RIFLE);
+        after.restore(snapshot).unwrap();
+        assert!(after.projectiles.is_empty());
+        assert_eq!(after.players.len(), 2);
+        for player in after.players.values() {
+            assert!(player.inventory.fits(&after.weapons));
+            assert_eq!(player.inventory.current_weapon(), 0);
+        }
+        // Bots fire and reload with what they're carrying
+        for _ in 0..TICKS_PER_SECOND * 3 {
+            after.tick();
+        }
+    }
+
+    #[test]
+    fn teams_last_through_map_changes() {
+        let mut game = game_of(RIFLE, ModeKind::CaptureTheFlag);
+        // Both on the same side, as if chosen in the lobby
+        for player in game.players.values_mut() {
+            player.team = Some(Team::Red);
+        }
+        game.change_map(map(8));
+        game.reset_world();
+        assert!(game
+            .players
+            .values()
+            .all(|player| player.team == Some(Team::Red)));
+
+        // A different mode starts the teams over
+        game.mode_config.kind = ModeKind::TeamDeathmatch;
+        game.mode_changed = true;
+        game.reset_world();
+        let red = game
+            .players
+            .values()
+            .filter(|player| player.team == Some(Team::Red))
+            .count();
+        assert_eq!(red, 1);
+    }
+}
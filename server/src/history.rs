@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+
+/// Opposite corners of a bounding box
+pub type Hitbox = ([f32; 3], [f32; 3]);
+
+/// A player's hitbox over the last few ticks, so that shots can be checked against
+/// what the shooter saw rather than where everyone is now.
+pub struct HitboxHistory {
+    /// Oldest first
+    entries: VecDeque<(u64, Hitbox)>,
+    length: usize,
+}
+
+impl HitboxHistory {
+    pub fn new(length: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(length),
+            length,
+        }
+    }
+
+    pub fn record(&mut self, tick: u64, hitbox: Hitbox) {
+        if self.entries.len() == self.length {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((tick, hitbox));
+    }
+
+    /// Keep a different number of ticks, dropping the oldest if there are too many
+    pub fn resize(&mut self, length: usize) {
+        while self.entries.len() > length {
+            self.entries.pop_front();
+        }
+        self.length = length;
+    }
+
+    /// Forget everything, e.g. when the player teleports
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The hitbox as of the given tick. If that's older than anything we have, the
+    /// oldest hitbox is returned.
+    pub fn at(&self, tick: u64) -> Option<Hitbox> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(recorded, _)| *recorded <= tick)
+            .or_else(|| self.entries.front())
+            .map(|(_, hitbox)| *hitbox)
+    }
+}
@@ -0,0 +1,147 @@
+use network::{Packet, SlotState, WeaponDefinition, WeaponId, Weapons};
+use serde_derive::{Deserialize, Serialize};
+
+/// The weapons a player is carrying, and what they're doing with them
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Inventory {
+    slots: Vec<SlotState>,
+    current: usize,
+
+    /// The tick at which the current weapon may next fire, after the last shot or
+    /// switching to it
+    ready_tick: u64,
+
+    /// While reloading, the tick at which it finishes
+    reload_tick: Option<u64>,
+}
+
+impl Inventory {
+    /// One of every weapon, fully loaded
+    pub fn new(weapons: &Weapons) -> Self {
+        Self {
+            slots: weapons
+                .iter()
+                .map(|(weapon, definition)| SlotState {
+                    weapon,
+                    magazine: definition.magazine_size.unwrap_or(0),
+                    reserve: definition.spare_ammo,
+                })
+                .collect(),
+            current: 0,
+            ready_tick: 0,
+            reload_tick: None,
+        }
+    }
+
+    /// Whether this was made from the same weapon definitions, so every slot is of
+    /// a weapon that exists, e.g. after restoring a snapshot
+    pub fn fits(&self, weapons: &Weapons) -> bool {
+        self.slots.len() == weapons.len()
+            && self.current < self.slots.len()
+            && self
+                .slots
+                .iter()
+                .zip(weapons.iter())
+                .all(|(slot, (weapon, _))| slot.weapon == weapon)
+    }
+
+    pub fn current_weapon(&self) -> WeaponId {
+        self.slots[self.current].weapon
+    }
+
+    /// Use up a round of the current weapon, if it's ready and has one
+    pub fn try_fire(&mut self, definition: &WeaponDefinition, tick: u64) -> bool {
+        if self.reload_tick.is_some() || tick < self.ready_tick {
+            return false;
+        }
+        let slot = &mut self.slots[self.current];
+        if definition.magazine_size.is_some() {
+            if slot.magazine == 0 {
+                return false;
+            }
+            slot.magazine -= 1;
+        }
+        self.ready_tick = tick + definition.cooldown_ticks();
+        true
+    }
+
+    /// Whether the current weapon has an empty magazine that could be refilled
+    pub fn needs_reload(&self, definition: &WeaponDefinition) -> bool {
+        let slot = &self.slots[self.current];
+        definition.magazine_size.is_some() && slot.magazine == 0 && slot.reserve > 0
+    }
+
+    /// Start reloading the current weapon, unless there's no point
+    pub fn start_reload(&mut self, definition: &WeaponDefinition, tick: u64) -> bool {
+        let slot = &self.slots[self.current];
+        let magazine_size = match definition.magazine_size {
+            Some(size) => size,
+            None => return false,
+        };
+        if self.reload_tick.is_some() || slot.magazine >= magazine_size || slot.reserve == 0 {
+            return false;
+        }
+        self.reload_tick = Some(tick + definition.reload_ticks());
+        true
+    }
+
+    /// Put away the current weapon and get out the one in the given slot. This
+    /// abandons any reload in progress.
+    pub fn switch(&mut self, slot: usize, definition: &WeaponDefinition, tick: u64) -> bool {
+        if slot >= self.slots.len() || slot == self.current {
+            return false;
+        }
+        self.current = slot;
+        self.reload_tick = None;
+        self.ready_tick = self.ready_tick.max(tick + definition.switch_ticks());
+        true
+    }
+
+    pub fn slot_weapon(&self, slot: usize) -> Option<WeaponId> {
+        self.slots.get(slot).map(|slot| slot.weapon)
+    }
+
+    pub fn current_slot(&self) -> usize {
+        self.current
+    }
+
+    /// Whether the weapon in the given slot has anything left to fire, loaded or not
+    pub fn has_ammo(&self, slot: usize, definition: &WeaponDefinition) -> bool {
+        match self.slots.get(slot) {
+            Some(slot) => {
+                definition.magazine_size.is_none() || slot.magazine > 0 || slot.reserve > 0
+            }
+            None => false,
+        }
+    }
+
+    /// Finish any reload that's due, returning whether it did
+    pub fn update(&mut self, weapons: &Weapons, tick: u64) -> bool {
+        match self.reload_tick {
+            Some(reload_tick) if reload_tick <= tick => {
+                let slot = &mut self.slots[self.current];
+                let magazine_size = weapons
+                    .get(slot.weapon)
+                    .and_then(|definition| definition.magazine_size)
+                    .unwrap_or(0);
+                let loaded = magazine_size
+                    .saturating_sub(slot.magazine)
+                    .min(slot.reserve);
+                slot.magazine += loaded;
+                slot.reserve -= loaded;
+                self.reload_tick = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// An Inventory packet describing this inventory
+    pub fn packet(&self) -> Packet {
+        Packet::Inventory {
+            current: self.current as u32,
+            reloading: self.reload_tick.is_some(),
+            slots: self.slots.clone(),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::config::MapSource;
+use crate::raycast::{self, RayHit};
+use anyhow::*;
+use cgmath::{InnerSpace, Point3, Vector3};
+use log::*;
+use mapgen::nav::{NavGrid, NavSettings};
+use mapgen::{DungeonSpecification, MapFile, RoomGraph, Voxel, VOXEL_SIZE};
+use network::{Packet, MAP_CHUNK_SIZE, MAX_VOXELS_PER_PACKET};
+use rand::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// How far outside the box around the voxels players may be, in world units
+const BOUNDS_MARGIN: f32 = 10.0;
+
+pub struct Map {
+    spawn_points: Vec<[f32; 3]>,
+    voxels: HashSet<(i32, i32, i32)>,
+
+    /// The corners of the box around every voxel, in world units
+    bounds: (Point3<f32>, Point3<f32>),
+
+    /// Which rooms lead into which, and where exactly there's room to walk, for
+    /// bots to find their way around
+    rooms: RoomGraph,
+    nav: NavGrid,
+
+    /// Everything destroyed since the map was loaded, in order. Clients get the
+    /// original map file and then these.
+    removed: Vec<Voxel>,
+
+    /// The map file, as sent to clients
+    data: Vec<u8>,
+    hash: [u8; 32],
+
+    /// Set if the map was generated, so clients can regenerate it themselves
+    generated_from: Option<(DungeonSpecification, u64)>,
+}
+
+/// A map as kept in a snapshot: the original, and what's been destroyed since
+#[derive(Deserialize, Serialize)]
+pub struct MapSnapshot {
+    data: Vec<u8>,
+    generated_from: Option<(DungeonSpecification, u64)>,
+    removed: Vec<Voxel>,
+}
+
+impl Map {
+    pub fn from_source<R: Rng>(source: &MapSource, rng: &mut R) -> Result<Self> {
+        match source {
+            MapSource::File { file } => Self::load(file),
+            MapSource::Generate { generate } => {
+                Self::generate(&generate.spec, generate.seed.unwrap_or_else(|| rng.gen()))
+            }
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())
+            .with_context(|| format!("Reading map {:?}", path.as_ref()))?;
+        Self::new(data, None)
+    }
+
+    pub fn generate(spec: &DungeonSpecification, seed: u64) -> Result<Self> {
+        info!("Generating {:?} dungeon from seed {}", spec, seed);
+        let data = mapgen::generate_map(spec, seed).to_bytes();
+        Self::new(data, Some((spec.clone(), seed)))
+    }
+
+    fn new(data: Vec<u8>, generated_from: Option<(DungeonSpecification, u64)>) -> Result<Self> {
+        let file = MapFile::from_bytes(&data)?;
+        if file.spawn_points.is_empty() {
+            bail!("Map has no spawn points");
+        }
+        info!(
+            "Loaded map with {} voxels and {} spawn points",
+            file.voxels.len(),
+            file.spawn_points.len()
+        );
+        let extent = |axis: fn(&Voxel) -> u16| {
+            let min = file.voxels.iter().map(axis).min().unwrap_or(0);
+            let max = file.voxels.iter().map(axis).max().map_or(0, |max| max + 1);
+            (min as f32 * VOXEL_SIZE, max as f32 * VOXEL_SIZE)
+        };
+        let (x, y, z) = (extent(|v| v.x), extent(|v| v.y), extent(|v| v.z));
+        Ok(Self {
+            spawn_points: file.spawn_points,
+            bounds: (Point3::new(x.0, 0.0, z.0), Point3::new(x.1, y.1, z.1)),
+            voxels: file
+                .voxels
+                .iter()
+                .map(|v| (v.x as i32, v.y as i32, v.z as i32))
+                .collect(),
+            rooms: RoomGraph::from_voxels(&file.voxels),
+            nav: NavGrid::new(&file.voxels, NavSettings::default()),
+            removed: vec![],
+            hash: network::content_hash(&data),
+            data,
+            generated_from,
+        })
+    }
+
+    pub fn restore(snapshot: MapSnapshot) -> Result<Self> {
+        let mut map = Self::new(snapshot.data, snapshot.generated_from)?;
+        map.remove_voxels(&snapshot.removed);
+        Ok(map)
+    }
+
+    pub fn snapshot(&self) -> MapSnapshot {
+        MapSnapshot {
+            data: self.data.clone(),
+            generated_from: self.generated_from.clone(),
+            removed: self.removed.clone(),
+        }
+    }
+
+    pub fn info(&self) -> Packet {
+        match &self.generated_from {
+            Some((spec, seed)) => Packet::MapSeed {
+                version: mapgen::ALGORITHM_VERSION,
+                spec: spec.clone(),
+                seed: *seed,
+                hash: self.hash,
+                size: self.data.len() as u32,
+            },
+            None => Packet::MapInfo {
+                hash: self.hash,
+                size: self.data.len() as u32,
+            },
+        }
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = Packet> + '_ {
+        self.data
+            .chunks(MAP_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| Packet::MapChunk {
+                offset: (i * MAP_CHUNK_SIZE) as u32,
+                data: chunk.to_vec(),
+            })
+    }
+
+    /// VoxelsChanged packets that bring the original map up to date
+    pub fn changes(&self) -> impl Iterator<Item = Packet> + '_ {
+        self.removed
+            .chunks(MAX_VOXELS_PER_PACKET)
+            .map(|removed| Packet::VoxelsChanged {
+                removed: removed.to_vec(),
+            })
+    }
+
+    /// Destroy every voxel whose center is within radius of the given point,
+    /// returning the ones that were there
+    pub fn remove_sphere(&mut self, center: Point3<f32>, radius: f32) -> Vec<Voxel> {
+        let min = (center - Vector3::new(radius, radius, radius)) / VOXEL_SIZE;
+        let max = (center + Vector3::new(radius, radius, radius)) / VOXEL_SIZE;
+        let mut removed = vec![];
+        for x in min.x.floor() as i32..=max.x.ceil() as i32 {
+            for y in min.y.floor() as i32..=max.y.ceil() as i32 {
+                for z in min.z.floor() as i32..=max.z.ceil() as i32 {
+                    let voxel_center =
+                        Point3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5) * VOXEL_SIZE;
+                    if (voxel_center - center).magnitude() <= radius
+                        && self.voxels.contains(&(x, y, z))
+                    {
+                        removed.push(Voxel {
+                            x: x as u16,
+                            y: y as u16,
+                            z: z as u16,
+                        });
+                    }
+                }
+            }
+        }
+        self.remove_voxels(&removed);
+        removed
+    }
+
+    fn remove_voxels(&mut self, removed: &[Voxel]) {
+        for voxel in removed {
+            self.voxels
+                .remove(&(voxel.x as i32, voxel.y as i32, voxel.z as i32));
+        }
+        self.removed.extend_from_slice(removed);
+        // Blasting through a wall can open up a new way between rooms
+        if !removed.is_empty() {
+            self.nav.remove_voxels(removed);
+            let voxels = &self.voxels;
+            self.rooms = RoomGraph::new(self.rooms.size(), |x, y, z| voxels.contains(&(x, y, z)));
+        }
+    }
+
+    /// The first wall or floor along the ray, if it's within max_distance.
+    /// `direction` must be normalized.
+    pub fn raycast(
+        &self,
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<RayHit> {
+        let finite = |v: [f32; 3]| v.iter().all(|c| c.is_finite());
+        if !finite(origin.into()) || !finite(direction.into()) {
+            return None;
+        }
+        // Only the stretch of the ray inside the map is stepped through, so rays from
+        // far away or over long ranges cost no more than ones across it. Everything
+        // below is floor, wherever it is.
+        let floor = raycast::floor_distance(origin, direction);
+        let limit = floor.min(max_distance);
+        let (min, max) = self.bounds;
+        let wall = raycast::clip_ray(origin, direction, min, max, limit).and_then(|(near, far)| {
+            // Starting a voxel short of the box, so the face it goes in by is found
+            let near = (near - VOXEL_SIZE).max(0.0);
+            let start = origin + direction * near;
+            raycast::ray_voxels(&self.voxels, start, direction, far - near).map(|hit| RayHit {
+                distance: near + hit.distance,
+                ..hit
+            })
+        });
+        wall.or_else(|| {
+            (floor <= max_distance).then(|| RayHit {
+                distance: floor,
+                normal: Vector3::unit_y(),
+            })
+        })
+    }
+
+    /// Whether a point is within the map, or not far outside it
+    pub fn contains(&self, point: [f32; 3]) -> bool {
+        let (min, max) = self.bounds;
+        (0..3).all(|axis| {
+            point[axis] >= min[axis] - BOUNDS_MARGIN && point[axis] <= max[axis] + BOUNDS_MARGIN
+        })
+    }
+
+    /// Whether there's nothing solid on the straight line between the two points
+    pub fn line_of_sight(&self, from: Point3<f32>, to: Point3<f32>) -> bool {
+        let offset = to - from;
+        let distance = offset.magnitude();
+        if distance == 0.0 {
+            return true;
+        }
+        self.raycast(from, offset / distance, distance).is_none()
+    }
+
+    pub fn rooms(&self) -> &RoomGraph {
+        &self.rooms
+    }
+
+    pub fn nav(&self) -> &NavGrid {
+        &self.nav
+    }
+
+    pub fn spawn_points(&self) -> &[[f32; 3]] {
+        &self.spawn_points
+    }
+
+    /// Pick a spawn point away from everyone in `others`, out of those `allowed`
+    /// accepts (or all of them, if it accepts none). We choose randomly amongst the
+    /// quarter of spawn points whose nearest player is furthest away, so that
+    /// players joining an empty server don't all start in the same place.
+    pub fn choose_spawn<R: Rng>(
+        &self,
+        others: &[[f32; 3]],
+        allowed: impl Fn([f32; 3]) -> bool,
+        rng: &mut R,
+    ) -> [f32; 3] {
+        let mut spawn_points: Vec<_> = self
+            .spawn_points
+            .iter()
+            .filter(|spawn| allowed(**spawn))
+            .collect();
+        if spawn_points.is_empty() {
+            spawn_points = self.spawn_points.iter().collect();
+        }
+        let mut candidates: Vec<_> = spawn_points
+            .into_iter()
+            .map(|spawn| {
+                let nearest = others
+                    .iter()
+                    .map(|other| distance_squared(spawn, other))
+                    .fold(f32::INFINITY, f32::min);
+                (nearest, *spawn)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        let count = (candidates.len() / 4).max(1);
+        candidates[rng.gen_range(0..count)].1
+    }
+}
+
+fn distance_squared(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
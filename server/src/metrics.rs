@@ -0,0 +1,352 @@
+use anyhow::*;
+use log::*;
+use network::Packet;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the rates are worked out and the page is brought up to date
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// How long a scraper gets to send its whole request, however slowly it trickles in
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The most of a request that's read, which is plenty for the request line and any
+/// headers a scraper sends
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+#[derive(Clone, Copy, Default)]
+struct Count {
+    packets: u64,
+    bytes: u64,
+}
+
+/// What's happening in a match right now
+pub struct MatchGauges {
+    pub name: String,
+    pub players: usize,
+    pub bots: usize,
+    pub spectators: usize,
+}
+
+/// Counts of what the server is doing, served as a Prometheus text page on
+/// localhost when a port is configured
+pub struct Metrics {
+    /// The page the endpoint serves, or None if there isn't one
+    page: Option<Arc<Mutex<String>>>,
+
+    /// Packets and bytes since the server started, by direction and packet name
+    totals: BTreeMap<(&'static str, &'static str), Count>,
+
+    /// The totals at the start of the last window, and the rates over it
+    previous: BTreeMap<(&'static str, &'static str), Count>,
+    rates: BTreeMap<(&'static str, &'static str), (f64, f64)>,
+
+    connection_errors: u64,
+
+    /// How long each tick has taken this window, and the average and longest over
+    /// the last one
+    tick_times: Vec<Duration>,
+    tick_average: Duration,
+    tick_max: Duration,
+
+    window_start: Instant,
+}
+
+impl Metrics {
+    /// Start serving on the given port, if there is one
+    pub fn new(port: Option<u16>) -> Result<Self> {
+        let page = match port {
+            Some(port) => Some(serve(port)?),
+            None => None,
+        };
+        let metrics = Self {
+            page,
+            totals: BTreeMap::new(),
+            previous: BTreeMap::new(),
+            rates: BTreeMap::new(),
+            connection_errors: 0,
+            tick_times: vec![],
+            tick_average: Duration::ZERO,
+            tick_max: Duration::ZERO,
+            window_start: Instant::now(),
+        };
+        // Something to serve until the first update
+        if let Some(page) = metrics.page.as_ref() {
+            *page.lock().unwrap() = metrics.render(0, &[]);
+        }
+        Ok(metrics)
+    }
+
+    pub fn sent(&mut self, packet: &Packet) {
+        self.count("sent", packet);
+    }
+
+    pub fn received(&mut self, packet: &Packet) {
+        self.count("received", packet);
+    }
+
+    fn count(&mut self, direction: &'static str, packet: &Packet) {
+        // Measuring packets costs something, so only bother if anyone's looking
+        if self.page.is_none() {
+            return;
+        }
+        let bytes = bincode::serialized_size(packet).unwrap_or(0);
+        let count = self.totals.entry((direction, packet.name())).or_default();
+        count.packets += 1;
+        count.bytes += bytes;
+    }
+
+    pub fn connection_error(&mut self) {
+        self.connection_errors += 1;
+    }
+
+    pub fn tick_time(&mut self, time: Duration) {
+        self.tick_times.push(time);
+    }
+
+    /// Whether it's time to call update
+    pub fn is_due(&self) -> bool {
+        self.page.is_some() && self.window_start.elapsed() >= WINDOW
+    }
+
+    /// Work out the rates over the last window, and bring the page up to date
+    pub fn update(&mut self, connections: usize, matches: &[MatchGauges]) {
+        let page = match self.page.as_ref() {
+            Some(page) => page.clone(),
+            None => return,
+        };
+        let elapsed = self.window_start.elapsed();
+        self.window_start = Instant::now();
+
+        let seconds = elapsed.as_secs_f64();
+        self.rates = self
+            .totals
+            .iter()
+            .map(|(key, count)| {
+                let before = self.previous.get(key).copied().unwrap_or_default();
+                let packets = (count.packets - before.packets) as f64 / seconds;
+                let bytes = (count.bytes - before.bytes) as f64 / seconds;
+                (*key, (packets, bytes))
+            })
+            .collect();
+        self.previous = self.totals.clone();
+
+        let ticks = std::mem::take(&mut self.tick_times);
+        self.tick_max = ticks.iter().max().copied().unwrap_or_default();
+        self.tick_average = match ticks.len() {
+            0 => Duration::ZERO,
+            count => ticks.iter().sum::<Duration>() / count as u32,
+        };
+
+        let text = self.render(connections, matches);
+        *page.lock().unwrap() = text;
+    }
+
+    fn render(&self, connections: usize, matches: &[MatchGauges]) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, f64)>| {
+            let _ = writeln!(text, "# HELP shootvoxel_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE shootvoxel_{} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(text, "shootvoxel_{}{} {}", name, labels, value);
+            }
+        };
+
+        metric(
+            "tick_seconds",
+            "gauge",
+            "Average time taken by a tick over the last second",
+            vec![(String::new(), self.tick_average.as_secs_f64())],
+        );
+        metric(
+            "tick_max_seconds",
+            "gauge",
+            "Longest time taken by a tick over the last second",
+            vec![(String::new(), self.tick_max.as_secs_f64())],
+        );
+        metric(
+            "connections",
+            "gauge",
+            "Open connections, including ones not in a match",
+            vec![(String::new(), connections as f64)],
+        );
+        let by_match = |value: fn(&MatchGauges) -> usize| {
+            matches
+                .iter()
+                .map(|gauges| (labels(&[("match", &gauges.name)]), value(gauges) as f64))
+                .collect()
+        };
+        metric(
+            "players",
+            "gauge",
+            "Players in each match, not counting bots",
+            by_match(|gauges| gauges.players),
+        );
+        metric(
+            "bots",
+            "gauge",
+            "Bots in each match",
+            by_match(|gauges| gauges.bots),
+        );
+        metric(
+            "spectators",
+            "gauge",
+            "Spectators in each match",
+            by_match(|gauges| gauges.spectators),
+        );
+
+        let by_packet = |value: fn(&(f64, f64)) -> f64| {
+            self.rates
+                .iter()
+                .map(|((direction, name), rates)| {
+                    let labels = labels(&[("direction", direction), ("type", name)]);
+                    (labels, value(rates))
+                })
+                .collect()
+        };
+        metric(
+            "packets_per_second",
+            "gauge",
+            "Packets sent and received over the last second, by type",
+            by_packet(|rates| rates.0),
+        );
+        metric(
+            "bytes_per_second",
+            "gauge",
+            "Bytes of packets sent and received over the last second, by type",
+            by_packet(|rates| rates.1),
+        );
+        let totals = |value: fn(&Count) -> u64| {
+            self.totals
+                .iter()
+                .map(|((direction, name), count)| {
+                    let labels = labels(&[("direction", direction), ("type", name)]);
+                    (labels, value(count) as f64)
+                })
+                .collect()
+        };
+        metric(
+            "packets_total",
+            "counter",
+            "Packets sent and received since the server started, by type",
+            totals(|count| count.packets),
+        );
+        metric(
+            "bytes_total",
+            "counter",
+            "Bytes of packets sent and received since the server started, by type",
+            totals(|count| count.bytes),
+        );
+
+        metric(
+            "connection_errors_total",
+            "counter",
+            "Connections lost or refused because of an error",
+            vec![(String::new(), self.connection_errors as f64)],
+        );
+        if let Some(bytes) = resident_memory() {
+            metric(
+                "resident_memory_bytes",
+                "gauge",
+                "Memory the server is using",
+                vec![(String::new(), bytes as f64)],
+            );
+        }
+        text
+    }
+}
+
+/// Prometheus labels, such as {match="main"}
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// The resident set size, where the system will tell us it
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Answer requests for the metrics on another thread, so a slow scraper doesn't
+/// hold up the game. Only local connections can reach it.
+fn serve(port: u16) -> Result<Arc<Mutex<String>>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Listening for metrics on port {}", port))?;
+    info!("Serving metrics at http://127.0.0.1:{}/metrics", port);
+    let page = Arc::new(Mutex::new(String::new()));
+    let served = page.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            if let Err(e) = respond(stream, &served) {
+                debug!("Couldn't answer a metrics request: {}", e);
+            }
+        }
+    });
+    Ok(page)
+}
+
+/// Reads from a scraper, each read only waiting for whatever's left of the time it
+/// has, so it can't hold on to the thread by sending a byte at a time
+struct Request<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Request<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn respond(stream: TcpStream, page: &Mutex<String>) -> std::io::Result<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = Request {
+        stream: &stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    let mut reader = BufReader::new(request.take(MAX_REQUEST_BYTES));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers don't matter, but they have to be read before replying
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", page.lock().unwrap().clone())
+    } else {
+        ("404 Not Found", "Try /metrics\n".to_string())
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,108 @@
+use crate::config::ModeConfig;
+use crate::ctf::CaptureTheFlag;
+use crate::deathmatch::Deathmatch;
+use crate::game::Player;
+use crate::map::Map;
+use anyhow::*;
+use network::{ModeKind, Packet, Team, Winner, TICKS_PER_SECOND};
+use std::collections::HashMap;
+
+/// How long dead players wait before respawning, unless the mode says otherwise
+pub const RESPAWN_TICKS: u64 = 3 * TICKS_PER_SECOND;
+
+/// The rules of the game being played. The game tells the mode what happens, and
+/// the mode decides what it means for the score. Packets returned by the mode are
+/// sent to everyone.
+pub trait GameMode {
+    fn kind(&self) -> ModeKind;
+
+    fn score_limit(&self) -> u32;
+
+    /// Packets describing the scores and so on, for players joining part way through
+    fn state(&self) -> Vec<Packet>;
+
+    /// A player's own score, for the scoreboard
+    fn player_score(&self, id: u32) -> i32;
+
+    /// The team a new player should join, if this is a team mode
+    fn choose_team(&mut self, _players: &HashMap<u32, Player>) -> Option<Team> {
+        None
+    }
+
+    /// Whether a player on the given team may spawn at the given spawn point
+    fn is_spawn_for(&self, _team: Option<Team>, _spawn: [f32; 3]) -> bool {
+        true
+    }
+
+    fn respawn_ticks(&self) -> u64 {
+        RESPAWN_TICKS
+    }
+
+    /// The victim has just died. The killer is the same player for suicides.
+    fn player_killed(&mut self, killer: &Player, victim: &Player) -> Vec<Packet>;
+
+    /// Called every tick during a round
+    fn update(&mut self, _players: &HashMap<u32, Player>, _tick: u64) -> Vec<Packet> {
+        vec![]
+    }
+
+    /// Who has won, if anyone has yet. Once time is up somebody has, or it's a draw.
+    fn winner(&self, time_up: bool) -> Option<Winner>;
+
+    /// Start again from nothing for a new round
+    fn reset(&mut self) -> Vec<Packet>;
+
+    /// The scores and so on, for a snapshot
+    fn save(&self) -> Result<Vec<u8>>;
+
+    /// Carry on from a snapshot saved by a mode of the same kind
+    fn restore(&mut self, data: &[u8]) -> Result<()>;
+}
+
+pub fn from_config(config: &ModeConfig, map: &Map) -> Box<dyn GameMode> {
+    match config.kind {
+        ModeKind::Deathmatch => Box::new(Deathmatch::new(false, config.score_limit.unwrap_or(25))),
+        ModeKind::TeamDeathmatch => {
+            Box::new(Deathmatch::new(true, config.score_limit.unwrap_or(50)))
+        }
+        ModeKind::CaptureTheFlag => {
+            Box::new(CaptureTheFlag::new(map, config.score_limit.unwrap_or(3)))
+        }
+    }
+}
+
+/// The team with the fewest players
+pub fn smaller_team(players: &HashMap<u32, Player>) -> Team {
+    let count = |team| {
+        players
+            .values()
+            .filter(|player| player.team == Some(team))
+            .count()
+    };
+    if count(Team::Blue) < count(Team::Red) {
+        Team::Blue
+    } else {
+        Team::Red
+    }
+}
+
+/// Whoever has the highest score, if they have it to themselves
+pub fn leader<K: Copy, S: Copy + Ord>(scores: impl Iterator<Item = (K, S)>) -> Option<(K, S)> {
+    let mut best: Option<(K, S)> = None;
+    let mut tied = false;
+    for (key, score) in scores {
+        match best {
+            Some((_, best_score)) if score < best_score => {}
+            Some((_, best_score)) if score == best_score => tied = true,
+            _ => {
+                best = Some((key, score));
+                tied = false;
+            }
+        }
+    }
+    if tied {
+        None
+    } else {
+        best
+    }
+}
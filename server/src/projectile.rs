@@ -0,0 +1,151 @@
+use crate::history::Hitbox;
+use crate::map::Map;
+use crate::raycast;
+use cgmath::{InnerSpace, Point3, Vector3, Zero};
+use network::{Ballistics, WeaponId, TICKS_PER_SECOND};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// How far from a surface projectiles are kept, so they don't start inside it
+const SURFACE_OFFSET: f32 = 0.01;
+
+/// Below this speed, in units per second, a bouncing projectile stops
+const REST_SPEED: f32 = 0.5;
+
+pub enum Step {
+    /// Still going. If it bounced, its velocity changed in a way clients won't
+    /// have predicted.
+    Flying {
+        bounced: bool,
+    },
+    Exploded(Point3<f32>),
+}
+
+pub struct Projectile {
+    pub id: u32,
+
+    /// The player who launched it
+    pub owner: u32,
+    pub weapon: WeaponId,
+    pub position: Point3<f32>,
+    pub velocity: Vector3<f32>,
+    pub explode_tick: u64,
+
+    /// The connections that have been told about it, which are only those that
+    /// have seen it
+    pub known: HashSet<u32>,
+}
+
+/// A projectile as kept in a snapshot
+#[derive(Deserialize, Serialize)]
+pub struct SavedProjectile {
+    id: u32,
+    owner: u32,
+    weapon: WeaponId,
+    position: [f32; 3],
+    velocity: [f32; 3],
+    explode_tick: u64,
+}
+
+impl From<&Projectile> for SavedProjectile {
+    fn from(projectile: &Projectile) -> Self {
+        Self {
+            id: projectile.id,
+            owner: projectile.owner,
+            weapon: projectile.weapon,
+            position: projectile.position.into(),
+            velocity: projectile.velocity.into(),
+            explode_tick: projectile.explode_tick,
+        }
+    }
+}
+
+impl From<SavedProjectile> for Projectile {
+    fn from(saved: SavedProjectile) -> Self {
+        Self {
+            id: saved.id,
+            owner: saved.owner,
+            weapon: saved.weapon,
+            position: saved.position.into(),
+            velocity: saved.velocity.into(),
+            explode_tick: saved.explode_tick,
+            known: HashSet::new(),
+        }
+    }
+}
+
+impl Projectile {
+    /// Advance by one tick. `targets` are the hitboxes of the players it might hit.
+    pub fn step(
+        &mut self,
+        ballistics: &Ballistics,
+        map: &Map,
+        targets: &[(u32, Hitbox)],
+        tick: u64,
+    ) -> Step {
+        if tick >= self.explode_tick {
+            return Step::Exploded(self.position);
+        }
+        let dt = 1.0 / TICKS_PER_SECOND as f32;
+        self.velocity.y -= ballistics.gravity * dt;
+        let movement = self.velocity * dt;
+        let distance = movement.magnitude();
+        if distance == 0.0 {
+            return Step::Flying { bounced: false };
+        }
+        let direction = movement / distance;
+        let wall = map.raycast(self.position, direction, distance);
+
+        let restitution = match ballistics.restitution {
+            Some(restitution) => restitution,
+            None => {
+                // Explode on whatever we touch first
+                let wall_distance = wall.as_ref().map(|hit| hit.distance).unwrap_or(distance);
+                let player_distance = targets
+                    .iter()
+                    .filter(|(id, _)| *id != self.owner)
+                    .filter_map(|(_, (min, max))| {
+                        raycast::ray_box(
+                            self.position,
+                            direction,
+                            (*min).into(),
+                            (*max).into(),
+                            wall_distance,
+                        )
+                    })
+                    .fold(None, |nearest: Option<f32>, d| {
+                        Some(nearest.map_or(d, |nearest| nearest.min(d)))
+                    });
+                if let Some(d) = player_distance {
+                    return Step::Exploded(self.position + direction * d);
+                }
+                if let Some(hit) = wall {
+                    return Step::Exploded(
+                        self.position + direction * hit.distance + hit.normal * SURFACE_OFFSET,
+                    );
+                }
+                self.position += movement;
+                return Step::Flying { bounced: false };
+            }
+        };
+
+        match wall {
+            Some(hit) => {
+                let speed = self.velocity.magnitude();
+                self.position += direction * hit.distance + hit.normal * SURFACE_OFFSET;
+                self.velocity = (self.velocity - hit.normal * 2.0 * self.velocity.dot(hit.normal))
+                    * restitution;
+                if self.velocity.magnitude() < REST_SPEED {
+                    self.velocity = Vector3::zero();
+                }
+                Step::Flying {
+                    bounced: speed >= REST_SPEED,
+                }
+            }
+            None => {
+                self.position += movement;
+                Step::Flying { bounced: false }
+            }
+        }
+    }
+}
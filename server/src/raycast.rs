@@ -0,0 +1,132 @@
+use cgmath::{Point3, Vector3, Zero};
+use mapgen::VOXEL_SIZE;
+use std::collections::HashSet;
+
+/// The distance along the ray at which it enters the box, if it does so before
+/// max_distance. `direction` must be normalized.
+pub fn ray_box(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    min: Point3<f32>,
+    max: Point3<f32>,
+    max_distance: f32,
+) -> Option<f32> {
+    clip_ray(origin, direction, min, max, max_distance).map(|(near, _)| near)
+}
+
+/// The distances along the ray at which it enters and leaves the box, if it passes
+/// through it before max_distance
+pub fn clip_ray(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    min: Point3<f32>,
+    max: Point3<f32>,
+    max_distance: f32,
+) -> Option<(f32, f32)> {
+    let mut near = 0.0f32;
+    let mut far = max_distance;
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (min[axis] - origin[axis]) / direction[axis];
+        let t2 = (max[axis] - origin[axis]) / direction[axis];
+        near = near.max(t1.min(t2));
+        far = far.min(t1.max(t2));
+        if near > far {
+            return None;
+        }
+    }
+    Some((near, far))
+}
+
+/// Where a ray stopped
+pub struct RayHit {
+    pub distance: f32,
+
+    /// The outward normal of the surface that was hit
+    pub normal: Vector3<f32>,
+}
+
+/// The first solid voxel or floor (at y = 0) along the ray, if either is closer than
+/// max_distance. `direction` must be normalized.
+///
+/// This steps through the voxel grid one cell boundary at a time, see "A Fast Voxel
+/// Traversal Algorithm for Ray Tracing" by Amanatides and Woo.
+pub fn ray_voxels(
+    voxels: &HashSet<(i32, i32, i32)>,
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    max_distance: f32,
+) -> Option<RayHit> {
+    let floor = floor_distance(origin, direction);
+    let limit = floor.min(max_distance);
+
+    // Work in voxel units
+    let origin = origin / VOXEL_SIZE;
+    let limit = limit / VOXEL_SIZE;
+    let mut cell = [
+        origin.x.floor() as i32,
+        origin.y.floor() as i32,
+        origin.z.floor() as i32,
+    ];
+    let mut step = [0; 3];
+    let mut next_boundary = [f32::INFINITY; 3];
+    let mut boundary_spacing = [f32::INFINITY; 3];
+    for axis in 0..3 {
+        if direction[axis] > 0.0 {
+            step[axis] = 1;
+            next_boundary[axis] = (cell[axis] as f32 + 1.0 - origin[axis]) / direction[axis];
+            boundary_spacing[axis] = 1.0 / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[axis] = -1;
+            next_boundary[axis] = (cell[axis] as f32 - origin[axis]) / direction[axis];
+            boundary_spacing[axis] = -1.0 / direction[axis];
+        }
+    }
+
+    let mut distance = 0.0;
+    // If we start inside a voxel there's no face to speak of, so just push back
+    let mut normal = -direction;
+    while distance <= limit {
+        if voxels.contains(&(cell[0], cell[1], cell[2])) {
+            return Some(RayHit {
+                distance: distance * VOXEL_SIZE,
+                normal,
+            });
+        }
+        let axis = if next_boundary[0] < next_boundary[1] && next_boundary[0] < next_boundary[2] {
+            0
+        } else if next_boundary[1] < next_boundary[2] {
+            1
+        } else {
+            2
+        };
+        distance = next_boundary[axis];
+        next_boundary[axis] += boundary_spacing[axis];
+        cell[axis] += step[axis];
+        normal = Vector3::zero();
+        normal[axis] = -step[axis] as f32;
+    }
+
+    if floor <= max_distance {
+        Some(RayHit {
+            distance: floor,
+            normal: Vector3::unit_y(),
+        })
+    } else {
+        None
+    }
+}
+
+/// How far along the ray it hits the floor, or infinity if it never does
+pub fn floor_distance(origin: Point3<f32>, direction: Vector3<f32>) -> f32 {
+    if direction.y < 0.0 && origin.y >= 0.0 {
+        -origin.y / direction.y
+    } else {
+        f32::INFINITY
+    }
+}
@@ -0,0 +1,801 @@
+use crate::admin::{self, Command};
+use crate::config::{Config, MapSource, MatchConfig};
+use crate::events::{Event, EventLog};
+use crate::game::{Game, Player};
+use crate::map::Map;
+use crate::metrics::Metrics;
+use crate::snapshot::Snapshot;
+use crate::stats::{self, StatsBook};
+use anyhow::*;
+use log::*;
+use network::{Ban, BanList, Connection, ConnectionClosed, ConnectionListener, Packet, Weapons};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// How long a connection that's been hung up on gets to receive its last packets
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often players who haven't chosen a match are sent the list again, so the
+/// player counts stay up to date
+const MATCH_LIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Who a connection logged in as
+#[derive(Clone, Copy)]
+struct Login {
+    username: [u8; 20],
+    spectator: bool,
+}
+
+/// Looks after the connections and admin commands, and passes everything else on to
+/// the match each player is in
+pub struct Server {
+    config: Config,
+
+    /// One for each match in the config, in the same order
+    games: Vec<Game>,
+
+    listener: ConnectionListener,
+    connections: Vec<Connection>,
+
+    /// Connections that have logged in but not chosen a match yet
+    lobby: HashMap<u32, Login>,
+
+    /// Which match each connection is playing in, by uid
+    playing: HashMap<u32, usize>,
+
+    last_match_list: Instant,
+    last_snapshot: Instant,
+
+    /// Connections that have been told why they're being disconnected, and when
+    closing: Vec<(Connection, Instant)>,
+
+    /// Connections allowed to run admin commands, by uid
+    admins: HashSet<u32>,
+
+    bans: BanList,
+    stats: StatsBook,
+    metrics: Metrics,
+    events: EventLog,
+
+    /// Lines typed into the server's terminal
+    console: Receiver<String>,
+
+    shutting_down: bool,
+}
+
+impl Server {
+    pub fn new(config: Config, weapons: Weapons) -> Result<Self> {
+        let mut games = vec![];
+        let mut names = HashSet::new();
+        for match_config in config.all_matches() {
+            if !names.insert(&match_config.name) {
+                bail!("There's more than one match called {}", match_config.name);
+            }
+            info!("Starting match {}", match_config.name);
+            let map = Map::from_source(&match_config.map, &mut rand::thread_rng())?;
+            games.push(Game::new(map, weapons.clone(), match_config));
+        }
+        if let Some(path) = config.snapshot_file.as_ref() {
+            // Better to start afresh than not at all
+            if let Err(e) = restore(&mut games, &config, path) {
+                error!("Couldn't restore the snapshot: {:#}", e);
+            }
+        }
+        let bans = BanList::load(&config.ban_file)?;
+        let stats = StatsBook::load(&config.stats_file)?;
+        let metrics = Metrics::new(config.metrics_port)?;
+        let events = EventLog::open(config.event_log.as_ref())?;
+        Ok(Self {
+            config,
+            games,
+            listener: ConnectionListener::new()?,
+            connections: vec![],
+            lobby: HashMap::new(),
+            playing: HashMap::new(),
+            last_match_list: Instant::now(),
+            last_snapshot: Instant::now(),
+            closing: vec![],
+            admins: HashSet::new(),
+            bans,
+            stats,
+            metrics,
+            events,
+            console: admin::console(),
+            shutting_down: false,
+        })
+    }
+
+    /// False once shut down, and everyone has been told
+    pub fn is_running(&self) -> bool {
+        !self.shutting_down || !self.closing.is_empty()
+    }
+
+    pub fn tick(&mut self) {
+        let tick_start = Instant::now();
+        if !self.shutting_down {
+            self.accept();
+        }
+        self.receive();
+        while let Ok(line) = self.console.try_recv() {
+            println!("{}", self.run_command("console", &line));
+        }
+        for (config, game) in self.config.all_matches().zip(self.games.iter_mut()) {
+            game.tick();
+            let results = game.drain_results();
+            if !results.is_empty() {
+                if let Err(e) = self.stats.record(&results) {
+                    error!("Couldn't record stats: {:#}", e);
+                }
+            }
+            for (tick, event) in game.drain_events() {
+                self.events.write(Some(tick), Some(&config.name), &event);
+            }
+        }
+        if self.last_match_list.elapsed() >= MATCH_LIST_INTERVAL {
+            self.last_match_list = Instant::now();
+            let uids: Vec<_> = self.lobby.keys().copied().collect();
+            for uid in uids {
+                self.send_to(uid, self.match_list());
+            }
+        }
+        if self.config.snapshot_file.is_some()
+            && self.last_snapshot.elapsed().as_secs_f32() >= self.config.snapshot_interval
+        {
+            self.last_snapshot = Instant::now();
+            self.save_snapshot();
+        }
+        self.send_outgoing();
+
+        self.closing.retain(|(cxn, since)| {
+            since.elapsed() < CLOSE_TIMEOUT && cxn.update(|_| Ok(())).is_ok()
+        });
+
+        self.events.flush();
+        self.metrics.tick_time(tick_start.elapsed());
+        if self.metrics.is_due() {
+            let gauges: Vec<_> = self
+                .config
+                .all_matches()
+                .zip(self.games.iter())
+                .map(|(config, game)| game.gauges(&config.name))
+                .collect();
+            self.metrics.update(self.connections.len(), &gauges);
+        }
+    }
+
+    /// Save every match, if snapshots are turned on
+    fn save_snapshot(&self) {
+        let path = match self.config.snapshot_file.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        let result = self
+            .config
+            .all_matches()
+            .zip(self.games.iter())
+            .map(|(config, game)| Ok((config.name.clone(), game.snapshot()?)))
+            .collect::<Result<_>>()
+            .and_then(|matches| Snapshot::new(matches).save(path));
+        match result {
+            Ok(()) => debug!("Saved a snapshot to {}", path),
+            Err(e) => error!("Couldn't save a snapshot: {:#}", e),
+        }
+    }
+
+    fn accept(&mut self) {
+        match self.listener.update(&self.bans) {
+            Ok(Some(cxn)) => self.connections.push(cxn),
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Couldn't accept connection: {:#}", e);
+                self.metrics.connection_error();
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        let mut received = vec![];
+        let mut dropped = vec![];
+        for cxn in self.connections.iter() {
+            match cxn.packets() {
+                Ok(packets) => received.push((cxn.uid(), packets)),
+                Err(e) => dropped.push((cxn.uid(), e)),
+            }
+        }
+        for (uid, e) in dropped {
+            self.lose_connection(uid, e);
+        }
+        for (uid, packets) in received {
+            for packet in packets {
+                // An admin may have kicked them in the meantime
+                if !self.connections.iter().any(|cxn| cxn.uid() == uid) {
+                    break;
+                }
+                debug!("Received packet {}", loggable(&packet));
+                self.metrics.received(&packet);
+                self.handle_packet(uid, packet);
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, uid: u32, packet: Packet) {
+        if out_of_turn(&packet, uid, &self.lobby, &self.playing) {
+            warn!(
+                "Ignoring {} from connection {}, which has already logged in",
+                packet.name(),
+                uid
+            );
+            return;
+        }
+        match packet {
+            Packet::AdminLogin { password } => {
+                let text = match self.config.admin_password.as_ref() {
+                    None => "Remote admin is disabled",
+                    Some(expected) if *expected == password => {
+                        info!("Connection {} logged in as an admin", uid);
+                        self.admins.insert(uid);
+                        "Logged in"
+                    }
+                    Some(_) => {
+                        warn!("Connection {} gave the wrong admin password", uid);
+                        "Wrong password"
+                    }
+                };
+                self.send_to(
+                    uid,
+                    Packet::AdminResponse {
+                        text: text.to_string(),
+                    },
+                );
+            }
+            Packet::AdminCommand { command } => {
+                let text = if self.admins.contains(&uid) {
+                    self.run_command(&format!("connection {}", uid), &command)
+                } else {
+                    "Not logged in as an admin".to_string()
+                };
+                self.send_to(uid, Packet::AdminResponse { text });
+            }
+            Packet::Login {
+                username: encoded,
+                ref password,
+                spectator,
+            } => {
+                let username = network::decode_username(&encoded);
+                if let Some(ban) = self.bans.find_username(&username) {
+                    info!("Turning away banned player {}", username);
+                    let reason = ban.message();
+                    self.disconnect(uid, &reason);
+                } else if self.config.password.is_some() && *password != self.config.password {
+                    info!("Turning away {} for the wrong password", username);
+                    let reason = match password {
+                        Some(_) => "Wrong password",
+                        None => "This server needs a password",
+                    };
+                    self.disconnect(uid, reason);
+                } else {
+                    let login = Login {
+                        username: encoded,
+                        spectator,
+                    };
+                    if self.games.len() == 1 {
+                        self.join(uid, 0, login);
+                    } else {
+                        self.lobby.insert(uid, login);
+                        self.send_to(uid, self.match_list());
+                    }
+                }
+            }
+            Packet::GetStats { username } => {
+                let stats = self.stats.find(&username);
+                self.send_to(uid, Packet::Stats { stats });
+            }
+            Packet::GetLeaderboard => {
+                let entries = self.stats.leaderboard();
+                self.send_to(uid, Packet::Leaderboard { entries });
+            }
+            Packet::JoinMatch { id } => {
+                let login = match self.lobby.get(&uid) {
+                    Some(login) => *login,
+                    None => {
+                        warn!("Connection {} isn't choosing a match", uid);
+                        return;
+                    }
+                };
+                if (id as usize) < self.games.len() {
+                    self.lobby.remove(&uid);
+                    self.join(uid, id as usize, login);
+                } else {
+                    warn!("Connection {} asked for unknown match {}", uid, id);
+                    self.send_to(uid, self.match_list());
+                }
+            }
+            packet => match self.playing.get(&uid) {
+                Some(index) => {
+                    if let Err(e) = self.games[*index].handle_packet(uid, &packet) {
+                        warn!("Dropping connection {}: {:#}", uid, e);
+                        self.metrics.connection_error();
+                        self.disconnect(uid, "Your client sent something it shouldn't have");
+                    }
+                }
+                None => debug!(
+                    "Ignoring {:?} from connection {} outside a match",
+                    packet, uid
+                ),
+            },
+        }
+    }
+
+    fn join(&mut self, uid: u32, index: usize, login: Login) {
+        info!(
+            "{} joined match {}{}",
+            network::decode_username(&login.username),
+            self.match_config(index).name,
+            if login.spectator { " to spectate" } else { "" }
+        );
+        self.playing.insert(uid, index);
+        if login.spectator {
+            self.games[index].spectate(uid, login.username);
+        } else {
+            self.games[index].join(uid, login.username);
+        }
+    }
+
+    fn match_list(&self) -> Packet {
+        let matches = self
+            .config
+            .all_matches()
+            .zip(self.games.iter())
+            .enumerate()
+            .map(|(id, (config, game))| game.summary(id as u32, &config.name))
+            .collect();
+        Packet::MatchList { matches }
+    }
+
+    fn match_config(&self, index: usize) -> &MatchConfig {
+        self.config.all_matches().nth(index).unwrap()
+    }
+
+    fn match_config_mut(&mut self, index: usize) -> &mut MatchConfig {
+        match index {
+            0 => &mut self.config.game,
+            _ => &mut self.config.matches[index - 1],
+        }
+    }
+
+    /// A match by number or name
+    fn find_match(&self, name: &str) -> Result<usize> {
+        let by_number = name.parse().ok().filter(|index| *index < self.games.len());
+        by_number
+            .or_else(|| {
+                self.config
+                    .all_matches()
+                    .position(|config| config.name == name)
+            })
+            .with_context(|| format!("No match {:?}", name))
+    }
+
+    fn send_to(&mut self, uid: u32, packet: Packet) {
+        if let Some(cxn) = self.connections.iter().find(|cxn| cxn.uid() == uid) {
+            // Failures are noticed when receiving
+            let _ = cxn.send(&packet);
+            self.metrics.sent(&packet);
+        }
+    }
+
+    fn send_outgoing(&mut self) {
+        let mut failed = vec![];
+        for (index, game) in self.games.iter_mut().enumerate() {
+            for (audience, packet) in game.drain_outgoing() {
+                for cxn in self.connections.iter() {
+                    let uid = cxn.uid();
+                    if self.playing.get(&uid) == Some(&index) && audience.includes(uid) {
+                        if let Err(e) = cxn.send(&packet) {
+                            failed.push((uid, e));
+                        }
+                        self.metrics.sent(&packet);
+                    }
+                }
+            }
+        }
+        for (uid, e) in failed {
+            if self.connections.iter().any(|cxn| cxn.uid() == uid) {
+                self.lose_connection(uid, e);
+            }
+        }
+    }
+
+    /// Forget a connection that's stopped working, which only counts as an error if
+    /// it didn't just hang up
+    fn lose_connection(&mut self, uid: u32, e: Error) {
+        if e.downcast_ref::<ConnectionClosed>().is_some() {
+            info!("Connection {} closed", uid);
+        } else {
+            info!("Lost connection {}: {:#}", uid, e);
+            self.metrics.connection_error();
+        }
+        self.remove_connection(uid);
+    }
+
+    fn remove_connection(&mut self, uid: u32) -> Option<Connection> {
+        let index = self.connections.iter().position(|cxn| cxn.uid() == uid)?;
+        self.admins.remove(&uid);
+        self.lobby.remove(&uid);
+        if let Some(index) = self.playing.remove(&uid) {
+            self.games[index].disconnect(uid);
+        }
+        Some(self.connections.remove(index))
+    }
+
+    /// Tell a connection why it's being dropped, and drop it
+    fn disconnect(&mut self, uid: u32, reason: &str) {
+        if let Some(cxn) = self.remove_connection(uid) {
+            self.close(cxn, reason);
+        }
+    }
+
+    fn close(&mut self, cxn: Connection, reason: &str) {
+        let packet = Packet::Disconnected {
+            reason: reason.to_string(),
+        };
+        self.metrics.sent(&packet);
+        if cxn.send(&packet).is_ok() {
+            self.closing.push((cxn, Instant::now()));
+        }
+    }
+
+    /// Run an admin command, returning what to tell the admin
+    pub fn run_command(&mut self, source: &str, line: &str) -> String {
+        let redacted = admin::redact(line);
+        info!("Admin command from {}: {}", source, redacted);
+        self.events.write(
+            None,
+            None,
+            &Event::Admin {
+                source: source.to_string(),
+                command: redacted,
+            },
+        );
+        match Command::parse(line).and_then(|command| self.execute(command, None)) {
+            Ok(output) => output,
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    /// Run a command on the given match, or the first (or all, for status) if it's
+    /// None
+    fn execute(&mut self, command: Command, index: Option<usize>) -> Result<String> {
+        match command {
+            Command::Kick { player, reason } => {
+                let (uid, name) = self.find_player(&player)?;
+                let reason = match reason {
+                    Some(reason) => format!("Kicked: {}", reason),
+                    None => "Kicked".to_string(),
+                };
+                self.disconnect(uid, &reason);
+                Ok(format!("Kicked {}", name))
+            }
+            Command::Ban {
+                player,
+                duration,
+                reason,
+            } => {
+                let how_long = match duration {
+                    Some(duration) => format!("for {}", network::format_duration(duration)),
+                    None => "forever".to_string(),
+                };
+                if self.lookup_player(&player)?.is_some() {
+                    let (uid, name) = self.find_player(&player)?;
+                    let address = self
+                        .connections
+                        .iter()
+                        .find(|cxn| cxn.uid() == uid)
+                        .and_then(|cxn| cxn.peer_addr())
+                        .map(|addr| addr.ip());
+                    let ban = Ban::new(Some(name.clone()), address, reason, duration);
+                    self.disconnect(uid, &ban.message());
+                    self.bans.add(ban)?;
+                    Ok(format!("Banned {} {}", name, how_long))
+                } else if let Ok(address) = player.parse::<IpAddr>() {
+                    if !network::is_bannable(address) {
+                        bail!("{} is this machine's own address", address);
+                    }
+                    self.bans
+                        .add(Ban::new(None, Some(address), reason, duration))?;
+                    Ok(format!("Banned address {} {}", address, how_long))
+                } else {
+                    self.bans
+                        .add(Ban::new(Some(player.clone()), None, reason, duration))?;
+                    Ok(format!("Banned username {} {}", player, how_long))
+                }
+            }
+            Command::Unban { player } => match self.bans.remove(&player)? {
+                0 => bail!("{} isn't banned", player),
+                _ => Ok(format!("Unbanned {}", player)),
+            },
+            Command::Status => {
+                if let Some(index) = index {
+                    return Ok(self.games[index].status());
+                }
+                let mut status = if self.games.len() == 1 {
+                    self.games[0].status()
+                } else {
+                    let mut status = String::new();
+                    for (index, game) in self.games.iter().enumerate() {
+                        status += &format!(
+                            "Match {} ({}): {}\n\n",
+                            index,
+                            self.match_config(index).name,
+                            game.status()
+                        );
+                    }
+                    status + &format!("{} choosing a match", self.lobby.len())
+                };
+                status += &format!(
+                    "\n{} connections, {} admins",
+                    self.connections.len(),
+                    self.admins.len()
+                );
+                Ok(status)
+            }
+            Command::Stats { username } => Ok(stats::describe(&self.stats.find(&username))),
+            Command::Leaderboard => {
+                let entries = self.stats.leaderboard();
+                if entries.is_empty() {
+                    return Ok("No stats have been recorded yet".to_string());
+                }
+                let lines: Vec<_> = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, stats)| format!("{:>3}. {}", rank + 1, stats::describe(stats)))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            Command::Say { message } => {
+                self.events.write(
+                    None,
+                    None,
+                    &Event::Chat {
+                        from: "admin".to_string(),
+                        text: message.clone(),
+                    },
+                );
+                let packet = Packet::ServerMessage { text: message };
+                for cxn in self.connections.iter() {
+                    let _ = cxn.send(&packet);
+                    self.metrics.sent(&packet);
+                }
+                Ok("Sent".to_string())
+            }
+            Command::ChangeMap { file } => {
+                let index = index.unwrap_or(0);
+                let source = match file {
+                    Some(file) => MapSource::File { file },
+                    None => self.match_config(index).map.clone(),
+                };
+                let map = Map::from_source(&source, &mut rand::thread_rng())?;
+                self.match_config_mut(index).map = source;
+                self.games[index].change_map(map);
+                Ok("Changed map".to_string())
+            }
+            Command::Set { key, value } => {
+                // Settings for the whole server are only found at the top
+                let value = match index {
+                    None => {
+                        self.config.set(&key, &value)?;
+                        self.config.get(&key).unwrap()
+                    }
+                    Some(index) => {
+                        let config = self.match_config_mut(index);
+                        config.set(&key, &value)?;
+                        config.get(&key).unwrap()
+                    }
+                };
+                let index = index.unwrap_or(0);
+                let config = self.match_config(index).clone();
+                self.games[index].configure(&config);
+                Ok(format!("{} = {}", key, value))
+            }
+            Command::Match { name, command } => {
+                let index = self.find_match(&name)?;
+                self.execute(*command, Some(index))
+            }
+            Command::Shutdown => {
+                info!("Shutting down");
+                // Before everyone leaves, so they're all there when it starts again
+                self.save_snapshot();
+                self.shutting_down = true;
+                let uids: Vec<_> = self.connections.iter().map(Connection::uid).collect();
+                for uid in uids {
+                    self.disconnect(uid, "The server is shutting down");
+                }
+                Ok("Shutting down".to_string())
+            }
+            Command::Help => Ok(admin::HELP.to_string()),
+        }
+    }
+
+    /// A player by id or username, or by <match>:<id or username> to say which match
+    /// they're in. Ids are per match, so one that's in more than one is an error.
+    fn lookup_player(&self, name: &str) -> Result<Option<&Player>> {
+        if let Some((match_name, player)) = name.split_once(':') {
+            let index = self.find_match(match_name)?;
+            return Ok(self.games[index].find_player(player));
+        }
+        let mut found = self.games.iter().filter_map(|game| game.find_player(name));
+        let player = found.next();
+        if name.parse::<u32>().is_ok() && found.next().is_some() {
+            bail!(
+                "There's a player {} in more than one match, say which with <match>:{}",
+                name,
+                name
+            );
+        }
+        Ok(player)
+    }
+
+    /// The connection and username of a player in any match. Bots don't have
+    /// connections.
+    fn find_player(&self, name: &str) -> Result<(u32, String)> {
+        let player = self
+            .lookup_player(name)?
+            .with_context(|| format!("No player {:?}", name))?;
+        let username = network::decode_username(&player.username);
+        match player.connection {
+            Some(uid) => Ok((uid, username)),
+            None => bail!(
+                "{} is a bot, change how many there are with set bots",
+                username
+            ),
+        }
+    }
+}
+
+/// Carry on each match from the snapshot, if there is one
+fn restore(games: &mut [Game], config: &Config, path: &str) -> Result<()> {
+    let snapshot = match Snapshot::load(path)? {
+        Some(snapshot) => snapshot,
+        None => return Ok(()),
+    };
+    for (name, saved) in snapshot.matches {
+        match config.all_matches().position(|config| config.name == name) {
+            Some(index) => {
+                if let Err(e) = games[index].restore(saved) {
+                    error!("Couldn't restore match {}: {:#}", name, e);
+                }
+            }
+            None => warn!("Match {} from the snapshot isn't in the config", name),
+        }
+    }
+    Ok(())
+}
+
+/// Whether a login or choice of match comes from a connection that's already past
+/// that point. Going along with it would put a second player in a match for the
+/// connection, and leave the first behind as a ghost.
+fn out_of_turn(
+    packet: &Packet,
+    uid: u32,
+    lobby: &HashMap<u32, Login>,
+    playing: &HashMap<u32, usize>,
+) -> bool {
+    match packet {
+        Packet::Login { .. } => lobby.contains_key(&uid) || playing.contains_key(&uid),
+        Packet::JoinMatch { .. } => playing.contains_key(&uid),
+        _ => false,
+    }
+}
+
+/// The packet as it's safe to write down, with passwords hidden
+fn loggable(packet: &Packet) -> String {
+    let hidden = || "<hidden>".to_string();
+    let redacted = match packet {
+        Packet::Login {
+            username,
+            password: Some(_),
+            spectator,
+        } => Packet::Login {
+            username: *username,
+            password: Some(hidden()),
+            spectator: *spectator,
+        },
+        Packet::AdminLogin { .. } => Packet::AdminLogin { password: hidden() },
+        Packet::AdminCommand { command } => Packet::AdminCommand {
+            command: admin::redact(command),
+        },
+        packet => return format!("{:?}", packet),
+    };
+    format!("{:?}", redacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> Packet {
+        Packet::Login {
+            username: [0; 20],
+            password: None,
+            spectator: false,
+        }
+    }
+
+    #[test]
+    fn login_once() {
+        let mut lobby = HashMap::new();
+        let mut playing = HashMap::new();
+        assert!(!out_of_turn(&login(), 1, &lobby, &playing));
+
+        lobby.insert(
+            1,
+            Login {
+                username: [0; 20],
+                spectator: false,
+            },
+        );
+        assert!(out_of_turn(&login(), 1, &lobby, &playing));
+        assert!(!out_of_turn(
+            &Packet::JoinMatch { id: 0 },
+            1,
+            &lobby,
+            &playing
+        ));
+        assert!(!out_of_turn(&login(), 2, &lobby, &playing));
+
+        lobby.clear();
+        playing.insert(1, 0);
+        assert!(out_of_turn(&login(), 1, &lobby, &playing));
+    }
+
+    #[test]
+    fn join_one_match() {
+        let lobby = HashMap::new();
+        let mut playing = HashMap::new();
+        playing.insert(1, 0);
+        assert!(out_of_turn(
+            &Packet::JoinMatch { id: 1 },
+            1,
+            &lobby,
+            &playing
+        ));
+        assert!(!out_of_turn(
+            &Packet::JoinMatch { id: 1 },
+            2,
+            &lobby,
+            &playing
+        ));
+    }
+
+    #[test]
+    fn passwords_not_logged() {
+        let mut username = [0; 20];
+        username[..5].copy_from_slice(b"alice");
+        let packets = [
+            Packet::Login {
+                username,
+                password: Some("hunter2".to_string()),
+                spectator: false,
+            },
+            Packet::AdminLogin {
+                password: "hunter2".to_string(),
+            },
+            Packet::AdminCommand {
+                command: "set password hunter2".to_string(),
+            },
+            Packet::AdminCommand {
+                command: "match second set admin_password hunter2".to_string(),
+            },
+        ];
+        for packet in &packets {
+            let logged = loggable(packet);
+            assert!(!logged.contains("hunter2"), "{}", logged);
+            assert!(logged.contains("<hidden>"), "{}", logged);
+        }
+        let command = Packet::AdminCommand {
+            command: "kick alice".to_string(),
+        };
+        assert!(loggable(&command).contains("kick alice"));
+    }
+}
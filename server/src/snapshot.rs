@@ -0,0 +1,58 @@
+use crate::game::GameSnapshot;
+use anyhow::*;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Bumped whenever what's saved changes, so old snapshots are ignored rather than
+/// misread
+const VERSION: u32 = 2;
+
+/// The state of every match, saved now and then so that a restarted server can
+/// carry on where it left off
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    version: u32,
+
+    /// By match name, since matches may have been added or removed in the config
+    pub matches: Vec<(String, GameSnapshot)>,
+}
+
+impl Snapshot {
+    pub fn new(matches: Vec<(String, GameSnapshot)>) -> Self {
+        Self {
+            version: VERSION,
+            matches,
+        }
+    }
+
+    /// The saved snapshot, if there is one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(path).with_context(|| format!("Reading {:?}", path))?;
+        let snapshot: Self =
+            bincode::deserialize(&data).with_context(|| format!("Parsing {:?}", path))?;
+        if snapshot.version != VERSION {
+            bail!(
+                "Snapshot {:?} is version {}, but this server reads version {}",
+                path,
+                snapshot.version,
+                VERSION
+            );
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Written alongside and then moved into place, so that a crash part way through
+    /// doesn't lose the last one
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, bincode::serialize(self)?)
+            .with_context(|| format!("Writing {:?}", temp))?;
+        std::fs::rename(&temp, path).with_context(|| format!("Writing {:?}", path))?;
+        Ok(())
+    }
+}
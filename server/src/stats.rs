@@ -0,0 +1,120 @@
+use anyhow::*;
+use log::*;
+use network::PlayerStats;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// How many players the leaderboard lists
+pub const LEADERBOARD_SIZE: usize = 10;
+
+/// How a player did in one round, to add to their career stats
+pub struct RoundResult {
+    pub username: String,
+    pub kills: u32,
+    pub deaths: u32,
+    pub shots: u32,
+    pub hits: u32,
+
+    /// False for players who left before the end, who don't get a match played
+    pub finished: bool,
+    pub won: bool,
+}
+
+/// Every player's career stats, kept in a TOML file so they outlast the server
+#[derive(Default, Deserialize, Serialize)]
+pub struct StatsBook {
+    #[serde(rename = "player", default)]
+    players: Vec<PlayerStats>,
+
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl StatsBook {
+    /// Load the stats from the file, or start afresh there if there isn't one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut book: StatsBook = if path.exists() {
+            let contents =
+                std::fs::read_to_string(path).with_context(|| format!("Reading {:?}", path))?;
+            toml::from_str(&contents).with_context(|| format!("Parsing {:?}", path))?
+        } else {
+            StatsBook::default()
+        };
+        book.path = path.to_path_buf();
+        info!("Stats kept for {} players", book.players.len());
+        Ok(book)
+    }
+
+    fn save(&self) -> Result<()> {
+        let contents = toml::to_string(self)?;
+        std::fs::write(&self.path, contents).with_context(|| format!("Writing {:?}", self.path))
+    }
+
+    pub fn record(&mut self, results: &[RoundResult]) -> Result<()> {
+        for result in results {
+            let index = match self
+                .players
+                .iter()
+                .position(|stats| stats.username == result.username)
+            {
+                Some(index) => index,
+                None => {
+                    self.players.push(PlayerStats {
+                        username: result.username.clone(),
+                        ..PlayerStats::default()
+                    });
+                    self.players.len() - 1
+                }
+            };
+            let stats = &mut self.players[index];
+            stats.kills += result.kills;
+            stats.deaths += result.deaths;
+            stats.shots += result.shots;
+            stats.hits += result.hits;
+            if result.finished {
+                stats.matches += 1;
+            }
+            if result.won {
+                stats.wins += 1;
+            }
+        }
+        self.save()
+    }
+
+    /// A player's stats, all zero if they haven't played here
+    pub fn find(&self, username: &str) -> PlayerStats {
+        self.players
+            .iter()
+            .find(|stats| stats.username == username)
+            .cloned()
+            .unwrap_or_else(|| PlayerStats {
+                username: username.to_string(),
+                ..PlayerStats::default()
+            })
+    }
+
+    /// The players with the most wins, and then the most kills
+    pub fn leaderboard(&self) -> Vec<PlayerStats> {
+        let mut ranked: Vec<_> = self.players.iter().collect();
+        ranked.sort_by(|a, b| {
+            (b.wins, b.kills)
+                .cmp(&(a.wins, a.kills))
+                .then_with(|| a.username.cmp(&b.username))
+        });
+        ranked.into_iter().take(LEADERBOARD_SIZE).cloned().collect()
+    }
+}
+
+/// A line about a player's stats, for admins
+pub fn describe(stats: &PlayerStats) -> String {
+    format!(
+        "{:<20} {:>4} wins {:>4} matches {:>5} kills {:>5} deaths {:>4.0}% accuracy",
+        stats.username,
+        stats.wins,
+        stats.matches,
+        stats.kills,
+        stats.deaths,
+        stats.accuracy() * 100.0
+    )
+}
@@ -0,0 +1,22 @@
+use cgmath::{InnerSpace, Vector3};
+use rand::prelude::*;
+
+/// Knock the direction of a shot off course by up to `spread` degrees, with every
+/// direction in that cone equally likely. `direction` must be normalized.
+pub fn spread<R: Rng>(direction: Vector3<f32>, spread: f32, rng: &mut R) -> Vector3<f32> {
+    if spread <= 0.0 {
+        return direction;
+    }
+    // Any two axes perpendicular to the direction
+    let reference = if direction.y.abs() < 0.9 {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_x()
+    };
+    let u = direction.cross(reference).normalize();
+    let v = direction.cross(u);
+
+    let angle = spread.to_radians() * rng.gen::<f32>().sqrt();
+    let around = rng.gen_range(0.0..std::f32::consts::TAU);
+    (direction + (u * around.cos() + v * around.sin()) * angle.tan()).normalize()
+}